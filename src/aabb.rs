@@ -0,0 +1,95 @@
+use cgmath::{vec3, ElementWise, Matrix4, Vector3};
+
+use crate::ray::Ray;
+
+#[derive(Clone, Copy)]
+pub struct Aabb {
+    pub min: Vector3<f32>,
+    pub max: Vector3<f32>,
+}
+
+impl Default for Aabb {
+    fn default() -> Self {
+        Self::EMPTY
+    }
+}
+
+impl Aabb {
+    pub const EMPTY: Aabb = Aabb {
+        min: vec3(f32::MAX, f32::MAX, f32::MAX),
+        max: vec3(f32::MIN, f32::MIN, f32::MIN),
+    };
+
+    pub fn new(a: Vector3<f32>, b: Vector3<f32>) -> Self {
+        Self {
+            min: vec3(a.x.min(b.x), a.y.min(b.y), a.z.min(b.z)),
+            max: vec3(a.x.max(b.x), a.y.max(b.y), a.z.max(b.z)),
+        }
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb {
+            min: vec3(self.min.x.min(other.min.x), self.min.y.min(other.min.y), self.min.z.min(other.min.z)),
+            max: vec3(self.max.x.max(other.max.x), self.max.y.max(other.max.y), self.max.z.max(other.max.z)),
+        }
+    }
+
+    pub fn grow(&self, point: Vector3<f32>) -> Aabb {
+        self.union(&Aabb { min: point, max: point })
+    }
+
+    // Bounds of all eight corners after an affine transform
+    pub fn transform(&self, matrix: &Matrix4<f32>) -> Aabb {
+        (0..8).fold(Aabb::EMPTY, |bounds, corner| {
+            let point = vec3(
+                if corner & 1 == 0 { self.min.x } else { self.max.x },
+                if corner & 2 == 0 { self.min.y } else { self.max.y },
+                if corner & 4 == 0 { self.min.z } else { self.max.z },
+            );
+            bounds.grow((matrix * point.extend(1.0)).truncate())
+        })
+    }
+
+    pub fn centroid(&self) -> Vector3<f32> {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn extent(&self) -> Vector3<f32> {
+        self.max - self.min
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        let e = self.extent();
+        if e.x < 0.0 || e.y < 0.0 || e.z < 0.0 {
+            return 0.0;
+        }
+
+        2.0 * (e.x * e.y + e.y * e.z + e.z * e.x)
+    }
+
+    pub fn largest_axis(&self) -> usize {
+        let e = self.extent();
+        if e.x > e.y && e.x > e.z {
+            0
+        } else if e.y > e.z {
+            1
+        } else {
+            2
+        }
+    }
+
+    // Slab test, returns the entry distance if the box is hit closer than `t_max`
+    pub fn hit(&self, ray: &Ray, inverse_direction: Vector3<f32>, t_max: f32) -> Option<f32> {
+        let t0 = (self.min - ray.origin).mul_element_wise(inverse_direction);
+        let t1 = (self.max - ray.origin).mul_element_wise(inverse_direction);
+
+        let t_near = t0.x.min(t1.x).max(t0.y.min(t1.y)).max(t0.z.min(t1.z)).max(0.0);
+        let t_far = t0.x.max(t1.x).min(t0.y.max(t1.y)).min(t0.z.max(t1.z)).min(t_max);
+
+        if t_near <= t_far {
+            Some(t_near)
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,291 @@
+use std::ops::AddAssign;
+use std::str::FromStr;
+
+use cgmath::{vec3, InnerSpace, Vector3, Zero};
+
+use crate::utils::pcg_hash;
+
+// Passes the renderer can show or write besides the beauty image, all taken at the first vertex of each path
+#[derive(Clone, Copy, PartialEq)]
+pub enum Aov {
+    Beauty,
+    Depth,
+    Normal,
+    Albedo,
+    MaterialIndex,
+    ObjectIndex,
+    Position,
+    // Light that scattered at most once on its way to the camera, and everything else
+    Direct,
+    Indirect,
+    // How many samples adaptive sampling spent on each pixel
+    SampleCount,
+}
+
+impl Aov {
+    pub const fn next(self) -> Self {
+        match self {
+            Aov::Beauty => Aov::Depth,
+            Aov::Depth => Aov::Normal,
+            Aov::Normal => Aov::Albedo,
+            Aov::Albedo => Aov::MaterialIndex,
+            Aov::MaterialIndex => Aov::ObjectIndex,
+            Aov::ObjectIndex => Aov::Position,
+            Aov::Position => Aov::Direct,
+            Aov::Direct => Aov::Indirect,
+            Aov::Indirect => Aov::SampleCount,
+            Aov::SampleCount => Aov::Beauty,
+        }
+    }
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Aov::Beauty => "beauty",
+            Aov::Depth => "depth",
+            Aov::Normal => "normal",
+            Aov::Albedo => "albedo",
+            Aov::MaterialIndex => "material",
+            Aov::ObjectIndex => "object",
+            Aov::Position => "position",
+            Aov::Direct => "direct",
+            Aov::Indirect => "indirect",
+            Aov::SampleCount => "samples",
+        }
+    }
+
+    // Radiance passes go through the display transform, the others are data shown as is
+    pub const fn is_radiance(self) -> bool {
+        matches!(self, Aov::Beauty | Aov::Direct | Aov::Indirect)
+    }
+
+    // Mean value of every pixel for the data passes, the beauty pass is filtered and read from the renderer's
+    // accumulation instead. Indices are -1 where nothing was hit
+    pub fn values(self, pixels: &[AovPixel]) -> Vec<Vector3<f32>> {
+        let index = |index: Option<usize>| {
+            let value = index.map_or(-1.0, |i| i as f32);
+            vec3(value, value, value)
+        };
+
+        pixels
+            .iter()
+            .map(|pixel| (pixel, pixel.samples.max(1) as f32))
+            .map(|(pixel, n)| match self {
+                Aov::Beauty => unreachable!("the beauty pass isn't averaged per pixel"),
+                Aov::Depth => vec3(pixel.depth, pixel.depth, pixel.depth) / n,
+                Aov::Normal if pixel.normal.magnitude2() > 0.0 => pixel.normal.normalize(),
+                Aov::Normal => Vector3::zero(),
+                Aov::Albedo => pixel.albedo / n,
+                Aov::MaterialIndex => index(pixel.material_index),
+                Aov::ObjectIndex => index(pixel.object_index),
+                Aov::Position => pixel.position / n,
+                Aov::Direct => pixel.direct / n,
+                Aov::Indirect => pixel.indirect / n,
+                Aov::SampleCount => vec3(n, n, n),
+            })
+            .collect()
+    }
+
+    // Maps data pass values to displayable colors in [0, 1]
+    pub fn visualize(self, values: &[Vector3<f32>]) -> Vec<Vector3<f32>> {
+        match self {
+            // Scaled by a high percentile rather than the maximum, which infinite planes can make huge
+            Aov::Depth => {
+                let Some(far) = percentile(values.iter().map(|v| v.x).filter(|&d| d > 0.0).collect(), 0.95) else {
+                    return values.to_vec();
+                };
+                values.iter().map(|v| (v / far.max(1e-6)).map(|d| d.min(1.0))).collect()
+            }
+            Aov::Normal => values.iter().map(|n| (n + vec3(1.0, 1.0, 1.0)) * 0.5).collect(),
+            Aov::Position => {
+                let bound = |axis: usize, p: f32| percentile(values.iter().map(|v| v[axis]).collect(), p).unwrap_or(0.0);
+                let min = vec3(bound(0, 0.05), bound(1, 0.05), bound(2, 0.05));
+                let max = vec3(bound(0, 0.95), bound(1, 0.95), bound(2, 0.95));
+                let extent = (max - min).map(|e| e.max(1e-6));
+                values.iter().map(|v| (v - min).zip(extent, |d, e| (d / e).clamp(0.0, 1.0))).collect()
+            }
+            // A random but stable color per index
+            Aov::MaterialIndex | Aov::ObjectIndex => values
+                .iter()
+                .map(|v| {
+                    if v.x < 0.0 {
+                        return Vector3::zero();
+                    }
+                    let hash = pcg_hash(v.x as u32);
+                    vec3((hash & 0xff) as f32, ((hash >> 8) & 0xff) as f32, ((hash >> 16) & 0xff) as f32) / 255.0
+                })
+                .collect(),
+            Aov::SampleCount => {
+                let most = values.iter().fold(1.0f32, |most, v| most.max(v.x));
+                values.iter().map(|v| heatmap(v.x / most)).collect()
+            }
+            Aov::Beauty | Aov::Albedo | Aov::Direct | Aov::Indirect => values.to_vec(),
+        }
+    }
+}
+
+// Dark blue through cyan, green and yellow to red
+fn heatmap(t: f32) -> Vector3<f32> {
+    const STOPS: [Vector3<f32>; 5] = [
+        vec3(0.0, 0.0, 0.5),
+        vec3(0.0, 0.6, 1.0),
+        vec3(0.1, 0.9, 0.2),
+        vec3(1.0, 0.9, 0.0),
+        vec3(1.0, 0.0, 0.0),
+    ];
+
+    let x = t.clamp(0.0, 1.0) * (STOPS.len() - 1) as f32;
+    let i = (x as usize).min(STOPS.len() - 2);
+    STOPS[i] + (STOPS[i + 1] - STOPS[i]) * (x - i as f32)
+}
+
+fn percentile(mut values: Vec<f32>, p: f32) -> Option<f32> {
+    if values.is_empty() {
+        return None;
+    }
+
+    let index = ((values.len() - 1) as f32 * p) as usize;
+    Some(*values.select_nth_unstable_by(index, f32::total_cmp).1)
+}
+
+impl FromStr for Aov {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [
+            Aov::Beauty,
+            Aov::Depth,
+            Aov::Normal,
+            Aov::Albedo,
+            Aov::MaterialIndex,
+            Aov::ObjectIndex,
+            Aov::Position,
+            Aov::Direct,
+            Aov::Indirect,
+            Aov::SampleCount,
+        ]
+        .into_iter()
+        .find(|aov| aov.name() == s)
+        .ok_or(())
+    }
+}
+
+// Per pixel sums over the accumulated samples, except for the indices which come from the first sample that hit
+// a surface. Also guides the denoiser
+#[derive(Clone, Copy)]
+pub struct AovPixel {
+    // Distance from the camera
+    pub depth: f32,
+    pub normal: Vector3<f32>,
+    pub albedo: Vector3<f32>,
+    pub position: Vector3<f32>,
+    pub material_index: Option<usize>,
+    // Index into `Scene::primitives`
+    pub object_index: Option<usize>,
+    pub direct: Vector3<f32>,
+    pub indirect: Vector3<f32>,
+    pub samples: u32,
+    // First and second moments of the sample luminance, for estimating the noise left in the pixel
+    pub luminance: f32,
+    pub luminance_squared: f32,
+}
+
+impl Default for AovPixel {
+    fn default() -> Self {
+        Self {
+            depth: 0.0,
+            normal: Vector3::zero(),
+            albedo: Vector3::zero(),
+            position: Vector3::zero(),
+            material_index: None,
+            object_index: None,
+            direct: Vector3::zero(),
+            indirect: Vector3::zero(),
+            samples: 0,
+            luminance: 0.0,
+            luminance_squared: 0.0,
+        }
+    }
+}
+
+impl AddAssign for AovPixel {
+    fn add_assign(&mut self, other: Self) {
+        self.depth += other.depth;
+        self.normal += other.normal;
+        self.albedo += other.albedo;
+        self.position += other.position;
+        self.material_index = self.material_index.or(other.material_index);
+        self.object_index = self.object_index.or(other.object_index);
+        self.direct += other.direct;
+        self.indirect += other.indirect;
+        self.samples += other.samples;
+        self.luminance += other.luminance;
+        self.luminance_squared += other.luminance_squared;
+    }
+}
+
+impl AovPixel {
+    // Variance of the mean luminance, which shrinks with every sample taken
+    pub fn variance(&self) -> f32 {
+        let n = self.samples.max(1) as f32;
+        let mean = self.luminance / n;
+        (self.luminance_squared / n - mean * mean).max(0.0) / n
+    }
+
+    // Standard error of the luminance roughly as it survives display: display encoding makes it relative to the
+    // square root of the mean and tone mapping compresses highlights. The floor keeps black pixels from needing
+    // endless samples
+    pub fn display_error(&self) -> f32 {
+        let mean = self.luminance / self.samples.max(1) as f32;
+        self.variance().sqrt() / ((mean + 0.01).sqrt() * (1.0 + mean))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(value: f32, index: Option<usize>) -> AovPixel {
+        AovPixel {
+            depth: value,
+            normal: vec3(0.0, value, 1.0),
+            albedo: vec3(value, 0.5, 0.0),
+            position: vec3(value, -value, 2.0),
+            material_index: index,
+            object_index: index.map(|i| i + 10),
+            direct: vec3(value, value, value),
+            indirect: vec3(2.0 * value, 0.0, 0.0),
+            samples: 1,
+            luminance: value,
+            luminance_squared: value * value,
+        }
+    }
+
+    #[test]
+    fn accumulated_passes_are_averaged() {
+        let values = [1.0, 2.0, 6.0, 3.0];
+        let mut pixel = AovPixel::default();
+        // The first sample misses, the indices come from the first one that hits
+        pixel += sample(values[0], None);
+        pixel += sample(values[1], Some(3));
+        for value in &values[2..] {
+            pixel += sample(*value, Some(7));
+        }
+
+        let mean = 3.0;
+        let pass = |aov: Aov| aov.values(&[pixel])[0];
+        assert!(pass(Aov::Depth) == vec3(mean, mean, mean));
+        assert!((pass(Aov::Normal) - vec3(0.0, mean, 1.0).normalize()).magnitude() < 1e-6);
+        assert!(pass(Aov::Albedo) == vec3(mean, 0.5, 0.0));
+        assert!(pass(Aov::Position) == vec3(mean, -mean, 2.0));
+        assert!(pass(Aov::Direct) == vec3(mean, mean, mean));
+        assert!(pass(Aov::Indirect) == vec3(2.0 * mean, 0.0, 0.0));
+        assert!(pass(Aov::MaterialIndex) == vec3(3.0, 3.0, 3.0));
+        assert!(pass(Aov::ObjectIndex) == vec3(13.0, 13.0, 13.0));
+        assert!(pass(Aov::SampleCount) == vec3(4.0, 4.0, 4.0));
+
+        // The samples spread by a variance of 3.5, their mean by a quarter of it
+        assert!((pixel.variance() - 3.5 / 4.0).abs() < 1e-6);
+        assert!(Aov::Depth.values(&[AovPixel::default()])[0] == Vector3::zero());
+        assert!(Aov::ObjectIndex.values(&[AovPixel::default()])[0] == vec3(-1.0, -1.0, -1.0));
+    }
+}
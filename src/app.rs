@@ -1,13 +1,35 @@
+use std::f32::consts::PI;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
 use std::time::{Duration, Instant};
 
-use cgmath::{vec3, ElementWise, MetricSpace};
-use minifb::Window;
+use cgmath::{vec3, Deg, ElementWise, InnerSpace, Matrix4, MetricSpace, Rad, Vector3, Zero};
+use minifb::{Key, KeyRepeat, MouseButton, MouseMode, Window};
 use rand::random;
 
-use crate::camera::Camera;
-use crate::renderer::Renderer;
-use crate::scene::{Material, Scene, Sphere};
+use crate::aov::Aov;
+use crate::bench::{self, BenchResult};
+use crate::camera::{Camera, Lens};
+use crate::display::DisplayTransform;
+use crate::environment::Environment;
+use crate::filter::Filter;
+use crate::image;
+use crate::integrator::Integrator;
+use crate::medium::Medium;
+use crate::obj;
+use crate::options::Options;
+use crate::ray::Ray;
+use crate::renderer::{RenderStats, Renderer};
+use crate::sampler::SamplerKind;
+use crate::aabb::Aabb;
+use crate::mesh::{Mesh, Triangle};
+use crate::spectrum::Dispersion;
+use crate::scene::{Instance, Material, Object, Prototype, Scene, Sphere};
+use crate::shape::{AxisAlignedBox, Cylinder, Disc, Plane, Quad};
 use crate::text::render_into_buffer;
+use crate::texture::Texture;
+use crate::tile::TileOrder;
 use crate::utils::random_vector3;
 
 pub struct App {
@@ -17,8 +39,15 @@ pub struct App {
     renderer: Renderer,
     scene: Scene,
     last_render_time: Duration,
+    // For detecting clicks, minifb only reports whether the button is held
+    left_was_down: bool,
 }
 
+// How long a window frame may trace before showing what it has, large frames then fill in over several updates
+const FRAME_BUDGET: Duration = Duration::from_millis(30);
+// How often offline renders report their progress
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
 const GROUND: Material = Material::lambertian(vec3(0.5, 0.5, 0.5));
 
 const PINK: Material = Material::lambertian(vec3(1.0, 0.0, 1.0));
@@ -29,39 +58,115 @@ const ORANGE: Material = Material::metal(vec3(0.8, 0.5, 0.2), 0.1).emissive(20.0
 
 const BROWN: Material = Material::lambertian(vec3(0.4, 0.2, 0.1));
 
-enum SceneVariant {
+// Crown glass, its Cauchy fit only matters to the spectral integrator
+const GLASS: Material = Material::dielectric(vec3(1.0, 1.0, 1.0), 1.517)
+    .with_dispersion(Dispersion::Cauchy { a: 1.5046, b: 0.0042 });
+
+const FLINT: Material = Material::dielectric(vec3(1.0, 1.0, 1.0), 1.785).with_dispersion(Dispersion::SF11);
+
+const BEAM: Material = Material::lambertian(vec3(1.0, 1.0, 1.0)).emissive(40.0);
+
+const STEEL: Material = Material::metal(vec3(0.7, 0.6, 0.5), 0.0);
+
+const WHITE: Material = Material::lambertian(vec3(0.73, 0.73, 0.73));
+
+const LAMP: Material = Material::lambertian(vec3(1.0, 0.9, 0.8)).emissive(8.0);
+
+const CHECKERED: Material = Material::lambertian(vec3(1.0, 1.0, 1.0)).with_albedo_texture(2);
+
+const MARBLE: Material = Material::lambertian(vec3(1.0, 1.0, 1.0)).with_albedo_texture(4);
+
+const BRUSHED: Material = Material::metal(vec3(0.8, 0.8, 0.8), 0.6).with_roughness_texture(3);
+
+const SMOKE_BOUNDARY: Material = Material::interface(0);
+
+// Bluish smoke, scattering short wavelengths more
+const SMOKE: Medium = Medium {
+    absorption: vec3(0.1, 0.1, 0.1),
+    scattering: vec3(1.5, 2.5, 4.0),
+    anisotropy: 0.0,
+};
+
+#[derive(Clone, Copy)]
+pub enum SceneVariant {
     ChernoSun,
     ChernoBalls,
     Rtiaw,
+    Shapes,
+    Prism,
 }
 
-const SCENE_VARIANT: SceneVariant = SceneVariant::Rtiaw;
+impl FromStr for SceneVariant {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cherno-sun" => Ok(SceneVariant::ChernoSun),
+            "cherno-balls" => Ok(SceneVariant::ChernoBalls),
+            "rtiaw" => Ok(SceneVariant::Rtiaw),
+            "shapes" => Ok(SceneVariant::Shapes),
+            "prism" => Ok(SceneVariant::Prism),
+            _ => Err(()),
+        }
+    }
+}
 
 impl App {
-    pub fn new(width: usize, height: usize) -> Self {
+    pub fn new(options: &Options) -> Self {
+        let (width, height) = (options.width, options.height);
         let mut scene = Scene::default();
+        let mut camera = Camera::new(45.0, 0.1, 100.0);
 
         scene.materials.push(PINK);
         scene.materials.push(ORANGE);
         scene.materials.push(BLUE);
         scene.materials.push(GROUND);
+        scene.materials.push(GLASS);
+        scene.materials.push(BROWN);
+        scene.materials.push(STEEL);
+        scene.materials.push(WHITE);
+        scene.materials.push(LAMP);
+        scene.materials.push(CHECKERED);
+        scene.materials.push(MARBLE);
+        scene.materials.push(BRUSHED);
+        scene.materials.push(SMOKE_BOUNDARY);
+        scene.materials.push(FLINT);
+        scene.materials.push(BEAM);
+
+        scene.media.push(SMOKE);
+
+        scene.textures.push(Texture::Constant(vec3(0.2, 0.3, 0.1)));
+        scene.textures.push(Texture::Constant(vec3(0.9, 0.9, 0.9)));
+        scene.textures.push(Texture::Checker { even: 0, odd: 1, scale: 1.0 });
+        scene.textures.push(Texture::Noise {
+            color: vec3(1.0, 1.0, 1.0),
+            scale: 4.0,
+            octaves: 6,
+        });
+        scene.textures.push(Texture::Marble {
+            color: vec3(0.9, 0.85, 0.8),
+            scale: 4.0,
+        });
 
-        match SCENE_VARIANT {
+        match options.scene {
             SceneVariant::ChernoSun => {
                 scene.spheres.push(Sphere {
                     material_index: 0,
                     position: vec3(0.0, 0.0, 0.0),
+                    motion: Vector3::zero(),
                     radius: 1.0,
                 });
 
                 scene.spheres.push(Sphere {
                     material_index: 1,
                     position: vec3(32.0, 32.0, -32.0),
+                    motion: Vector3::zero(),
                     radius: 20.0,
                 });
 
                 scene.spheres.push(Sphere {
                     position: vec3(0.0, -101.0, 0.0),
+                    motion: Vector3::zero(),
                     material_index: 2,
                     radius: 100.0,
                 });
@@ -71,33 +176,60 @@ impl App {
                 scene.spheres.push(Sphere {
                     material_index: 0,
                     position: vec3(0.0, 0.0, 0.0),
+                    motion: Vector3::zero(),
                     radius: 1.0,
                 });
 
                 scene.spheres.push(Sphere {
                     material_index: 1,
                     position: vec3(2.0, 0.0, 0.0),
+                    motion: Vector3::zero(),
                     radius: 1.0,
                 });
 
                 scene.spheres.push(Sphere {
                     material_index: 2,
                     position: vec3(0.0, -101.0, 0.0),
+                    motion: Vector3::zero(),
                     radius: 100.0,
                 });
                 scene.global_illumination = true;
             }
             SceneVariant::Rtiaw => {
-                scene.spheres.push(Sphere {
-                    position: vec3(0.0, -1000.0, 0.0),
-                    radius: 1000.0,
+                // Above the ground plane, which the default camera would sit exactly on
+                camera.set_position(vec3(0.0, 1.0, 10.0));
+                camera.set_lens(Lens {
+                    focus_distance: 10.0,
+                    ..camera.get_lens()
+                });
+
+                scene.objects.push(Object {
+                    shape: Box::new(Plane {
+                        point: vec3(0.0, 0.0, 0.0),
+                        normal: vec3(0.0, 1.0, 0.0),
+                    }),
                     material_index: 3,
                 });
 
                 scene.spheres.push(Sphere {
                     position: vec3(0.0, 1.0, 0.0),
+                    motion: Vector3::zero(),
                     radius: 1.0,
-                    material_index: 0,
+                    material_index: 4,
+                });
+
+                scene.spheres.push(Sphere {
+                    position: vec3(-4.0, 1.0, 0.0),
+                    motion: Vector3::zero(),
+                    radius: 1.0,
+                    material_index: 5,
+                });
+
+                scene.spheres.push(Sphere {
+                    position: vec3(4.0, 1.0, 0.0),
+                    motion: Vector3::zero(),
+                    radius: 1.0,
+                    material_index: 6,
                 });
 
                 let scene_center = vec3(4.0, 0.2, 0.0);
@@ -110,11 +242,26 @@ impl App {
                         );
 
                         if center.distance(scene_center) > 0.9 {
-                            let albedo = random_vector3().mul_element_wise(random_vector3());
-                            scene.materials.push(Material::lambertian(albedo));
+                            let choose_material = random::<f32>();
+                            // Diffuse spheres bounce up while the shutter is open
+                            let mut motion = Vector3::zero();
+                            let material_index = if choose_material < 0.8 {
+                                let albedo = random_vector3().mul_element_wise(random_vector3());
+                                scene.materials.push(Material::lambertian(albedo));
+                                motion = vec3(0.0, 0.5 * random::<f32>(), 0.0);
+                                scene.materials.len() - 1
+                            } else if choose_material < 0.95 {
+                                let albedo = random_vector3() * 0.5 + vec3(0.5, 0.5, 0.5);
+                                scene.materials.push(Material::metal(albedo, 0.5 * random::<f32>()));
+                                scene.materials.len() - 1
+                            } else {
+                                4
+                            };
+
                             scene.spheres.push(Sphere {
-                                material_index: scene.materials.len() - 1,
+                                material_index,
                                 position: center,
+                                motion,
                                 radius: 0.2,
                             })
                         }
@@ -123,18 +270,165 @@ impl App {
 
                 scene.global_illumination = true;
             }
+            SceneVariant::Shapes => {
+                scene.objects.push(Object {
+                    shape: Box::new(Plane {
+                        point: vec3(0.0, -1.0, 0.0),
+                        normal: vec3(0.0, 1.0, 0.0),
+                    }),
+                    material_index: 9,
+                });
+
+                scene.objects.push(Object {
+                    shape: Box::new(Quad {
+                        corner: vec3(-1.0, 3.0, -1.0),
+                        edge_u: vec3(2.0, 0.0, 0.0),
+                        edge_v: vec3(0.0, 0.0, 2.0),
+                    }),
+                    material_index: 8,
+                });
+
+                scene.objects.push(Object {
+                    shape: Box::new(AxisAlignedBox {
+                        min: vec3(-2.8, -1.0, -0.5),
+                        max: vec3(-1.6, 0.2, 0.5),
+                    }),
+                    material_index: 10,
+                });
+
+                // Pillars sharing one unit cylinder
+                scene.prototypes.push(Prototype::Shape {
+                    shape: Box::new(Cylinder {
+                        base: vec3(0.0, 0.0, 0.0),
+                        axis: vec3(0.0, 1.0, 0.0),
+                        radius: 1.0,
+                        height: 1.0,
+                    }),
+                    material_index: 2,
+                });
+                let pillars = [
+                    Matrix4::from_translation(vec3(2.2, -1.0, 0.0)) * Matrix4::from_nonuniform_scale(0.5, 1.5, 0.5),
+                    Matrix4::from_translation(vec3(3.4, -1.0, -1.8))
+                        * Matrix4::from_angle_y(Deg(30.0))
+                        * Matrix4::from_nonuniform_scale(0.6, 2.5, 0.3),
+                    Matrix4::from_translation(vec3(1.2, -0.7, 1.2))
+                        * Matrix4::from_angle_z(Deg(90.0))
+                        * Matrix4::from_nonuniform_scale(0.3, 1.2, 0.3),
+                ];
+                scene.instances.extend(pillars.into_iter().filter_map(|transform| Instance::new(0, transform)));
+
+                scene.objects.push(Object {
+                    shape: Box::new(Disc {
+                        center: vec3(0.0, 0.5, -3.0),
+                        normal: vec3(0.0, 0.0, 1.0),
+                        radius: 1.5,
+                    }),
+                    material_index: 11,
+                });
+
+                scene.spheres.push(Sphere {
+                    position: vec3(0.0, -0.3, 0.0),
+                    motion: Vector3::zero(),
+                    radius: 0.7,
+                    material_index: 4,
+                });
+
+                scene.spheres.push(Sphere {
+                    position: vec3(-0.9, 0.1, -1.8),
+                    motion: Vector3::zero(),
+                    radius: 1.0,
+                    material_index: 12,
+                });
+
+                scene.global_illumination = false;
+            }
+            // A small bright light low on the left shining through a flint glass prism, the spectral integrator
+            // splits what comes out into a rainbow on the floor
+            SceneVariant::Prism => {
+                scene.objects.push(Object {
+                    shape: Box::new(Plane {
+                        point: vec3(0.0, -1.0, 0.0),
+                        normal: vec3(0.0, 1.0, 0.0),
+                    }),
+                    material_index: 7,
+                });
+
+                scene.objects.push(Object {
+                    shape: Box::new(Quad {
+                        corner: vec3(-6.0, -1.0, -3.0),
+                        edge_u: vec3(12.0, 0.0, 0.0),
+                        edge_v: vec3(0.0, 6.0, 0.0),
+                    }),
+                    material_index: 7,
+                });
+
+                scene.meshes.push(prism_mesh(13));
+
+                // A thin strip of light overhead, only seen bent down through the prism and split into its colors
+                scene.objects.push(Object {
+                    shape: Box::new(Quad {
+                        corner: vec3(-4.0, 4.0, -1.65),
+                        edge_u: vec3(8.0, 0.0, 0.0),
+                        edge_v: vec3(0.0, 0.0, 0.05),
+                    }),
+                    material_index: 14,
+                });
+
+                scene.spheres.push(Sphere {
+                    position: vec3(-7.0, 3.0, 2.0),
+                    motion: Vector3::zero(),
+                    radius: 1.0,
+                    material_index: 8,
+                });
+
+                camera.set_position(vec3(0.0, 0.3, 6.0));
+                scene.global_illumination = false;
+            }
+        }
+
+        if options.fog > 0.0 {
+            scene.media.push(Medium {
+                absorption: Vector3::zero(),
+                scattering: vec3(options.fog, options.fog, options.fog),
+                anisotropy: options.fog_anisotropy,
+            });
+            scene.atmosphere = Some(scene.media.len() - 1);
         }
 
+        scene.rebuild();
+
+        camera.set_shutter(0.0, options.shutter);
+
+        let lens = camera.get_lens();
+        camera.set_lens(Lens {
+            aperture_radius: options.aperture_radius,
+            focus_distance: options.focus_distance.unwrap_or(lens.focus_distance),
+            aperture_blades: options.aperture_blades,
+        });
+
         let mut renderer = Renderer::default();
         renderer.on_resize(width, height);
+        renderer.set_max_depth(options.max_depth);
+        renderer.set_filter(options.filter);
+        renderer.set_sampler(options.sampler);
+        renderer.set_denoise(options.denoise);
+        renderer.set_noise_target(options.noise_target);
+        renderer.set_spectral(options.spectral);
+        renderer.set_integrator(options.integrator);
+        renderer.set_tile_order(options.tile_order);
+        renderer.set_display_transform(DisplayTransform {
+            exposure: options.exposure,
+            tone_mapper: options.tone_mapper,
+        });
 
         App {
             renderer,
             viewport_width: width,
             viewport_height: height,
-            camera: Camera::new(45.0, 0.1, 100.0),
+            camera,
             scene,
             last_render_time: Duration::ZERO,
+            left_was_down: false,
         }
     }
 
@@ -142,21 +436,237 @@ impl App {
         if self.camera.on_update(ts, window) {
             self.renderer.reset_frame_index();
         }
+
+        let mut lens = self.camera.get_lens();
+        if window.is_key_pressed(Key::RightBracket, KeyRepeat::Yes) {
+            lens.aperture_radius = (lens.aperture_radius * 1.25).max(0.01);
+        }
+        if window.is_key_pressed(Key::LeftBracket, KeyRepeat::Yes) {
+            lens.aperture_radius = if lens.aperture_radius > 0.01 { lens.aperture_radius / 1.25 } else { 0.0 };
+        }
+        let left_down = window.get_mouse_down(MouseButton::Left);
+        if left_down && !self.left_was_down {
+            if let Some(distance) = self.focus_distance_at_cursor(window) {
+                lens.focus_distance = distance;
+            }
+        }
+        self.left_was_down = left_down;
+
+        let old_lens = self.camera.get_lens();
+        if lens.aperture_radius != old_lens.aperture_radius || lens.focus_distance != old_lens.focus_distance {
+            self.camera.set_lens(lens);
+            self.renderer.reset_frame_index();
+        }
+
+        let mut display = self.renderer.get_display_transform();
+        if window.is_key_pressed(Key::T, KeyRepeat::No) {
+            display.tone_mapper = display.tone_mapper.next();
+        }
+        if window.is_key_pressed(Key::Equal, KeyRepeat::Yes) {
+            display.exposure += 0.5;
+        }
+        if window.is_key_pressed(Key::Minus, KeyRepeat::Yes) {
+            display.exposure -= 0.5;
+        }
+        self.renderer.set_display_transform(display);
+
+        if window.is_key_pressed(Key::F, KeyRepeat::No) {
+            self.renderer.set_filter(self.renderer.get_filter().next());
+        }
+        if window.is_key_pressed(Key::O, KeyRepeat::No) {
+            self.renderer.set_denoise(!self.renderer.get_denoise());
+        }
+        if window.is_key_pressed(Key::N, KeyRepeat::No) {
+            self.renderer.set_sampler(self.renderer.get_sampler().next());
+        }
+        if window.is_key_pressed(Key::L, KeyRepeat::No) {
+            self.renderer.set_spectral(!self.renderer.get_spectral());
+        }
+        if window.is_key_pressed(Key::B, KeyRepeat::No) {
+            self.renderer.set_integrator(self.renderer.get_integrator().next());
+        }
+        if window.is_key_pressed(Key::G, KeyRepeat::No) {
+            self.renderer.set_tile_order(self.renderer.get_tile_order().next());
+        }
+        if window.is_key_pressed(Key::V, KeyRepeat::No) {
+            self.renderer.set_aov(self.renderer.get_aov().next());
+        }
+    }
+
+    // Distance to the surface under the cursor along the view direction, for click to focus
+    fn focus_distance_at_cursor(&self, window: &Window) -> Option<f32> {
+        let (mouse_x, mouse_y) = window.get_mouse_pos(MouseMode::Discard)?;
+        // The image is stored bottom row first
+        let direction = self
+            .camera
+            .get_direction(mouse_x, self.camera.viewport_height as f32 - mouse_y)
+            .normalize();
+
+        let ray = Ray {
+            origin: self.camera.get_position(),
+            direction,
+            time: self.camera.get_shutter_open(),
+        };
+        let hit = self.scene.intersect(&ray, f32::MAX)?;
+
+        Some(hit.distance * direction.dot(self.camera.get_view_direction()))
+    }
+
+    pub fn get_filter(&self) -> Filter {
+        self.renderer.get_filter()
+    }
+
+    pub fn get_denoise(&self) -> bool {
+        self.renderer.get_denoise()
+    }
+
+    pub fn get_sampler(&self) -> SamplerKind {
+        self.renderer.get_sampler()
     }
 
-    pub fn render(&mut self, buffer: &mut Vec<u32>) {
+    pub fn get_spectral(&self) -> bool {
+        self.renderer.get_spectral()
+    }
+
+    pub fn get_integrator(&self) -> Integrator {
+        self.renderer.get_integrator()
+    }
+
+    pub fn get_tile_order(&self) -> TileOrder {
+        self.renderer.get_tile_order()
+    }
+
+    pub fn get_frame_progress(&self) -> f32 {
+        self.renderer.get_frame_progress()
+    }
+
+    pub fn is_converged(&self) -> bool {
+        self.renderer.is_converged()
+    }
+
+    pub fn get_aov(&self) -> Aov {
+        self.renderer.get_aov()
+    }
+
+    pub fn get_lens(&self) -> Lens {
+        self.camera.get_lens()
+    }
+
+    pub fn get_display_transform(&self) -> DisplayTransform {
+        self.renderer.get_display_transform()
+    }
+
+    pub fn render(&mut self, buffer: &mut [u32]) {
         let time = Instant::now();
 
         self.camera
             .on_resize(self.viewport_width, self.viewport_height);
-        self.renderer.render(&self.scene, &self.camera, buffer);
+        self.renderer.render(&self.scene, &self.camera, FRAME_BUDGET, buffer);
 
         self.last_render_time = time.elapsed();
 
         self.render_elapsed(buffer);
     }
 
-    fn render_elapsed(&self, buffer: &mut Vec<u32>) {
+    // Accumulates up to `samples` frames without any overlay, for writing the result to disk. Stops early once
+    // every pixel meets the noise target and returns how many frames were rendered. `progress` regularly gets the
+    // completed frames and how far the current one is
+    pub fn render_offline(&mut self, samples: usize, buffer: &mut [u32], mut progress: impl FnMut(usize, f32)) -> usize {
+        self.camera
+            .on_resize(self.viewport_width, self.viewport_height);
+        self.renderer.reset_frame_index();
+
+        let mut frames = 0;
+        while frames < samples && !self.renderer.is_converged() {
+            if self.renderer.accumulate(&self.scene, &self.camera, Some(Instant::now() + PROGRESS_INTERVAL)) {
+                frames += 1;
+            }
+            progress(frames, self.renderer.get_frame_progress());
+        }
+        self.renderer.encode_aov(self.renderer.get_aov(), self.viewport_width, self.viewport_height, buffer);
+
+        frames
+    }
+
+    pub fn bench(&mut self, rays: usize) -> BenchResult {
+        self.camera
+            .on_resize(self.viewport_width, self.viewport_height);
+        bench::run(&self.scene, &self.camera, rays)
+    }
+
+    // Writes a pass of what has been accumulated so far, raw for float formats and as it is displayed otherwise
+    pub fn write_aov(&self, aov: Aov, path: &Path) -> io::Result<()> {
+        let (width, height) = (self.viewport_width, self.viewport_height);
+        if image::is_float_image(path) {
+            return image::write_float_image(path, width, height, &self.renderer.get_aov_values(aov, width, height));
+        }
+
+        let mut buffer = vec![0; width * height];
+        self.renderer.encode_aov(aov, width, height, &mut buffer);
+        image::write_image(path, width, height, &buffer)
+    }
+
+    pub fn has_emissive_instances(&self) -> bool {
+        self.scene.has_emissive_instances()
+    }
+
+    // With `instances` above zero the mesh is scattered as that many instances instead of placed once
+    pub fn load_obj(&mut self, path: &Path, instances: usize) -> io::Result<()> {
+        let mesh = obj::load_obj(path, &mut self.scene.materials, &mut self.scene.textures)?;
+        if instances == 0 {
+            self.scene.meshes.push(mesh);
+        } else {
+            self.scatter_instances(mesh, instances)?;
+        }
+        self.scene.rebuild();
+        self.renderer.reset_frame_index();
+
+        Ok(())
+    }
+
+    // Randomly rotated and stretched copies standing on the y = 0 plane, all sharing one mesh
+    fn scatter_instances(&mut self, mesh: Mesh, count: usize) -> io::Result<()> {
+        if mesh.triangles.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "mesh without faces to instance"));
+        }
+
+        let bounds = mesh.positions.iter().fold(Aabb::EMPTY, |b, p| b.grow(*p));
+        let size = bounds.extent().x.max(bounds.extent().y).max(bounds.extent().z).max(1e-6);
+        let base = vec3(bounds.centroid().x, bounds.min.y, bounds.centroid().z);
+
+        self.scene.prototypes.push(Prototype::from_mesh(mesh));
+        let prototype = self.scene.prototypes.len() - 1;
+
+        let spread = (count as f32).sqrt() * 1.5;
+        for _ in 0..count {
+            let scale = (0.5 + random::<f32>()) / size;
+            let height = scale * (0.75 + 0.5 * random::<f32>());
+            let position = vec3((random::<f32>() - 0.5) * spread, 0.0, (random::<f32>() - 0.5) * spread);
+
+            let transform = Matrix4::from_translation(position)
+                * Matrix4::from_angle_y(Rad(2.0 * PI * random::<f32>()))
+                * Matrix4::from_nonuniform_scale(scale, height, scale)
+                * Matrix4::from_translation(-base);
+            let instance = Instance::new(prototype, transform)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "mesh too small to scale into instances"))?;
+            self.scene.instances.push(instance);
+        }
+
+        Ok(())
+    }
+
+    pub fn set_environment(&mut self, environment: Environment) {
+        self.scene.environment = Some(environment);
+        self.renderer.reset_frame_index();
+    }
+
+    pub fn get_stats(&self) -> RenderStats {
+        self.renderer.get_stats()
+    }
+
+    pub const fn get_last_render_time(&self) -> Duration { self.last_render_time }
+
+    fn render_elapsed(&self, buffer: &mut [u32]) {
         let t = self.last_render_time.as_millis() as u8;
         let mut x_offset = 0;
         if t > 9 {
@@ -169,3 +679,37 @@ impl App {
         render_into_buffer(buffer, c, x_offset, self.viewport_width);
     }
 }
+
+// A flint prism lying along the x axis with its edge down, tilted so light from above leaves it towards the camera
+fn prism_mesh(material_index: usize) -> Mesh {
+    let rotation = Matrix4::from_angle_x(Deg(35.0));
+    let corners = [vec3(0.0, -0.5, 0.0), vec3(0.0, 0.712, -0.7), vec3(0.0, 0.712, 0.7)]
+        .map(|c| (rotation * c.extend(0.0)).truncate());
+    let front = |i: usize| i;
+    let back = |i: usize| i + 3;
+
+    let mut positions: Vec<Vector3<f32>> = corners.iter().map(|c| c + vec3(1.5, 0.0, 0.0)).collect();
+    positions.extend(corners.iter().map(|c| c - vec3(1.5, 0.0, 0.0)));
+
+    // Counter-clockwise seen from outside
+    let mut faces = vec![[front(0), front(1), front(2)], [back(0), back(2), back(1)]];
+    for i in 0..3 {
+        let j = (i + 1) % 3;
+        faces.push([front(i), back(i), back(j)]);
+        faces.push([front(i), back(j), front(j)]);
+    }
+
+    Mesh {
+        positions,
+        triangles: faces
+            .into_iter()
+            .map(|positions| Triangle {
+                positions,
+                normals: None,
+                uvs: None,
+                material_index,
+            })
+            .collect(),
+        ..Mesh::default()
+    }
+}
@@ -0,0 +1,961 @@
+use cgmath::{vec2, vec3, ElementWise, InnerSpace, Vector2, Vector3, Zero};
+
+use crate::aov::AovPixel;
+use crate::bsdf;
+use crate::camera::Camera;
+use crate::light;
+use crate::medium::Medium;
+use crate::ray::Ray;
+use crate::renderer::{
+    delta_track, medium_behind, offset_origin, FreeFlight, HitPayload, PixelSample, Renderer, Vertex,
+    CAMERA_DIMENSIONS, SEGMENT_DIMENSIONS,
+};
+use crate::sampler::Sampler;
+use crate::scene::{Material, Scene};
+use crate::spectrum::{Channels, Dispersion};
+use crate::utils::{cosine_hemisphere_pdf, luminance, power_heuristic};
+
+// Light picked, position on it and direction leaving it
+const EMISSION_DIMENSIONS: u32 = 5;
+// Reserved for each pair of subpath lengths: a light sample, a lens position or an environment sample
+const CONNECTION_DIMENSIONS: u32 = 3;
+
+// Bidirectional path tracing (Veach 1997): a subpath from the camera and one from a light, with every prefix of the
+// one joined to every prefix of the other and each such strategy weighted against all others that could have made
+// the same path with the power heuristic. Light subpaths connected straight to the lens end up in the splats
+pub fn per_pixel(
+    renderer: &Renderer,
+    scene: &Scene,
+    camera: &Camera,
+    sampler: &mut dyn Sampler,
+    x: usize,
+    y: usize,
+    sample_index: u32,
+) -> PixelSample {
+    sampler.start_pixel_sample(x, y, sample_index);
+
+    let film_position = vec2(x as f32, y as f32) + sampler.get_2d();
+    let ray = camera.get_ray(film_position.x, film_position.y, sampler);
+    let channels = if renderer.get_spectral() {
+        Channels::sample_wavelengths(sampler.get_1d())
+    } else {
+        Channels::Rgb
+    };
+
+    let max_depth = renderer.get_max_depth() as usize;
+    let context = Context {
+        renderer,
+        scene,
+        camera,
+        channels,
+        time: ray.time,
+        max_vertices: max_depth + 1,
+    };
+
+    let mut camera_path = Vec::with_capacity(context.max_vertices);
+    camera_path.push(PathVertex::at(VertexKind::Camera, ray.origin, vec3(1.0, 1.0, 1.0), scene.atmosphere));
+    let pdf = camera.direction_pdf(ray.origin, ray.direction);
+    let escape = context.random_walk(ray, vec3(1.0, 1.0, 1.0), pdf, Transport::Radiance, &mut camera_path, sampler);
+
+    let mut light_path = Vec::with_capacity(context.max_vertices);
+    context.start_light_path(&mut light_path, sampler);
+
+    let mut light = Vector3::zero();
+    let mut splats = Vec::new();
+    // Paths of at most two segments, to match the path tracer's direct pass
+    let mut direct = Vector3::zero();
+
+    if let Some(escape) = escape {
+        let value = context.escaped(&escape, camera_path.last().unwrap());
+        light += value;
+        if camera_path.len() <= 2 {
+            direct += value;
+        }
+    }
+
+    let connection_start = context.first_dimension(Part::Connections);
+    for t in 1..=camera_path.len() {
+        for s in 0..=light_path.len() {
+            if (s == 1 && t == 1) || s + t < 2 || s + t > max_depth + 2 {
+                continue;
+            }
+
+            sampler.start_dimension(connection_start + (s * (max_depth + 2) + t) as u32 * CONNECTION_DIMENSIONS);
+
+            // Light subpaths can't hit the environment, so it only takes part in the path tracer's weighting
+            let value = if s == 0 && t >= 2 {
+                context.connect_emitter(&camera_path, t) + context.sample_environment(&camera_path[t - 1], sampler)
+            } else {
+                let Some((value, film)) = context.connect(&light_path, &camera_path, s, t, sampler) else {
+                    continue;
+                };
+                if let Some(film) = film {
+                    splats.push((film, channels.to_rgb(value)));
+                    continue;
+                }
+                value
+            };
+
+            light += value;
+            if s + t <= 3 {
+                direct += value;
+            }
+        }
+    }
+
+    let direct = channels.to_rgb(direct);
+    let light = channels.to_rgb(light);
+    let aov = AovPixel {
+        direct,
+        indirect: light - direct,
+        samples: 1,
+        luminance: luminance(light),
+        luminance_squared: luminance(light).powi(2),
+        ..context.first_hit_aov(&camera_path)
+    };
+
+    PixelSample {
+        film_position,
+        color: light,
+        path_length: (camera_path.len() - 1 + light_path.len().saturating_sub(1)) as u32,
+        aov,
+        splats,
+    }
+}
+
+// The blocks of sampler dimensions of a sample, after the camera ray's
+enum Part {
+    CameraPath,
+    LightPath,
+    Connections,
+}
+
+#[derive(Clone, Copy)]
+enum Transport {
+    // Camera subpaths, carrying the radiance arriving back along them
+    Radiance,
+    // Light subpaths, carrying the importance of the camera. BSDFs with shading normals aren't symmetric for it
+    Importance,
+}
+
+// What a vertex of a subpath sits on
+enum VertexKind {
+    // A point on the lens
+    Camera,
+    // A point on an emitter a light subpath starts from
+    Light { primitive_index: usize },
+    Surface { payload: HitPayload, material: Material },
+    Medium { medium: Medium },
+}
+
+struct PathVertex {
+    kind: VertexKind,
+    position: Vector3<f32>,
+    // Facing the side the subpath arrived from like `HitPayload`, outward for lights and zero elsewhere
+    geometric_normal: Vector3<f32>,
+    // Direction of the segment arriving at the vertex
+    incoming: Vector3<f32>,
+    // Throughput from the start of the subpath up to the vertex over the densities it was sampled with
+    beta: Vector3<f32>,
+    // Area densities of sampling this vertex from the previous one of its subpath, and from the next one if the
+    // subpath was traced the other way
+    pdf_fwd: f32,
+    pdf_rev: f32,
+    // Left along a delta lobe, no connection can go through it
+    delta: bool,
+    // A dispersive surface was hit up to here, leaving only the hero wavelength valid
+    dispersed: bool,
+    // What the vertex sits in, on the side the subpath arrived from
+    medium: Option<usize>,
+}
+
+impl PathVertex {
+    fn at(kind: VertexKind, position: Vector3<f32>, beta: Vector3<f32>, medium: Option<usize>) -> Self {
+        Self {
+            kind,
+            position,
+            geometric_normal: Vector3::zero(),
+            incoming: Vector3::zero(),
+            beta,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+            delta: false,
+            dispersed: false,
+            medium,
+        }
+    }
+
+    fn is_on_surface(&self) -> bool {
+        matches!(self.kind, VertexKind::Surface { .. } | VertexKind::Light { .. })
+    }
+
+    fn is_connectible(&self) -> bool {
+        match &self.kind {
+            VertexKind::Surface { material, .. } => !bsdf::is_specular(material),
+            _ => true,
+        }
+    }
+
+    // A solid angle density of leaving this vertex as an area density at `next`
+    fn to_area(&self, pdf: f32, next: &PathVertex) -> f32 {
+        let offset = next.position - self.position;
+        let distance_squared = offset.magnitude2();
+        if distance_squared == 0.0 {
+            return 0.0;
+        }
+
+        let pdf = pdf / distance_squared;
+        if next.is_on_surface() {
+            pdf * next.geometric_normal.dot(offset).abs() / distance_squared.sqrt()
+        } else {
+            pdf
+        }
+    }
+
+    // BSDF times the cosine, or the phase function, for scattering from where the subpath arrived towards `next`
+    fn scatter(&self, next: &PathVertex, transport: Transport) -> Vector3<f32> {
+        let direction = (next.position - self.position).normalize();
+        match &self.kind {
+            VertexKind::Surface { payload, material } => {
+                let view = -self.incoming;
+                let Some((value, _)) = bsdf::evaluate(material, view, direction, payload.world_normal) else {
+                    return Vector3::zero();
+                };
+                match transport {
+                    Transport::Radiance => value,
+                    Transport::Importance => value * shading_correction(payload, view, direction),
+                }
+            }
+            VertexKind::Medium { medium } => {
+                let phase = medium.phase(self.incoming, direction);
+                vec3(phase, phase, phase)
+            }
+            VertexKind::Camera | VertexKind::Light { .. } => Vector3::zero(),
+        }
+    }
+
+    // Area density of sampling `next` from this vertex, had the subpath arrived from `previous`
+    fn pdf(&self, camera: &Camera, previous: Option<&PathVertex>, next: &PathVertex) -> f32 {
+        let direction = (next.position - self.position).normalize();
+        // Media can scatter right where the previous vertex is, which leaves no direction to have arrived from
+        let incoming = previous
+            .map(|previous| self.position - previous.position)
+            .filter(|incoming| !incoming.is_zero())
+            .map(|incoming| incoming.normalize());
+
+        let pdf = match (&self.kind, incoming) {
+            (VertexKind::Camera, _) => camera.direction_pdf(self.position, direction),
+            (VertexKind::Light { .. }, _) => return self.emission_pdf(next),
+            (VertexKind::Surface { payload, material }, Some(incoming)) => {
+                bsdf::evaluate(material, -incoming, direction, payload.world_normal).map_or(0.0, |(_, pdf)| pdf)
+            }
+            (VertexKind::Medium { medium }, Some(incoming)) => medium.phase(incoming, direction),
+            (_, None) => 0.0,
+        };
+
+        self.to_area(pdf, next)
+    }
+
+    // Area density of a light subpath leaving this emitter towards `next`
+    fn emission_pdf(&self, next: &PathVertex) -> f32 {
+        let direction = (next.position - self.position).normalize();
+        self.to_area(cosine_hemisphere_pdf(self.geometric_normal.dot(direction).max(0.0)), next)
+    }
+
+    fn shadow_origin(&self, direction: Vector3<f32>) -> Vector3<f32> {
+        match &self.kind {
+            VertexKind::Surface { payload, .. } => offset_origin(payload, direction),
+            _ => self.position,
+        }
+    }
+}
+
+// Shading normals break the symmetry of the BSDF, light subpaths make up for it (Veach 1996)
+fn shading_correction(payload: &HitPayload, view: Vector3<f32>, direction: Vector3<f32>) -> f32 {
+    let denominator = view.dot(payload.geometric_normal).abs() * direction.dot(payload.world_normal).abs();
+    if denominator == 0.0 {
+        return 0.0;
+    }
+
+    view.dot(payload.world_normal).abs() * direction.dot(payload.geometric_normal).abs() / denominator
+}
+
+// Where a camera subpath left the scene
+struct Escape {
+    direction: Vector3<f32>,
+    beta: Vector3<f32>,
+    pdf: f32,
+    specular: bool,
+}
+
+// Everything a pixel sample's subpaths and connections share
+struct Context<'a> {
+    renderer: &'a Renderer,
+    scene: &'a Scene,
+    camera: &'a Camera,
+    channels: Channels,
+    time: f32,
+    // Of either subpath. The camera's is as long as a path of the path tracer, so its last vertex can still be
+    // connected to a light
+    max_vertices: usize,
+}
+
+impl Context<'_> {
+    fn first_dimension(&self, part: Part) -> u32 {
+        let subpath = self.max_vertices as u32 * SEGMENT_DIMENSIONS;
+        match part {
+            Part::CameraPath => CAMERA_DIMENSIONS,
+            Part::LightPath => CAMERA_DIMENSIONS + subpath,
+            Part::Connections => CAMERA_DIMENSIONS + subpath + EMISSION_DIMENSIONS + subpath,
+        }
+    }
+
+    // Starts a light subpath on a light and follows it as far as it goes
+    fn start_light_path(&self, path: &mut Vec<PathVertex>, sampler: &mut dyn Sampler) {
+        sampler.start_dimension(self.first_dimension(Part::LightPath));
+        let Some(emission) = light::sample_emission(self.scene, self.time, sampler) else {
+            return;
+        };
+        let radiance = self.channels.color(emission.radiance);
+        if radiance.is_zero() {
+            return;
+        }
+
+        path.push(PathVertex {
+            geometric_normal: emission.normal,
+            pdf_fwd: emission.position_pdf,
+            ..PathVertex::at(
+                VertexKind::Light { primitive_index: emission.primitive_index },
+                emission.position,
+                radiance,
+                self.scene.atmosphere,
+            )
+        });
+
+        if emission.direction_pdf <= 0.0 {
+            return;
+        }
+        let cos_theta = emission.normal.dot(emission.direction);
+        let beta = radiance * (cos_theta / (emission.position_pdf * emission.direction_pdf));
+        let ray = Ray {
+            origin: emission.position + emission.normal * 0.0001,
+            direction: emission.direction,
+            time: self.time,
+        };
+        self.random_walk(ray, beta, emission.direction_pdf, Transport::Importance, path, sampler);
+    }
+
+    // Extends a subpath from its last vertex, which `ray` leaves with solid angle density `pdf`, until it is full or
+    // is absorbed, terminated or escapes the scene, which is returned
+    fn random_walk(
+        &self,
+        mut ray: Ray,
+        mut beta: Vector3<f32>,
+        mut pdf: f32,
+        transport: Transport,
+        path: &mut Vec<PathVertex>,
+        sampler: &mut dyn Sampler,
+    ) -> Option<Escape> {
+        let scene = self.scene;
+        let max_vertices = self.max_vertices;
+        let first_dimension = match transport {
+            Transport::Radiance => self.first_dimension(Part::CameraPath),
+            Transport::Importance => self.first_dimension(Part::LightPath) + EMISSION_DIMENSIONS,
+        };
+        let mut medium = path.last()?.medium;
+        let mut specular = true;
+        let mut dispersed = false;
+        let mut segment = 0;
+
+        // Light subpaths start out with the power of the light, Russian roulette goes by the throughput relative
+        // to where they started
+        let scale = beta.x.max(beta.y).max(beta.z);
+        let russian_roulette = |depth: usize, beta: &mut Vector3<f32>, sampler: &mut dyn Sampler| {
+            let mut relative = *beta / scale;
+            let survived = self.renderer.russian_roulette(depth as u32, &mut relative, sampler);
+            *beta = relative * scale;
+            survived
+        };
+
+        while path.len() < max_vertices {
+            sampler.start_dimension(first_dimension + segment * SEGMENT_DIMENSIONS);
+            segment += 1;
+
+            let previous = path.len() - 1;
+            let hit = self.renderer.trace_ray(&ray, scene);
+
+            if let Some(medium_index) = medium {
+                let hit_distance = hit.as_ref().map_or(f32::MAX, |payload| payload.hit_distance);
+                let current = self.channels.medium(&scene.media[medium_index]);
+                match delta_track(&current, hit_distance, &mut beta, sampler) {
+                    FreeFlight::Reached => {}
+                    FreeFlight::Absorbed => return None,
+                    FreeFlight::Scattered(distance) => {
+                        let position = ray.origin + ray.direction * distance;
+                        let mut vertex = PathVertex {
+                            incoming: ray.direction,
+                            dispersed,
+                            ..PathVertex::at(VertexKind::Medium { medium: current }, position, beta, medium)
+                        };
+                        vertex.pdf_fwd = path[previous].to_area(pdf, &vertex);
+                        path.push(vertex);
+                        if path.len() == max_vertices {
+                            return None;
+                        }
+
+                        // The phase function is symmetric, so it samples the way back with the same density
+                        let (direction, phase_pdf) = current.sample_phase(ray.direction, sampler.get_2d());
+                        path[previous].pdf_rev = path[previous + 1].to_area(phase_pdf, &path[previous]);
+                        pdf = phase_pdf;
+                        specular = false;
+
+                        if !russian_roulette(path.len() - 1, &mut beta, sampler) {
+                            return None;
+                        }
+
+                        ray = Ray { origin: position, direction, time: ray.time };
+                        continue;
+                    }
+                }
+            }
+
+            let Some(payload) = hit else {
+                return Some(Escape { direction: ray.direction, beta, pdf, specular });
+            };
+
+            let resolved = scene.materials[payload.material_index].resolve(&scene.textures, payload.uv, payload.world_position);
+            let material = self.channels.material(&resolved);
+
+            if material.is_interface() {
+                beta = beta.mul_element_wise(material.albedo);
+                medium = medium_behind(scene, &material, payload.front_face);
+                ray.origin = offset_origin(&payload, ray.direction);
+                continue;
+            }
+
+            dispersed |= material.dispersion != Dispersion::None;
+
+            // The last vertex only gets connected, not scattered from
+            let is_last = path.len() + 1 == max_vertices;
+            let sample = if is_last {
+                None
+            } else {
+                bsdf::sample(&material, ray.direction, payload.world_normal, payload.front_face, sampler)
+            };
+            let reverse_pdf = match &sample {
+                Some(sample) if !sample.specular => {
+                    bsdf::evaluate(&material, sample.direction, -ray.direction, payload.world_normal)
+                        .map_or(0.0, |(_, pdf)| pdf)
+                }
+                _ => 0.0,
+            };
+            let correction = match (&sample, transport) {
+                (Some(sample), Transport::Importance) => shading_correction(&payload, -ray.direction, sample.direction),
+                _ => 1.0,
+            };
+
+            let (position, geometric_normal) = (payload.world_position, payload.geometric_normal);
+            let transmitted_medium = medium_behind(scene, &material, payload.front_face);
+            let next_origin = sample.as_ref().map(|sample| offset_origin(&payload, sample.direction));
+            let mut vertex = PathVertex {
+                geometric_normal,
+                incoming: ray.direction,
+                delta: sample.as_ref().is_some_and(|sample| sample.specular),
+                dispersed,
+                ..PathVertex::at(VertexKind::Surface { payload, material }, position, beta, medium)
+            };
+            vertex.pdf_fwd = path[previous].to_area(pdf, &vertex);
+            path.push(vertex);
+
+            let (Some(sample), Some(origin)) = (sample, next_origin) else {
+                return None;
+            };
+            path[previous].pdf_rev = path[previous + 1].to_area(reverse_pdf, &path[previous]);
+
+            beta = beta.mul_element_wise(sample.weight) * correction;
+            pdf = if sample.specular { 0.0 } else { sample.pdf };
+            specular = sample.specular;
+
+            if !russian_roulette(path.len() - 1, &mut beta, sampler) {
+                return None;
+            }
+
+            // Transmitted rays continue in whatever fills the other side
+            if sample.direction.dot(geometric_normal) < 0.0 {
+                medium = transmitted_medium;
+            }
+
+            ray = Ray {
+                origin,
+                direction: sample.direction,
+                time: ray.time,
+            };
+        }
+
+        None
+    }
+
+    // Radiance of what a camera subpath escaped into, weighted against sampling the environment like the path tracer
+    fn escaped(&self, escape: &Escape, last: &PathVertex) -> Vector3<f32> {
+        let radiance = if let Some(environment) = &self.scene.environment {
+            let weight = if escape.specular {
+                1.0
+            } else {
+                power_heuristic(escape.pdf, environment.pdf(escape.direction))
+            };
+            environment.radiance(escape.direction) * weight
+        } else if self.scene.global_illumination {
+            vec3(0.6, 0.7, 0.9)
+        } else {
+            return Vector3::zero();
+        };
+
+        self.hero_only(self.channels.color(radiance).mul_element_wise(escape.beta), last.dispersed)
+    }
+
+    // Next-event estimation towards the environment from a camera vertex, as the path tracer does it
+    fn sample_environment(&self, vertex: &PathVertex, sampler: &mut dyn Sampler) -> Vector3<f32> {
+        if self.scene.environment.is_none() || !vertex.is_connectible() {
+            return Vector3::zero();
+        }
+
+        let ray = Ray {
+            origin: vertex.position,
+            direction: vertex.incoming,
+            time: self.time,
+        };
+        let scatter = match &vertex.kind {
+            VertexKind::Surface { payload, material } => Vertex::Surface { payload, material },
+            VertexKind::Medium { medium } => Vertex::Medium { position: vertex.position, medium },
+            VertexKind::Camera | VertexKind::Light { .. } => return Vector3::zero(),
+        };
+
+        let value = self
+            .renderer
+            .sample_environment_light(self.scene, &ray, &scatter, vertex.medium, self.channels, sampler);
+        self.hero_only(value.mul_element_wise(vertex.beta), vertex.dispersed)
+    }
+
+    // A camera subpath that ended on an emitter by itself
+    fn connect_emitter(&self, camera_path: &[PathVertex], t: usize) -> Vector3<f32> {
+        let pt = &camera_path[t - 1];
+        let VertexKind::Surface { payload, material } = &pt.kind else {
+            return Vector3::zero();
+        };
+        if !payload.front_face || material.emission_power <= 0.0 {
+            return Vector3::zero();
+        }
+
+        let value = material.get_emission().mul_element_wise(pt.beta);
+        self.hero_only(value, pt.dispersed) * self.mis_weight(&[], camera_path, None, 0, t)
+    }
+
+    // Joins the first `s` vertices of the light subpath with the first `t` of the camera subpath, sampling a new
+    // end vertex on a light for `s` = 1 or on the lens for `t` = 1. The latter also returns the film position the
+    // light subpath lands on
+    fn connect(
+        &self,
+        light_path: &[PathVertex],
+        camera_path: &[PathVertex],
+        s: usize,
+        t: usize,
+        sampler: &mut dyn Sampler,
+    ) -> Option<(Vector3<f32>, Option<Vector2<f32>>)> {
+        let (value, sampled, film, dispersed) = if t == 1 {
+            let qs = &light_path[s - 1];
+            if !qs.is_connectible() {
+                return None;
+            }
+
+            let importance = self.camera.sample_importance(qs.position, sampler.get_2d())?;
+            let lens = PathVertex::at(VertexKind::Camera, importance.lens_position, Vector3::zero(), self.scene.atmosphere);
+            let value = qs.beta.mul_element_wise(qs.scatter(&lens, Transport::Importance)) * importance.weight;
+            if value.is_zero() {
+                return None;
+            }
+
+            let value = value.mul_element_wise(self.transmittance(qs, &lens));
+            (value, Some(lens), Some(importance.film_position), qs.dispersed)
+        } else if s == 1 {
+            let pt = &camera_path[t - 1];
+            if !pt.is_connectible() {
+                return None;
+            }
+
+            // Camera subpaths ending on a light may sample the point they are at, closer than any ray offset
+            let light_sample = light::sample(self.scene, pt.position, self.time, sampler)
+                .filter(|light_sample| light_sample.distance > 0.0001 && light_sample.pdf > 0.0)?;
+            let light = PathVertex {
+                geometric_normal: light_sample.normal,
+                pdf_fwd: light::origin_pdf(self.scene, self.time, light_sample.primitive_index),
+                ..PathVertex::at(
+                    VertexKind::Light { primitive_index: light_sample.primitive_index },
+                    pt.position + light_sample.direction * light_sample.distance,
+                    self.channels.color(light_sample.radiance) / light_sample.pdf,
+                    self.scene.atmosphere,
+                )
+            };
+            let value = pt.beta.mul_element_wise(pt.scatter(&light, Transport::Radiance)).mul_element_wise(light.beta);
+            if value.is_zero() {
+                return None;
+            }
+
+            let value = value.mul_element_wise(self.transmittance(pt, &light));
+            (value, Some(light), None, pt.dispersed)
+        } else {
+            let (qs, pt) = (&light_path[s - 1], &camera_path[t - 1]);
+            if !qs.is_connectible() || !pt.is_connectible() {
+                return None;
+            }
+
+            let distance_squared = (qs.position - pt.position).magnitude2();
+            if distance_squared == 0.0 {
+                return None;
+            }
+
+            // Both scattering terms already include their cosine, which leaves the inverse square of the distance
+            let value = qs
+                .beta
+                .mul_element_wise(qs.scatter(pt, Transport::Importance))
+                .mul_element_wise(pt.scatter(qs, Transport::Radiance))
+                .mul_element_wise(pt.beta)
+                / distance_squared;
+            if value.is_zero() {
+                return None;
+            }
+
+            let value = value.mul_element_wise(self.transmittance(pt, qs));
+            (value, None, None, qs.dispersed || pt.dispersed)
+        };
+
+        if value.is_zero() {
+            return None;
+        }
+
+        let weight = self.mis_weight(light_path, camera_path, sampled.as_ref(), s, t);
+        Some((self.hero_only(value, dispersed) * weight, film))
+    }
+
+    // Power heuristic over every strategy that could have made the joined path, as ratios of the densities of
+    // each strategy to this one walking away from the connection on both sides (Veach 1997, pbrt's formulation).
+    // Delta vertices can't be connected through, which rules out the strategies ending on them
+    fn mis_weight(
+        &self,
+        light_path: &[PathVertex],
+        camera_path: &[PathVertex],
+        sampled: Option<&PathVertex>,
+        s: usize,
+        t: usize,
+    ) -> f32 {
+        if s + t == 2 {
+            return 1.0;
+        }
+
+        // Ends of the two subpaths, with a vertex sampled for the connection standing in where there is one
+        let qs = match s {
+            0 => None,
+            1 => sampled,
+            _ => Some(&light_path[s - 1]),
+        };
+        let pt = match (t, sampled) {
+            (1, Some(sampled)) => sampled,
+            (1, None) => return 0.0,
+            _ => &camera_path[t - 1],
+        };
+        let qs_minus = (s > 1).then(|| &light_path[s - 2]);
+        let pt_minus = (t > 1).then(|| &camera_path[t - 2]);
+
+        // Densities of walking through the connection in the other direction, the rest are as the walks left them
+        let pt_rev = match qs {
+            Some(qs) => qs.pdf(self.camera, qs_minus, pt),
+            None => match &pt.kind {
+                VertexKind::Surface { payload, .. } => light::origin_pdf(self.scene, self.time, payload.object_index),
+                _ => 0.0,
+            },
+        };
+        // Emitters light subpaths never start from, like triangles, leave no other way to find them
+        if s == 0 && pt_rev == 0.0 {
+            return 1.0;
+        }
+        let pt_minus_rev = pt_minus.map_or(0.0, |pt_minus| match qs {
+            Some(qs) => pt.pdf(self.camera, Some(qs), pt_minus),
+            None => pt.emission_pdf(pt_minus),
+        });
+        let qs_rev = qs.map_or(0.0, |qs| pt.pdf(self.camera, pt_minus, qs));
+        let qs_minus_rev = match (qs, qs_minus) {
+            (Some(qs), Some(qs_minus)) => qs.pdf(self.camera, Some(pt), qs_minus),
+            _ => 0.0,
+        };
+
+        let remap = |pdf: f32| if pdf != 0.0 { pdf } else { 1.0 };
+        let mut sum = 0.0;
+
+        let mut ratio = 1.0;
+        for i in (1..t).rev() {
+            let (pdf_fwd, pdf_rev) = match t - 1 - i {
+                0 => (pt.pdf_fwd, pt_rev),
+                1 => (camera_path[i].pdf_fwd, pt_minus_rev),
+                _ => (camera_path[i].pdf_fwd, camera_path[i].pdf_rev),
+            };
+            ratio *= remap(pdf_rev) / remap(pdf_fwd);
+
+            let delta = i != t - 1 && camera_path[i].delta;
+            if !delta && !camera_path[i - 1].delta {
+                sum += ratio * ratio;
+            }
+        }
+
+        let mut ratio = 1.0;
+        for i in (0..s).rev() {
+            let (pdf_fwd, pdf_rev) = match s - 1 - i {
+                0 => (qs.map_or(0.0, |qs| qs.pdf_fwd), qs_rev),
+                1 => (light_path[i].pdf_fwd, qs_minus_rev),
+                _ => (light_path[i].pdf_fwd, light_path[i].pdf_rev),
+            };
+            ratio *= remap(pdf_rev) / remap(pdf_fwd);
+
+            let delta = i != s - 1 && light_path[i].delta;
+            let previous_delta = i > 0 && light_path[i - 1].delta;
+            if !delta && !previous_delta {
+                sum += ratio * ratio;
+            }
+        }
+
+        1.0 / (1.0 + sum)
+    }
+
+    // Of the segment joining two vertices, zero if something opaque is in between
+    fn transmittance(&self, from: &PathVertex, to: &PathVertex) -> Vector3<f32> {
+        let offset = to.position - from.position;
+        let distance = offset.magnitude();
+        let direction = offset / distance;
+        let ray = Ray {
+            origin: from.shadow_origin(direction),
+            direction,
+            time: self.time,
+        };
+
+        // Lights are sampled on their visible side and may be hit as a whole, anything else has to be reached
+        // without hitting anything on the way, stopping just short of its own surface
+        let (distance, target) = match to.kind {
+            VertexKind::Light { primitive_index } => (distance * 1.001, Some(primitive_index)),
+            _ => (distance * 0.999, None),
+        };
+
+        self.renderer.transmittance(self.scene, ray, distance, target, from.medium, self.channels)
+    }
+
+    // Once a path went through a dispersive surface only the hero wavelength holds
+    fn hero_only(&self, mut value: Vector3<f32>, dispersed: bool) -> Vector3<f32> {
+        if dispersed {
+            let mut channels = self.channels;
+            channels.terminate_secondary(&mut value);
+        }
+        value
+    }
+
+    // Data passes taken where the camera subpath first scattered
+    fn first_hit_aov(&self, camera_path: &[PathVertex]) -> AovPixel {
+        let Some(vertex) = camera_path.get(1) else {
+            return AovPixel {
+                albedo: vec3(1.0, 1.0, 1.0),
+                ..AovPixel::default()
+            };
+        };
+        let depth = (vertex.position - camera_path[0].position).magnitude();
+
+        match &vertex.kind {
+            VertexKind::Surface { payload, .. } => {
+                let material = &self.scene.materials[payload.material_index];
+                AovPixel {
+                    albedo: material.resolve(&self.scene.textures, payload.uv, payload.world_position).albedo,
+                    normal: payload.world_normal,
+                    depth,
+                    position: payload.world_position,
+                    material_index: Some(payload.material_index),
+                    object_index: Some(payload.object_index),
+                    ..AovPixel::default()
+                }
+            }
+            VertexKind::Medium { .. } => {
+                let rgb = vertex.medium.map(|index| &self.scene.media[index]);
+                AovPixel {
+                    albedo: rgb.map_or(vec3(1.0, 1.0, 1.0), |rgb| {
+                        rgb.scattering.zip(rgb.extinction(), |s, t| if t > 0.0 { s / t } else { 1.0 })
+                    }),
+                    normal: -vertex.incoming,
+                    depth,
+                    position: vertex.position,
+                    ..AovPixel::default()
+                }
+            }
+            VertexKind::Camera | VertexKind::Light { .. } => AovPixel::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aov::Aov;
+    use crate::filter::Filter;
+    use crate::integrator::Integrator;
+    use crate::sampler::SamplerKind;
+    use crate::scene::Object;
+    use crate::shape::Quad;
+
+    // A closed gray box around the camera, lit by a small quad just below the ceiling
+    fn box_scene() -> (Scene, Camera) {
+        let center = vec3(0.0, 0.0, 6.0);
+        let mut scene = Scene::default();
+        scene.materials.push(Material::lambertian(vec3(0.5, 0.5, 0.5)));
+        scene.materials.push(Material::lambertian(vec3(1.0, 1.0, 1.0)).emissive(5.0));
+
+        for axis in [Vector3::unit_x(), Vector3::unit_y(), Vector3::unit_z()] {
+            let (edge_u, edge_v) = (vec3(axis.y, axis.z, axis.x) * 2.0, vec3(axis.z, axis.x, axis.y) * 2.0);
+            for side in [-1.0, 1.0] {
+                scene.objects.push(Object {
+                    shape: Box::new(Quad {
+                        corner: center + axis * side - (edge_u + edge_v) * 0.5,
+                        edge_u,
+                        edge_v,
+                    }),
+                    material_index: 0,
+                });
+            }
+        }
+        scene.objects.push(Object {
+            shape: Box::new(Quad {
+                corner: center + vec3(-0.3, 0.99, -0.3),
+                edge_u: vec3(0.6, 0.0, 0.0),
+                edge_v: vec3(0.0, 0.0, 0.6),
+            }),
+            material_index: 1,
+        });
+        scene.rebuild();
+
+        let mut camera = Camera::new(45.0, 0.1, 100.0);
+        camera.on_resize(16, 16);
+        (scene, camera)
+    }
+
+    fn mean_radiance(integrator: Integrator, scene: &Scene, camera: &Camera, frames: usize) -> f32 {
+        let mut renderer = Renderer::default();
+        renderer.on_resize(16, 16);
+        renderer.set_integrator(integrator);
+        renderer.set_filter(Filter::Box);
+        renderer.set_max_depth(5);
+
+        let mut finished = 0;
+        while finished < frames {
+            if renderer.accumulate(scene, camera, None) {
+                finished += 1;
+            }
+        }
+
+        let values = renderer.get_aov_values(Aov::Beauty, 16, 16);
+        values.iter().map(|value| luminance(*value)).sum::<f32>() / values.len() as f32
+    }
+
+    #[test]
+    fn matches_path_tracer_in_expectation() {
+        let (scene, camera) = box_scene();
+        let path = mean_radiance(Integrator::Path, &scene, &camera, 64);
+        let bidirectional = mean_radiance(Integrator::Bidirectional, &scene, &camera, 64);
+
+        assert!(path > 0.0);
+        assert!((bidirectional / path - 1.0).abs() < 0.02, "{bidirectional} against {path}");
+    }
+
+    #[test]
+    fn mis_weights_of_a_path_sum_to_one() {
+        let (scene, camera) = box_scene();
+        let renderer = Renderer::default();
+        let context = Context {
+            renderer: &renderer,
+            scene: &scene,
+            camera: &camera,
+            channels: Channels::Rgb,
+            time: 0.0,
+            max_vertices: 8,
+        };
+
+        let mut sampler = SamplerKind::Independent.create();
+        sampler.start_pixel_sample(0, 0, 0);
+        let ray = camera.get_ray(8.0, 8.0, sampler.as_mut());
+        let trace = |from: Vector3<f32>, to: Vector3<f32>| {
+            let direction = (to - from).normalize();
+            renderer.trace_ray(&Ray { origin: from + direction * 0.0001, direction, time: 0.0 }, &scene).unwrap()
+        };
+
+        // From the lens over the walls of the box to the light, bouncing once, twice and three times
+        let first = ray.origin + ray.direction * trace(ray.origin, ray.origin + ray.direction).hit_distance;
+        let bounces = [vec3(0.5, -1.0, 6.2), vec3(1.0, 0.2, 5.8), vec3(-0.4, -0.3, 5.0)];
+        let light_point = vec3(0.1, 0.99, 6.1);
+        let light_index = trace(first, light_point).object_index;
+        assert!(scene.lights.contains(&light_index));
+
+        for bounce_count in 0..=bounces.len() {
+            let mut positions = vec![ray.origin, first];
+            for target in &bounces[..bounce_count] {
+                positions.push(trace(*positions.last().unwrap(), *target).world_position);
+            }
+            positions.push(light_point);
+            let k = positions.len();
+
+            // Vertex `i` of the full path as reached from `from`, the light end starting a light subpath is on the
+            // emitter rather than its surface
+            let vertex = |i: usize, from: Option<usize>| {
+                if i == 0 {
+                    return PathVertex::at(VertexKind::Camera, positions[0], vec3(1.0, 1.0, 1.0), None);
+                }
+                let payload = trace(positions[i - 1], positions[i]);
+                let (position, geometric_normal) = (positions[i], payload.geometric_normal);
+                let Some(from) = from else {
+                    return PathVertex {
+                        geometric_normal: vec3(0.0, -1.0, 0.0),
+                        pdf_fwd: light::origin_pdf(&scene, 0.0, light_index),
+                        ..PathVertex::at(VertexKind::Light { primitive_index: light_index }, position, Vector3::zero(), None)
+                    };
+                };
+                let material = scene.materials[payload.material_index];
+                PathVertex {
+                    geometric_normal,
+                    incoming: (position - positions[from]).normalize(),
+                    ..PathVertex::at(VertexKind::Surface { payload, material }, position, Vector3::zero(), None)
+                }
+            };
+
+            // Densities both ways along a subpath, as the random walk leaves them
+            let subpath = |mut path: Vec<PathVertex>| {
+                for i in 1..path.len() {
+                    path[i].pdf_fwd = path[i - 1].pdf(&camera, i.checked_sub(2).map(|j| &path[j]), &path[i]);
+                }
+                for i in 0..path.len().saturating_sub(2) {
+                    path[i].pdf_rev = path[i + 1].pdf(&camera, path.get(i + 2), &path[i]);
+                }
+                path
+            };
+            let camera_path = subpath((0..k).map(|i| vertex(i, i.checked_sub(1))).collect());
+            let light_path = subpath(
+                (0..k)
+                    .rev()
+                    .map(|i| vertex(i, (i + 1 < k).then_some(i + 1)))
+                    .collect(),
+            );
+
+            let total: f32 = (0..k)
+                .map(|s| {
+                    let t = k - s;
+                    let sampled = match (s, t) {
+                        (1, _) => Some(vertex(k - 1, None)),
+                        (_, 1) => Some(vertex(0, None)),
+                        _ => None,
+                    };
+                    context.mis_weight(&light_path[..s], &camera_path[..t], sampled.as_ref(), s, t)
+                })
+                .sum();
+            assert!((total - 1.0).abs() < 1e-4, "{total} for {k} vertices");
+        }
+    }
+}
@@ -0,0 +1,99 @@
+use std::time::{Duration, Instant};
+
+use cgmath::vec2;
+use rand::random;
+
+use crate::camera::Camera;
+use crate::ray::Ray;
+use crate::scene::{Hit, Scene};
+use crate::utils::sample_uniform_sphere;
+
+// Each traversal is timed this many times and the fastest run kept, the others mostly measure the machine
+const RUNS: usize = 3;
+// The linear scan only traces every this many rays, all of them would take minutes on large meshes
+const LINEAR_STRIDE: usize = 10;
+
+pub struct BenchResult {
+    pub rays: usize,
+    // Extrapolated from the rays the linear scan traced to all of them
+    pub linear: Duration,
+    pub scalar: Duration,
+    pub wide: Duration,
+    // Rays of the linear scan either BVH disagrees with on the closest primitive
+    pub mismatches: usize,
+}
+
+impl BenchResult {
+    pub fn rays_per_second(&self, time: Duration) -> f64 {
+        self.rays as f64 / time.as_secs_f64()
+    }
+}
+
+// Traces the same rays through every primitive in turn, the baseline, through the binary BVH one primitive at a
+// time and through the wide BVH with sphere packets, on a single thread so only the traversal is measured
+pub fn run(scene: &Scene, camera: &Camera, count: usize) -> BenchResult {
+    let rays = generate_rays(scene, camera, count);
+
+    let time = |stride: usize, intersect: &dyn Fn(&Ray) -> Option<Hit>| {
+        let mut hits = Vec::with_capacity(rays.len() / stride + 1);
+        let mut best = Duration::MAX;
+        for _ in 0..RUNS {
+            hits.clear();
+            let start = Instant::now();
+            hits.extend(rays.iter().step_by(stride).map(intersect));
+            best = best.min(start.elapsed());
+        }
+        (best, hits)
+    };
+
+    let (linear, linear_hits) = time(LINEAR_STRIDE, &|ray| scene.intersect_linear(ray, f32::MAX));
+    let (scalar, scalar_hits) = time(1, &|ray| scene.intersect_scalar(ray, f32::MAX));
+    let (wide, wide_hits) = time(1, &|ray| scene.intersect(ray, f32::MAX));
+
+    let primitive = |hit: &Option<Hit>| hit.map(|hit| hit.primitive);
+    let mismatches = linear_hits
+        .iter()
+        .zip(scalar_hits.iter().step_by(LINEAR_STRIDE).zip(wide_hits.iter().step_by(LINEAR_STRIDE)))
+        .filter(|(expected, (scalar, wide))| primitive(scalar) != primitive(expected) || primitive(wide) != primitive(expected))
+        .count();
+
+    BenchResult {
+        rays: rays.len(),
+        linear: linear * LINEAR_STRIDE as u32,
+        scalar,
+        wide,
+        mismatches,
+    }
+}
+
+// Half camera rays through random points on the film and half leaving their hits in random directions, like the
+// coherent first and incoherent later segments of a path
+fn generate_rays(scene: &Scene, camera: &Camera, count: usize) -> Vec<Ray> {
+    let mut rays = Vec::with_capacity(count);
+
+    while rays.len() < count {
+        let film = vec2(
+            random::<f32>() * camera.viewport_width as f32,
+            random::<f32>() * camera.viewport_height as f32,
+        );
+        let primary = Ray {
+            origin: camera.get_position(),
+            direction: camera.get_direction(film.x, film.y),
+            time: random(),
+        };
+
+        if let Some(hit) = scene.intersect(&primary, f32::MAX) {
+            // Backed off the surface so the bounce can't hit it again right away
+            let position = primary.origin + primary.direction * (hit.distance * 0.999);
+            rays.push(Ray {
+                origin: position,
+                direction: sample_uniform_sphere(vec2(random(), random())),
+                time: primary.time,
+            });
+        }
+        rays.push(primary);
+    }
+
+    rays.truncate(count);
+    rays
+}
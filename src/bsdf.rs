@@ -0,0 +1,335 @@
+use std::f32::consts::PI;
+
+use cgmath::{vec3, InnerSpace, Vector3, Zero};
+
+use crate::scene::Material;
+use crate::sampler::Sampler;
+use crate::utils::{cosine_hemisphere_pdf, from_local, reflect, sample_cosine_hemisphere};
+
+// Below this roughness the specular lobe is treated as a perfect mirror
+const MIRROR_ROUGHNESS: f32 = 0.01;
+
+pub struct BsdfSample {
+    pub direction: Vector3<f32>,
+    // BSDF * cos(theta) / pdf
+    pub weight: Vector3<f32>,
+    // Solid angle density of the non-delta lobes in `direction`, used for multiple importance sampling
+    pub pdf: f32,
+    // Sampled from a delta lobe (mirror or glass), which light sampling can never hit
+    pub specular: bool,
+}
+
+// True if every lobe of the material is a delta distribution, so light sampling is pointless
+pub fn is_specular(material: &Material) -> bool {
+    material.transmission >= 1.0 || (material.metallic >= 1.0 && material.roughness < MIRROR_ROUGHNESS)
+}
+
+// Picks the specular lobe with probability `metallic`, so each lobe's weight is its own estimate.
+// `normal` faces the incoming ray, `front_face` tells whether the ray arrived from outside the surface
+pub fn sample(material: &Material, direction: Vector3<f32>, normal: Vector3<f32>, front_face: bool, sampler: &mut dyn Sampler) -> Option<BsdfSample> {
+    let view = -direction;
+
+    if material.transmission > 0.0 && sampler.get_1d() < material.transmission {
+        return Some(sample_dielectric(material, direction, normal, front_face, sampler));
+    }
+
+    let (direction, weight) = if material.metallic > 0.0 && sampler.get_1d() < material.metallic {
+        if material.roughness < MIRROR_ROUGHNESS {
+            return Some(BsdfSample {
+                direction: reflect(direction, normal),
+                weight: fresnel_schlick(material.albedo, normal.dot(view)),
+                pdf: 0.0,
+                specular: true,
+            });
+        }
+
+        sample_specular(material, view, normal, sampler)?
+    } else {
+        // Lambertian: f * cos / pdf = (albedo / pi) * cos / (cos / pi)
+        let direction = from_local(sample_cosine_hemisphere(sampler.get_2d()), normal);
+        let cos_theta = normal.dot(direction);
+        let pdf = cosine_hemisphere_pdf(cos_theta);
+        if pdf <= 0.0 {
+            return None;
+        }
+        (direction, material.albedo * (cos_theta / PI / pdf))
+    };
+
+    let (_, pdf) = evaluate(material, view, direction, normal)?;
+
+    Some(BsdfSample {
+        direction,
+        weight,
+        pdf,
+        specular: false,
+    })
+}
+
+// Returns BSDF * cos(theta) and the sampling density for the non-delta lobes, for light sampling
+pub fn evaluate(material: &Material, view: Vector3<f32>, direction: Vector3<f32>, normal: Vector3<f32>) -> Option<(Vector3<f32>, f32)> {
+    let n_dot_v = normal.dot(view);
+    let n_dot_l = normal.dot(direction);
+    if n_dot_v <= 0.0 || n_dot_l <= 0.0 {
+        return None;
+    }
+
+    let opaque = 1.0 - material.transmission.min(1.0);
+    let diffuse = opaque * (1.0 - material.metallic);
+
+    let mut value = material.albedo * (diffuse * n_dot_l / PI);
+    let mut pdf = diffuse * cosine_hemisphere_pdf(n_dot_l);
+
+    if material.metallic > 0.0 && material.roughness >= MIRROR_ROUGHNESS {
+        let specular = opaque * material.metallic;
+        let alpha = material.roughness * material.roughness;
+        let half = (view + direction).normalize();
+        let n_dot_h = normal.dot(half);
+        let v_dot_h = view.dot(half);
+
+        let d = ggx_distribution(n_dot_h, alpha);
+        let g = smith_g1(n_dot_v, alpha) * smith_g1(n_dot_l, alpha);
+
+        value += fresnel_schlick(material.albedo, v_dot_h) * (specular * d * g / (4.0 * n_dot_v));
+        pdf += specular * d * n_dot_h / (4.0 * v_dot_h);
+    }
+
+    if pdf <= 0.0 || value.is_zero() {
+        return None;
+    }
+
+    Some((value, pdf))
+}
+
+fn sample_specular(material: &Material, view: Vector3<f32>, normal: Vector3<f32>, sampler: &mut dyn Sampler) -> Option<(Vector3<f32>, Vector3<f32>)> {
+    let n_dot_v = normal.dot(view);
+    if n_dot_v <= 0.0 {
+        return None;
+    }
+
+    let alpha = material.roughness * material.roughness;
+    let half = sample_ggx_half_vector(normal, alpha, sampler);
+    let direction = reflect(-view, half);
+
+    let n_dot_l = normal.dot(direction);
+    if n_dot_l <= 0.0 {
+        return None;
+    }
+
+    let n_dot_h = normal.dot(half);
+    let v_dot_h = view.dot(half);
+
+    // f * cos / pdf with pdf = D * n.h / (4 * v.h), the D terms cancel
+    let fresnel = fresnel_schlick(material.albedo, v_dot_h);
+    let g = smith_g1(n_dot_v, alpha) * smith_g1(n_dot_l, alpha);
+
+    Some((direction, fresnel * (g * v_dot_h / (n_dot_v * n_dot_h))))
+}
+
+// Smooth glass: reflects with the Fresnel probability and refracts otherwise, or reflects on total internal reflection
+fn sample_dielectric(material: &Material, direction: Vector3<f32>, normal: Vector3<f32>, front_face: bool, sampler: &mut dyn Sampler) -> BsdfSample {
+    let eta = if front_face { 1.0 / material.ior } else { material.ior };
+
+    let cos_i = (-direction.dot(normal)).min(1.0);
+    let sin2_t = eta * eta * (1.0 - cos_i * cos_i);
+
+    let reflection = BsdfSample {
+        direction: reflect(direction, normal),
+        weight: vec3(1.0, 1.0, 1.0),
+        pdf: 0.0,
+        specular: true,
+    };
+
+    if sin2_t >= 1.0 {
+        return reflection;
+    }
+
+    let cos_t = (1.0 - sin2_t).sqrt();
+
+    if sampler.get_1d() < fresnel_dielectric(cos_i, cos_t, eta) {
+        reflection
+    } else {
+        BsdfSample {
+            direction: (direction * eta + normal * (eta * cos_i - cos_t)).normalize(),
+            weight: material.albedo,
+            pdf: 0.0,
+            specular: true,
+        }
+    }
+}
+
+// Exact unpolarized Fresnel reflectance, `eta` is the ratio of the incident over the transmitted index
+fn fresnel_dielectric(cos_i: f32, cos_t: f32, eta: f32) -> f32 {
+    let r_parallel = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    let r_perpendicular = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+
+    0.5 * (r_parallel * r_parallel + r_perpendicular * r_perpendicular)
+}
+
+// Importance samples a microfacet normal proportional to D(h) * cos(theta_h)
+fn sample_ggx_half_vector(normal: Vector3<f32>, alpha: f32, sampler: &mut dyn Sampler) -> Vector3<f32> {
+    let u = sampler.get_2d();
+    let (u1, u2) = (u.x, u.y);
+
+    let cos_theta = ((1.0 - u1) / (1.0 + (alpha * alpha - 1.0) * u1)).sqrt();
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * u2;
+
+    from_local(vec3(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta), normal)
+}
+
+fn ggx_distribution(n_dot_h: f32, alpha: f32) -> f32 {
+    let a2 = alpha * alpha;
+    let d = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+    a2 / (PI * d * d)
+}
+
+fn smith_g1(n_dot_x: f32, alpha: f32) -> f32 {
+    let a2 = alpha * alpha;
+    2.0 * n_dot_x / (n_dot_x + (a2 + (1.0 - a2) * n_dot_x * n_dot_x).sqrt())
+}
+
+fn fresnel_schlick(f0: Vector3<f32>, cos_theta: f32) -> Vector3<f32> {
+    let f = (1.0 - cos_theta.clamp(0.0, 1.0)).powi(5);
+    f0 + (vec3(1.0, 1.0, 1.0) - f0) * f
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::SamplerKind;
+    use crate::utils::pcg_float;
+
+    const NORMAL: Vector3<f32> = vec3(0.0, 0.0, 1.0);
+
+    fn view_at(cos_theta: f32) -> Vector3<f32> {
+        vec3((1.0 - cos_theta * cos_theta).sqrt(), 0.0, cos_theta)
+    }
+
+    fn samples(material: &Material, view: Vector3<f32>, count: u32) -> impl Iterator<Item = Option<BsdfSample>> + '_ {
+        let mut sampler = SamplerKind::Independent.create();
+        (0..count).map(move |index| {
+            sampler.start_pixel_sample(0, 0, index);
+            sample(material, -view, NORMAL, true, sampler.as_mut())
+        })
+    }
+
+    #[test]
+    fn ggx_samples_match_their_density() {
+        let material = Material::metal(vec3(0.9, 0.6, 0.3), 0.5);
+        let view = view_at(0.7);
+        let count = 100_000;
+
+        // Sampled directions binned by their cosine against what `evaluate` says lands in each bin
+        const BINS: usize = 8;
+        let mut histogram = [0.0; BINS];
+        for sample in samples(&material, view, count).flatten() {
+            let (value, pdf) = evaluate(&material, view, sample.direction, NORMAL).unwrap();
+            assert!((sample.pdf - pdf).abs() <= 1e-3 * pdf);
+            assert!((sample.weight * pdf - value).magnitude() <= 1e-3 * value.magnitude());
+
+            let bin = ((NORMAL.dot(sample.direction) * BINS as f32) as usize).min(BINS - 1);
+            histogram[bin] += 1.0 / count as f32;
+        }
+
+        let mut expected = [0.0; BINS];
+        let (steps_theta, steps_phi) = (400, 400);
+        let (d_theta, d_phi) = (0.5 * PI / steps_theta as f32, 2.0 * PI / steps_phi as f32);
+        for i in 0..steps_theta {
+            let theta = (i as f32 + 0.5) * d_theta;
+            for j in 0..steps_phi {
+                let phi = (j as f32 + 0.5) * d_phi;
+                let direction = vec3(theta.sin() * phi.cos(), theta.sin() * phi.sin(), theta.cos());
+                if let Some((_, pdf)) = evaluate(&material, view, direction, NORMAL) {
+                    let bin = ((theta.cos() * BINS as f32) as usize).min(BINS - 1);
+                    expected[bin] += pdf * theta.sin() * d_theta * d_phi;
+                }
+            }
+        }
+
+        for (measured, expected) in histogram.iter().zip(expected) {
+            assert!((measured - expected).abs() < 0.005, "{histogram:?} against {expected:?}");
+        }
+    }
+
+    #[test]
+    fn ggx_is_reciprocal() {
+        let mut state = 1;
+        let mut random_direction = || {
+            let cos_theta = pcg_float(&mut state).max(0.01);
+            let phi = 2.0 * PI * pcg_float(&mut state);
+            let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+            vec3(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+        };
+
+        for roughness in [0.1, 0.5, 1.0] {
+            let mut material = Material::metal(vec3(0.9, 0.6, 0.3), roughness);
+            material.metallic = 0.5;
+            for _ in 0..1000 {
+                let (a, b) = (random_direction(), random_direction());
+                // Without the cosine of the direction light leaves in, swapping both directions gives the same BSDF
+                let (forward, _) = evaluate(&material, a, b, NORMAL).unwrap();
+                let (backward, _) = evaluate(&material, b, a, NORMAL).unwrap();
+                let (forward, backward) = (forward / NORMAL.dot(b), backward / NORMAL.dot(a));
+                assert!((forward - backward).magnitude() <= 1e-4 * forward.magnitude().max(1.0));
+            }
+        }
+    }
+
+    // A white material under uniform light reflects at most all of it
+    #[test]
+    fn ggx_passes_the_white_furnace() {
+        let count = 20_000;
+        for roughness in [0.05, 0.3, 0.6, 1.0] {
+            for metallic in [0.5, 1.0] {
+                let mut material = Material::metal(vec3(1.0, 1.0, 1.0), roughness);
+                material.metallic = metallic;
+                for cos_theta in [1.0, 0.7, 0.3, 0.05] {
+                    let reflected = samples(&material, view_at(cos_theta), count)
+                        .flatten()
+                        .map(|sample| sample.weight.x)
+                        .sum::<f32>()
+                        / count as f32;
+                    assert!(reflected <= 1.01, "{reflected} for roughness {roughness} at cosine {cos_theta}");
+                    // Single scattering loses what bounces between microfacets, which only adds up when rough
+                    let lower = if roughness < 0.1 { 0.99 } else { 0.25 };
+                    assert!(reflected > lower, "{reflected} for roughness {roughness} at cosine {cos_theta}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn fresnel_at_normal_incidence() {
+        for (n1, n2) in [(1.0f32, 1.5f32), (1.5, 1.0), (1.0, 2.4), (1.33, 1.5), (1.0, 1.0)] {
+            let expected = ((n1 - n2) / (n1 + n2)).powi(2);
+            assert!((fresnel_dielectric(1.0, 1.0, n1 / n2) - expected).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn total_internal_reflection_above_the_critical_angle() {
+        let material = Material::dielectric(vec3(1.0, 1.0, 1.0), 1.5);
+        let critical = (1.0f32 / 1.5).asin();
+        let mut sampler = SamplerKind::Independent.create();
+
+        // Leaving the glass, with the normal facing the ray inside it
+        for angle in [critical + 0.01, critical + 0.3, 0.5 * PI - 0.01] {
+            let direction = vec3(angle.sin(), 0.0, -angle.cos());
+            for index in 0..100 {
+                sampler.start_pixel_sample(0, 0, index);
+                let sample = sample(&material, direction, NORMAL, false, sampler.as_mut()).unwrap();
+                assert!((sample.direction - reflect(direction, NORMAL)).magnitude() < 1e-6);
+                assert!(sample.specular && sample.weight == vec3(1.0, 1.0, 1.0));
+            }
+        }
+
+        // Just below it some light still gets out, and all the more the further below
+        let reflectance = |angle: f32| {
+            let cos_i = angle.cos();
+            let cos_t = (1.0 - 1.5 * 1.5 * (1.0 - cos_i * cos_i)).sqrt();
+            fresnel_dielectric(cos_i, cos_t, 1.5)
+        };
+        assert!(reflectance(critical - 0.001) > 0.5 && reflectance(critical - 0.001) < 1.0);
+        assert!(reflectance(critical - 0.1) < reflectance(critical - 0.01));
+    }
+}
@@ -0,0 +1,596 @@
+use cgmath::{vec3, Vector3};
+
+use crate::aabb::Aabb;
+use crate::ray::Ray;
+use crate::simd::F32x4;
+
+const BIN_COUNT: usize = 16;
+const MAX_LEAF_SIZE: usize = 4;
+const TRAVERSAL_COST: f32 = 1.0;
+const INTERSECTION_COST: f32 = 1.0;
+// From this depth on nodes are split at the median instead, halving them every level, so even with degenerate SAH
+// splits and 2^32 primitives no leaf is deeper than `MAX_DEPTH` and the traversal stacks can't overflow
+const MAX_SAH_DEPTH: usize = 32;
+const MAX_DEPTH: usize = MAX_SAH_DEPTH + 32;
+// Marks a wide node child as an index into the leaves rather than the nodes
+const LEAF: u32 = 1 << 31;
+const WIDE_LEAF_SIZE: usize = 4;
+
+// Nodes are stored depth first, so the left child of an interior node always directly follows it
+#[derive(Clone, Copy)]
+struct BvhNode {
+    bounds: Aabb,
+    // Leaf: index of the first primitive in `indices`. Interior: index of the right child
+    offset: u32,
+    // Zero for interior nodes
+    count: u16,
+    axis: u16,
+}
+
+#[derive(Default)]
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+    indices: Vec<usize>,
+}
+
+// Four children tested against a ray at once, their bounds stored one axis per vector
+#[derive(Clone, Copy)]
+struct WideNode {
+    min: [F32x4; 3],
+    max: [F32x4; 3],
+    children: [u32; 4],
+    count: u32,
+}
+
+// The binary hierarchy with every two levels collapsed into one, a quarter as many nodes to visit and each of them
+// tested in one go. Leaves are reported as a whole, so callers can intersect their primitives together too
+#[derive(Default)]
+pub struct WideBvh {
+    nodes: Vec<WideNode>,
+    // First index in `indices` and count
+    leaves: Vec<(usize, usize)>,
+    indices: Vec<usize>,
+}
+
+#[derive(Clone, Copy)]
+struct BuildPrimitive {
+    index: usize,
+    bounds: Aabb,
+    centroid: Vector3<f32>,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Bin {
+    bounds: Aabb,
+    count: usize,
+}
+
+impl Bvh {
+    // Builds a hierarchy over primitives given by their bounds, primitive `i` is reported back as index `i`
+    pub fn build(bounds: &[Aabb]) -> Self {
+        let mut primitives: Vec<BuildPrimitive> = bounds
+            .iter()
+            .enumerate()
+            .map(|(index, bounds)| BuildPrimitive {
+                index,
+                bounds: *bounds,
+                centroid: bounds.centroid(),
+            })
+            .collect();
+
+        let mut bvh = Bvh {
+            nodes: Vec::with_capacity(2 * primitives.len()),
+            indices: Vec::with_capacity(primitives.len()),
+        };
+
+        if !primitives.is_empty() {
+            bvh.build_recursive(&mut primitives, 0);
+        }
+
+        bvh
+    }
+
+    fn build_recursive(&mut self, primitives: &mut [BuildPrimitive], depth: usize) -> usize {
+        let node_index = self.nodes.len();
+
+        let bounds = primitives.iter().fold(Aabb::EMPTY, |b, p| b.union(&p.bounds));
+        let centroid_bounds = primitives.iter().fold(Aabb::EMPTY, |b, p| b.grow(p.centroid));
+
+        self.nodes.push(BvhNode {
+            bounds,
+            offset: 0,
+            count: 0,
+            axis: 0,
+        });
+
+        let split = if primitives.len() <= 1 {
+            None
+        } else if depth >= MAX_SAH_DEPTH {
+            (primitives.len() > MAX_LEAF_SIZE).then_some((centroid_bounds.largest_axis(), primitives.len() / 2))
+        } else {
+            find_sah_split(primitives, &bounds, &centroid_bounds)
+        };
+
+        match split {
+            Some((axis, mid)) => {
+                primitives.sort_unstable_by(|a, b| a.centroid[axis].total_cmp(&b.centroid[axis]));
+                let mid = mid.clamp(1, primitives.len() - 1);
+                let (left, right) = primitives.split_at_mut(mid);
+
+                self.build_recursive(left, depth + 1);
+                let right_index = self.build_recursive(right, depth + 1);
+
+                let node = &mut self.nodes[node_index];
+                node.offset = right_index as u32;
+                node.axis = axis as u16;
+            }
+            None => {
+                let node = &mut self.nodes[node_index];
+                node.offset = self.indices.len() as u32;
+                node.count = primitives.len() as u16;
+
+                self.indices.extend(primitives.iter().map(|p| p.index));
+            }
+        }
+
+        node_index
+    }
+
+    // First index in `indices` and count of the primitives below a node, which the depth first build keeps together
+    fn subtree_primitives(&self, node_index: usize) -> (usize, usize) {
+        let node = &self.nodes[node_index];
+        if node.count > 0 {
+            return (node.offset as usize, node.count as usize);
+        }
+
+        let (first, left_count) = self.subtree_primitives(node_index + 1);
+        let (_, right_count) = self.subtree_primitives(node.offset as usize);
+        (first, left_count + right_count)
+    }
+
+    // Walks the hierarchy front to back. `intersect` is called with a primitive index and the current closest
+    // distance and returns a closer hit and its distance, if any
+    pub fn intersect<T, F>(&self, ray: &Ray, mut t_max: f32, mut intersect: F) -> Option<(T, f32)>
+        where F: FnMut(usize, f32) -> Option<(T, f32)>
+    {
+        if self.nodes.is_empty() {
+            return None;
+        }
+
+        let inverse_direction = vec3(1.0 / ray.direction.x, 1.0 / ray.direction.y, 1.0 / ray.direction.z);
+        let direction_negative = [ray.direction.x < 0.0, ray.direction.y < 0.0, ray.direction.z < 0.0];
+
+        let mut closest = None;
+        let mut stack = [0usize; MAX_DEPTH];
+        let mut stack_size = 0;
+        let mut node_index = 0;
+
+        loop {
+            let node = &self.nodes[node_index];
+
+            if node.bounds.hit(ray, inverse_direction, t_max).is_some() {
+                if node.count > 0 {
+                    let first = node.offset as usize;
+                    for &index in &self.indices[first..first + node.count as usize] {
+                        if let Some((hit, t)) = intersect(index, t_max) {
+                            t_max = t;
+                            closest = Some((hit, t));
+                        }
+                    }
+                } else {
+                    // Visit the child closest along the split axis first
+                    let (near, far) = if direction_negative[node.axis as usize] {
+                        (node.offset as usize, node_index + 1)
+                    } else {
+                        (node_index + 1, node.offset as usize)
+                    };
+
+                    stack[stack_size] = far;
+                    stack_size += 1;
+                    node_index = near;
+                    continue;
+                }
+            }
+
+            if stack_size == 0 {
+                break;
+            }
+            stack_size -= 1;
+            node_index = stack[stack_size];
+        }
+
+        closest
+    }
+}
+
+impl WideBvh {
+    // Subtrees of up to four primitives that `packable` accepts all of become a single leaf, for callers that can
+    // intersect those together
+    pub fn collapse(bvh: &Bvh, packable: impl Fn(usize) -> bool) -> Self {
+        let mut wide = WideBvh {
+            nodes: Vec::with_capacity(bvh.nodes.len() / 2),
+            leaves: Vec::new(),
+            indices: bvh.indices.clone(),
+        };
+
+        if !bvh.nodes.is_empty() {
+            wide.collapse_recursive(bvh, 0, &packable);
+        }
+
+        wide
+    }
+
+    fn collapse_recursive(&mut self, bvh: &Bvh, binary_index: usize, packable: &impl Fn(usize) -> bool) -> u32 {
+        let node = &bvh.nodes[binary_index];
+        let mut children = if node.count > 0 {
+            vec![binary_index]
+        } else {
+            vec![binary_index + 1, node.offset as usize]
+        };
+        let is_leaf = |child: usize| {
+            let (first, count) = bvh.subtree_primitives(child);
+            bvh.nodes[child].count > 0
+                || count <= WIDE_LEAF_SIZE && bvh.indices[first..first + count].iter().all(|&i| packable(i))
+        };
+
+        // Open up the largest interior child until there are four, keeping the boxes tested together similar in size
+        while children.len() < 4 {
+            let largest = (0..children.len())
+                .filter(|&i| !is_leaf(children[i]))
+                .max_by(|&a, &b| {
+                    let area = |i: usize| bvh.nodes[children[i]].bounds.surface_area();
+                    area(a).total_cmp(&area(b))
+                });
+            let Some(largest) = largest else {
+                break;
+            };
+
+            let child = children.swap_remove(largest);
+            children.push(child + 1);
+            children.push(bvh.nodes[child].offset as usize);
+        }
+
+        let node_index = self.nodes.len();
+        self.nodes.push(WideNode {
+            min: [F32x4::splat(0.0); 3],
+            max: [F32x4::splat(0.0); 3],
+            children: [0; 4],
+            count: children.len() as u32,
+        });
+
+        let mut min = [[0.0; 4]; 3];
+        let mut max = [[0.0; 4]; 3];
+        let mut references = [0; 4];
+        for (slot, &child) in children.iter().enumerate() {
+            let child_node = &bvh.nodes[child];
+            for axis in 0..3 {
+                min[axis][slot] = child_node.bounds.min[axis];
+                max[axis][slot] = child_node.bounds.max[axis];
+            }
+
+            references[slot] = if is_leaf(child) {
+                self.leaves.push(bvh.subtree_primitives(child));
+                (self.leaves.len() - 1) as u32 | LEAF
+            } else {
+                self.collapse_recursive(bvh, child, packable)
+            };
+        }
+
+        let node = &mut self.nodes[node_index];
+        node.min = min.map(F32x4::new);
+        node.max = max.map(F32x4::new);
+        node.children = references;
+
+        node_index as u32
+    }
+
+    pub fn leaf_count(&self) -> usize {
+        self.leaves.len()
+    }
+
+    pub fn leaf_primitives(&self, leaf: usize) -> &[usize] {
+        let (first, count) = self.leaves[leaf];
+        &self.indices[first..first + count]
+    }
+
+    // Walks the hierarchy nearest child first. `intersect` is called with a leaf index and the current closest
+    // distance and returns a closer hit and its distance, if any
+    pub fn intersect<T, F>(&self, ray: &Ray, mut t_max: f32, mut intersect: F) -> Option<(T, f32)>
+        where F: FnMut(usize, f32) -> Option<(T, f32)>
+    {
+        if self.nodes.is_empty() {
+            return None;
+        }
+
+        let origin = [0, 1, 2].map(|axis| F32x4::splat(ray.origin[axis]));
+        let inverse_direction = [0, 1, 2].map(|axis| F32x4::splat(1.0 / ray.direction[axis]));
+        // By sign bit, so -0 counts as negative like its infinite inverse
+        let negative = [0, 1, 2].map(|axis| ray.direction[axis].is_sign_negative());
+
+        let mut closest = None;
+        // Children still to visit with the distance at which the ray enters them. Every level down leaves at most
+        // three siblings behind, and wide nodes are never deeper than the binary ones they were collapsed from
+        let mut stack = [(0u32, 0.0f32); 3 * MAX_DEPTH + 1];
+        let mut stack_size = 1;
+
+        while stack_size > 0 {
+            stack_size -= 1;
+            let (reference, entry) = stack[stack_size];
+            if entry > t_max {
+                continue;
+            }
+
+            if reference & LEAF != 0 {
+                if let Some((hit, t)) = intersect((reference & !LEAF) as usize, t_max) {
+                    t_max = t;
+                    closest = Some((hit, t));
+                }
+                continue;
+            }
+
+            let node = &self.nodes[reference as usize];
+
+            // Slab test against all four boxes, the near and far planes picked by the direction's sign. An axis
+            // parallel ray starting on a plane gets 0 * inf = NaN there, which the operand order drops so that
+            // axis doesn't constrain the interval
+            let mut t_near = F32x4::splat(0.0);
+            let mut t_far = F32x4::splat(t_max);
+            for axis in 0..3 {
+                let (near, far) = if negative[axis] { (&node.max, &node.min) } else { (&node.min, &node.max) };
+                t_near = ((near[axis] - origin[axis]) * inverse_direction[axis]).max(t_near);
+                t_far = ((far[axis] - origin[axis]) * inverse_direction[axis]).min(t_far);
+            }
+            let mut hit = t_near.le(t_far).bits() & ((1 << node.count) - 1);
+            let t_near = t_near.to_array();
+
+            // Pushed farthest first so the nearest child is visited next
+            let mut children = [(0u32, 0.0f32); 4];
+            let mut count = 0;
+            while hit != 0 {
+                let slot = hit.trailing_zeros() as usize;
+                hit &= hit - 1;
+                children[count] = (node.children[slot], t_near[slot]);
+                count += 1;
+            }
+            for i in 1..count {
+                let mut j = i;
+                while j > 0 && children[j - 1].1 < children[j].1 {
+                    children.swap(j - 1, j);
+                    j -= 1;
+                }
+            }
+
+            stack[stack_size..stack_size + count].copy_from_slice(&children[..count]);
+            stack_size += count;
+        }
+
+        closest
+    }
+}
+
+// Binned surface area heuristic, returns the split axis and the number of primitives going left,
+// or None if keeping the primitives in a leaf is cheaper
+fn find_sah_split(primitives: &[BuildPrimitive], bounds: &Aabb, centroid_bounds: &Aabb) -> Option<(usize, usize)> {
+    let leaf_cost = INTERSECTION_COST * primitives.len() as f32;
+    let mut best: Option<(usize, usize)> = None;
+    let mut best_cost = f32::MAX;
+
+    for axis in 0..3 {
+        let min = centroid_bounds.min[axis];
+        let extent = centroid_bounds.max[axis] - min;
+        if extent <= 0.0 {
+            continue;
+        }
+
+        let mut bins = [Bin::default(); BIN_COUNT];
+        let scale = BIN_COUNT as f32 / extent;
+        for p in primitives {
+            let b = (((p.centroid[axis] - min) * scale) as usize).min(BIN_COUNT - 1);
+            bins[b].count += 1;
+            bins[b].bounds = bins[b].bounds.union(&p.bounds);
+        }
+
+        // Sweep from the right to collect the cost of everything right of each split plane
+        let mut right_area = [0.0f32; BIN_COUNT - 1];
+        let mut right_count = [0usize; BIN_COUNT - 1];
+        let mut accumulated = Bin::default();
+        for i in (1..BIN_COUNT).rev() {
+            accumulated.bounds = accumulated.bounds.union(&bins[i].bounds);
+            accumulated.count += bins[i].count;
+            right_area[i - 1] = accumulated.bounds.surface_area();
+            right_count[i - 1] = accumulated.count;
+        }
+
+        let mut accumulated = Bin::default();
+        for i in 0..BIN_COUNT - 1 {
+            accumulated.bounds = accumulated.bounds.union(&bins[i].bounds);
+            accumulated.count += bins[i].count;
+
+            if accumulated.count == 0 || right_count[i] == 0 {
+                continue;
+            }
+
+            let cost = accumulated.count as f32 * accumulated.bounds.surface_area()
+                + right_count[i] as f32 * right_area[i];
+            if cost < best_cost {
+                best_cost = cost;
+                best = Some((axis, accumulated.count));
+            }
+        }
+    }
+
+    let split_cost = TRAVERSAL_COST + INTERSECTION_COST * best_cost / bounds.surface_area().max(f32::MIN_POSITIVE);
+
+    match best {
+        Some(split) if split_cost < leaf_cost || primitives.len() > MAX_LEAF_SIZE => Some(split),
+        // All centroids coincide, split in the middle so leaves stay small
+        None if primitives.len() > MAX_LEAF_SIZE => Some((bounds.largest_axis(), primitives.len() / 2)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::pcg_float;
+
+    fn random_vector(state: &mut u32, scale: f32) -> Vector3<f32> {
+        vec3(pcg_float(state) - 0.5, pcg_float(state) - 0.5, pcg_float(state) - 0.5) * scale
+    }
+
+    fn random_boxes(state: &mut u32, count: usize) -> Vec<Aabb> {
+        (0..count)
+            .map(|_| {
+                let corner = random_vector(state, 20.0);
+                Aabb::new(corner, corner + vec3(pcg_float(state), pcg_float(state), pcg_float(state)))
+            })
+            .collect()
+    }
+
+    fn random_rays(state: &mut u32, count: usize) -> Vec<Ray> {
+        (0..count)
+            .map(|_| Ray {
+                origin: random_vector(state, 40.0),
+                direction: random_vector(state, 2.0),
+                time: 0.0,
+            })
+            .collect()
+    }
+
+    // The boxes themselves are the primitives
+    fn hit_box(boxes: &[Aabb], index: usize, ray: &Ray, t_max: f32) -> Option<(usize, f32)> {
+        let inverse_direction = vec3(1.0 / ray.direction.x, 1.0 / ray.direction.y, 1.0 / ray.direction.z);
+        let t = boxes[index].hit(ray, inverse_direction, t_max)?;
+        (t < t_max).then_some((index, t))
+    }
+
+    fn linear_scan(boxes: &[Aabb], ray: &Ray) -> Option<f32> {
+        let mut closest = None;
+        for index in 0..boxes.len() {
+            if let Some((_, t)) = hit_box(boxes, index, ray, closest.unwrap_or(f32::MAX)) {
+                closest = Some(t);
+            }
+        }
+        closest
+    }
+
+    fn depth(bvh: &Bvh, node_index: usize) -> usize {
+        let node = &bvh.nodes[node_index];
+        if node.count > 0 {
+            return 1;
+        }
+        1 + depth(bvh, node_index + 1).max(depth(bvh, node.offset as usize))
+    }
+
+    #[test]
+    fn binary_matches_linear_scan() {
+        let mut state = 1;
+        let boxes = random_boxes(&mut state, 500);
+        let bvh = Bvh::build(&boxes);
+
+        for ray in random_rays(&mut state, 2000) {
+            let hit = bvh.intersect(&ray, f32::MAX, |index, t_max| hit_box(&boxes, index, &ray, t_max));
+            assert_eq!(hit.map(|(_, t)| t), linear_scan(&boxes, &ray));
+        }
+    }
+
+    #[test]
+    fn median_splits_past_max_sah_depth() {
+        let mut state = 2;
+        let boxes = random_boxes(&mut state, 4096);
+        let mut primitives: Vec<BuildPrimitive> = boxes
+            .iter()
+            .enumerate()
+            .map(|(index, bounds)| BuildPrimitive {
+                index,
+                bounds: *bounds,
+                centroid: bounds.centroid(),
+            })
+            .collect();
+
+        // As if the SAH had already used up its levels, what is left is halved down to leaves of four
+        let mut bvh = Bvh::default();
+        bvh.build_recursive(&mut primitives, MAX_SAH_DEPTH);
+        assert_eq!(depth(&bvh, 0), 11);
+
+        for ray in random_rays(&mut state, 500) {
+            let hit = bvh.intersect(&ray, f32::MAX, |index, t_max| hit_box(&boxes, index, &ray, t_max));
+            assert_eq!(hit.map(|(_, t)| t), linear_scan(&boxes, &ray));
+        }
+    }
+
+    fn wide_intersect(wide: &WideBvh, boxes: &[Aabb], ray: &Ray, hit: impl Fn(usize, f32) -> Option<f32>) -> Option<f32> {
+        let closest = wide.intersect(ray, f32::MAX, |leaf, t_max| {
+            let mut closest = None;
+            for &index in wide.leaf_primitives(leaf) {
+                if let Some(t) = hit(index, closest.map_or(t_max, |(_, t)| t)) {
+                    closest = Some((index, t));
+                }
+            }
+            closest
+        });
+        assert!(closest.is_none_or(|(index, _)| index < boxes.len()));
+        closest.map(|(_, t)| t)
+    }
+
+    #[test]
+    fn wide_matches_linear_scan() {
+        let mut state = 3;
+        let boxes = random_boxes(&mut state, 500);
+        let bvh = Bvh::build(&boxes);
+        let rays = random_rays(&mut state, 2000);
+
+        for packable in [false, true] {
+            let wide = WideBvh::collapse(&bvh, |_| packable);
+            for ray in &rays {
+                let hit = |index, t_max| hit_box(&boxes, index, ray, t_max).map(|(_, t)| t);
+                assert_eq!(wide_intersect(&wide, &boxes, ray, hit), linear_scan(&boxes, ray));
+            }
+        }
+    }
+
+    // Treats the boxes as closed, so an axis parallel ray exactly on a face still hits
+    fn hit_closed_box(bounds: &Aabb, ray: &Ray, t_max: f32) -> Option<f32> {
+        let (mut near, mut far) = (0.0f32, t_max);
+        for axis in 0..3 {
+            let (origin, direction) = (ray.origin[axis], ray.direction[axis]);
+            if direction == 0.0 {
+                if origin < bounds.min[axis] || origin > bounds.max[axis] {
+                    return None;
+                }
+                continue;
+            }
+            let t0 = (bounds.min[axis] - origin) / direction;
+            let t1 = (bounds.max[axis] - origin) / direction;
+            near = near.max(t0.min(t1));
+            far = far.min(t0.max(t1));
+        }
+        (near <= far && near < t_max).then_some(near)
+    }
+
+    #[test]
+    fn wide_finds_axis_parallel_rays_on_slab_planes() {
+        let mut state = 4;
+        let boxes = random_boxes(&mut state, 500);
+        let wide = WideBvh::collapse(&Bvh::build(&boxes), |_| true);
+
+        for bounds in &boxes {
+            // 0 * inf = NaN for the x slabs of every node sharing the plane
+            for (x, direction_x) in [(bounds.min.x, 0.0), (bounds.max.x, 0.0), (bounds.min.x, -0.0), (bounds.max.x, -0.0)] {
+                let y = bounds.min.y + (bounds.max.y - bounds.min.y) * pcg_float(&mut state);
+                let ray = Ray {
+                    origin: vec3(x, y, -30.0),
+                    direction: vec3(direction_x, 0.0, 1.0),
+                    time: 0.0,
+                };
+
+                let expected = boxes
+                    .iter()
+                    .filter_map(|b| hit_closed_box(b, &ray, f32::MAX))
+                    .min_by(|a, b| a.total_cmp(b));
+                let hit = |index: usize, t_max| hit_closed_box(&boxes[index], &ray, t_max);
+                assert_eq!(wide_intersect(&wide, &boxes, &ray, hit), expected);
+            }
+        }
+    }
+}
@@ -1,9 +1,40 @@
+use std::f32::consts::PI;
 use std::time::Duration;
 
-use cgmath::{Deg, Euler, Matrix4, perspective, Point3, Quaternion, Rad, vec2, vec3, vec4, Vector2, Vector3};
+use cgmath::{Deg, Matrix4, perspective, Point3, Quaternion, Rad, vec2, vec3, vec4, Vector2, Vector3};
 use cgmath::prelude::*;
 use minifb::{CursorStyle, Key, MouseButton, MouseMode, Window};
 
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::utils::{sample_concentric_disk, sample_uniform_triangle};
+
+// Thin lens, a zero aperture radius gives a pinhole camera with everything in focus
+#[derive(Clone, Copy)]
+pub struct Lens {
+    pub aperture_radius: f32,
+    // Distance of the plane in focus, along the view direction
+    pub focus_distance: f32,
+    // Polygonal aperture with this many blades, round below three
+    pub aperture_blades: u32,
+}
+
+impl Default for Lens {
+    fn default() -> Self {
+        Self {
+            aperture_radius: 0.0,
+            focus_distance: 6.0,
+            aperture_blades: 0,
+        }
+    }
+}
+
+pub struct ImportanceSample {
+    pub lens_position: Vector3<f32>,
+    pub film_position: Vector2<f32>,
+    pub weight: f32,
+}
+
 pub struct Camera {
     projection: Matrix4<f32>,
     view: Matrix4<f32>,
@@ -14,9 +45,20 @@ pub struct Camera {
     far_clip: f32,
     position: Vector3<f32>,
     forward_direction: Vector3<f32>,
-
-    // Cached before moving to GPU
-    ray_directions: Vec<Vector3<f32>>,
+    lens: Lens,
+    // Times at which the shutter opens and closes, objects move over [0, 1]
+    shutter_open: f32,
+    shutter_close: f32,
+
+    // Through the center of the image, with the lens axes perpendicular to it
+    view_direction: Vector3<f32>,
+    lens_right: Vector3<f32>,
+    lens_up: Vector3<f32>,
+    // Film plane at unit distance along the view direction: where film position zero lands on it and how far one
+    // pixel moves along it in x and y, to map light paths connecting to the camera back to the film
+    film_origin: Vector3<f32>,
+    film_right: Vector3<f32>,
+    film_up: Vector3<f32>,
     last_mouse_position: Vector2<f32>,
 
     pub viewport_width: usize,
@@ -25,7 +67,15 @@ pub struct Camera {
 
 impl Camera {
     pub const fn get_position(&self) -> Vector3<f32> { self.position }
-    pub const fn get_ray_directions(&self) -> &Vec<Vector3<f32>> { &self.ray_directions }
+    pub fn set_position(&mut self, position: Vector3<f32>) { self.position = position; }
+    pub const fn get_view_direction(&self) -> Vector3<f32> { self.view_direction }
+    pub const fn get_lens(&self) -> Lens { self.lens }
+    pub fn set_lens(&mut self, lens: Lens) { self.lens = lens; }
+    pub const fn get_shutter_open(&self) -> f32 { self.shutter_open }
+    pub fn set_shutter(&mut self, open: f32, close: f32) {
+        self.shutter_open = open;
+        self.shutter_close = close;
+    }
 
     pub const fn get_rotation_speed(&self) -> f32 {
         0.3
@@ -43,13 +93,125 @@ impl Camera {
             far_clip,
             position: vec3(0.0, 0.0, 6.0),
             forward_direction: vec3(0.0, 0.0, 1.0),
-            ray_directions: Vec::new(),
+            lens: Lens::default(),
+            shutter_open: 0.0,
+            shutter_close: 0.0,
+            view_direction: vec3(0.0, 0.0, -1.0),
+            lens_right: vec3(1.0, 0.0, 0.0),
+            lens_up: vec3(0.0, 1.0, 0.0),
+            film_origin: vec3(0.0, 0.0, -1.0),
+            film_right: Vector3::zero(),
+            film_up: Vector3::zero(),
             last_mouse_position: Vector2::zero(),
             viewport_width: 0,
             viewport_height: 0,
         }
     }
 
+    // Pinhole direction through a point on the film, in pixels from the bottom left corner
+    pub fn get_direction(&self, film_x: f32, film_y: f32) -> Vector3<f32> {
+        let coord = vec2(film_x / self.viewport_width as f32, film_y / self.viewport_height as f32) * 2.0 - vec2(1.0, 1.0);
+
+        let target = self.inverse_projection * vec4(coord.x, coord.y, 1.0, 1.0);
+        (self.inverse_view * (target.truncate() / target.w).normalize().extend(0.0)).truncate()
+    }
+
+    // Primary ray through a point on the film, starting from a random point on the lens at a random time
+    // while the shutter is open
+    pub fn get_ray(&self, film_x: f32, film_y: f32, sampler: &mut dyn Sampler) -> Ray {
+        let time = self.shutter_open + sampler.get_1d() * (self.shutter_close - self.shutter_open);
+
+        let direction = self.get_direction(film_x, film_y);
+        if self.lens.aperture_radius <= 0.0 {
+            return Ray {
+                origin: self.position,
+                direction,
+                time,
+            };
+        }
+
+        // Every ray through the same pixel meets on the focus plane
+        let focus_point = self.position + direction * (self.lens.focus_distance / direction.dot(self.view_direction));
+
+        let u = sampler.get_2d();
+        let lens = sample_aperture(self.lens.aperture_blades, u.x, u.y) * self.lens.aperture_radius;
+        let origin = self.position + self.lens_right * lens.x + self.lens_up * lens.y;
+
+        Ray {
+            origin,
+            direction: (focus_point - origin).normalize(),
+            time,
+        }
+    }
+
+    // Solid angle density with which `get_ray` leaves `lens_position` in `direction`, zero if it misses the film
+    pub fn direction_pdf(&self, lens_position: Vector3<f32>, direction: Vector3<f32>) -> f32 {
+        if self.lens_film_position(lens_position, direction).is_none() {
+            return 0.0;
+        }
+
+        1.0 / (self.film_area() * direction.dot(self.view_direction).powi(3))
+    }
+
+    // Picks a point on the lens to connect `position` to, for paths traced from the lights. Returns the point, the
+    // film position the connection lands on and the importance arriving at `position`, divided by the solid angle
+    // density of the picked direction
+    pub fn sample_importance(&self, position: Vector3<f32>, u: Vector2<f32>) -> Option<ImportanceSample> {
+        let lens_position = if self.lens.aperture_radius <= 0.0 {
+            self.position
+        } else {
+            let lens = sample_aperture(self.lens.aperture_blades, u.x, u.y) * self.lens.aperture_radius;
+            self.position + self.lens_right * lens.x + self.lens_up * lens.y
+        };
+
+        let to_position = position - lens_position;
+        let distance = to_position.magnitude();
+        let direction = to_position / distance;
+        let film_position = self.lens_film_position(lens_position, direction)?;
+
+        // Importance 1 / (film area * lens area * cos⁴) normalizes the camera to one over film and lens, the
+        // lens area cancels against the density of the lens point and one cosine against the conversion to
+        // solid angle
+        let cos_theta = direction.dot(self.view_direction);
+        Some(ImportanceSample {
+            lens_position,
+            film_position,
+            weight: 1.0 / (self.film_area() * cos_theta.powi(3) * distance * distance),
+        })
+    }
+
+    // Where a ray leaving the lens in `direction` came from on the film, if it did
+    fn lens_film_position(&self, lens_position: Vector3<f32>, direction: Vector3<f32>) -> Option<Vector2<f32>> {
+        let cos_theta = direction.dot(self.view_direction);
+        if cos_theta <= 0.0 {
+            return None;
+        }
+
+        // Off the pinhole, rays through the same film position meet again on the focus plane
+        let pinhole_direction = if self.lens.aperture_radius <= 0.0 {
+            direction
+        } else {
+            lens_position + direction * (self.lens.focus_distance / cos_theta) - self.position
+        };
+
+        let offset = pinhole_direction / pinhole_direction.dot(self.view_direction) - self.film_origin;
+        let film_position = vec2(
+            offset.dot(self.film_right) / self.film_right.magnitude2(),
+            offset.dot(self.film_up) / self.film_up.magnitude2(),
+        );
+
+        let inside = film_position.x >= 0.0
+            && film_position.y >= 0.0
+            && film_position.x < self.viewport_width as f32
+            && film_position.y < self.viewport_height as f32;
+        inside.then_some(film_position)
+    }
+
+    // Of the whole film, on the plane at unit distance
+    fn film_area(&self) -> f32 {
+        self.viewport_width as f32 * self.film_right.magnitude() * self.viewport_height as f32 * self.film_up.magnitude()
+    }
+
     fn get_mouse_pos(&self, window: &Window) -> Vector2<f32> {
         let (mouse_x, mouse_y) = window.get_mouse_pos(MouseMode::Pass).unwrap();
 
@@ -65,7 +227,7 @@ impl Camera {
         self.viewport_height = height;
 
         self.recalculate_projection();
-        self.recalculate_ray_directions();
+        self.recalculate_lens_axes();
     }
 
     pub fn on_update(&mut self, dur: Duration, window: &mut Window) -> bool {
@@ -134,7 +296,7 @@ impl Camera {
 
         if moved {
             self.recalculate_view();
-            self.recalculate_ray_directions();
+            self.recalculate_lens_axes();
         }
 
         moved
@@ -149,23 +311,83 @@ impl Camera {
         self.inverse_projection = self.projection.invert().unwrap();
     }
     fn recalculate_view(&mut self) {
-        self.view = Matrix4::look_to_lh(Point3::from_vec(self.position), self.forward_direction, vec3(0.0, 1.0, 0.0));
+        self.view = Matrix4::look_to_lh(Point3::from_vec(self.position), self.forward_direction, up_vector(self.forward_direction));
 
 
         self.inverse_view = self.view.invert().unwrap();
     }
-    fn recalculate_ray_directions(&mut self) {
-        self.ray_directions.resize(self.viewport_width * self.viewport_height, Vector3::zero());
+    fn recalculate_lens_axes(&mut self) {
+        self.view_direction = self
+            .get_direction(self.viewport_width as f32 * 0.5, self.viewport_height as f32 * 0.5)
+            .normalize();
+        self.lens_right = self.view_direction.cross(up_vector(self.view_direction)).normalize();
+        self.lens_up = self.lens_right.cross(self.view_direction);
+
+        let on_film_plane = |direction: Vector3<f32>| direction / direction.dot(self.view_direction);
+        let film_origin = on_film_plane(self.get_direction(0.0, 0.0));
+        let film_right = on_film_plane(self.get_direction(1.0, 0.0)) - film_origin;
+        let film_up = on_film_plane(self.get_direction(0.0, 1.0)) - film_origin;
+        (self.film_origin, self.film_right, self.film_up) = (film_origin, film_right, film_up);
+    }
+}
+
+// The world up axis, or another one when looking straight along it, which would leave no horizon to orient the view
+// and the lens by
+fn up_vector(direction: Vector3<f32>) -> Vector3<f32> {
+    if direction.cross(vec3(0.0, 1.0, 0.0)).magnitude2() > 1e-12 {
+        vec3(0.0, 1.0, 0.0)
+    } else {
+        vec3(0.0, 0.0, 1.0)
+    }
+}
+
+// Uniform point on the unit disk, or on a regular polygon inscribed in it
+fn sample_aperture(blades: u32, u1: f32, u2: f32) -> Vector2<f32> {
+    if blades < 3 {
+        return sample_concentric_disk(vec2(u1, u2));
+    }
 
-        for y in 0..self.viewport_height {
-            for x in 0..self.viewport_width {
-                let mut coord = vec2((x as f32) / (self.viewport_width as f32), (y as f32) / (self.viewport_height as f32));
+    // Pick one of the equal triangles fanning out from the center, then a uniform point inside it
+    let scaled = u1 * blades as f32;
+    let blade = scaled.floor().min(blades as f32 - 1.0);
+    let u1 = scaled - blade;
 
-                coord = (coord * 2.0).sub_element_wise(1.0);
+    let angle = 2.0 * PI / blades as f32;
+    let corner = |i: f32| vec2((PI / 2.0 + i * angle).cos(), (PI / 2.0 + i * angle).sin());
+
+    let b = sample_uniform_triangle(vec2(u1, u2));
+    corner(blade) * b.y + corner(blade + 1.0) * b.z
+}
 
-                let target = self.inverse_projection * vec4(coord.x, coord.y, 1.0, 1.0);
-                let ray_direction = (self.inverse_view * (target.truncate() / target.w).normalize().extend(0.0)).truncate();
-                self.ray_directions[x + y * self.viewport_width] = ray_direction;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::SamplerKind;
+
+    #[test]
+    fn lens_stays_defined_looking_straight_up_or_down() {
+        for forward_direction in [vec3(0.0, 1.0, 0.0), vec3(0.0, -1.0, 0.0), vec3(0.0, 0.0, 1.0)] {
+            let mut camera = Camera::new(45.0, 0.1, 100.0);
+            camera.set_lens(Lens {
+                aperture_radius: 0.2,
+                focus_distance: 5.0,
+                aperture_blades: 6,
+            });
+            camera.on_resize(64, 48);
+            camera.forward_direction = forward_direction;
+            camera.recalculate_view();
+            camera.recalculate_lens_axes();
+
+            let (right, up, view) = (camera.lens_right, camera.lens_up, camera.view_direction);
+            assert!((right.magnitude() - 1.0).abs() < 1e-5 && (up.magnitude() - 1.0).abs() < 1e-5);
+            assert!(right.dot(up).abs() < 1e-5 && right.dot(view).abs() < 1e-5 && up.dot(view).abs() < 1e-5);
+
+            let mut sampler = SamplerKind::Independent.create();
+            for (x, y) in [(0.0, 0.0), (32.0, 24.0), (63.5, 47.5)] {
+                sampler.start_pixel_sample(0, 0, 0);
+                let ray = camera.get_ray(x, y, sampler.as_mut());
+                assert!(ray.origin.x.is_finite() && ray.origin.y.is_finite() && ray.origin.z.is_finite());
+                assert!((ray.direction.magnitude() - 1.0).abs() < 1e-5);
             }
         }
     }
@@ -0,0 +1,132 @@
+use cgmath::{ElementWise, InnerSpace, Vector3, Vector4, Zero};
+use rayon::prelude::*;
+
+use crate::aov::AovPixel;
+use crate::utils::luminance;
+
+const ITERATIONS: u32 = 5;
+// B3 spline, the 5x5 kernel is its outer product
+const KERNEL: [f32; 3] = [3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+const SIGMA_LUMINANCE: f32 = 4.0;
+const NORMAL_POWER: i32 = 128;
+// Relative to the depth of the center pixel, per pixel of distance
+const SIGMA_DEPTH: f32 = 0.02;
+
+// Edge-avoiding à-trous wavelet filter (Dammertz et al. 2010) with the luminance weight scaled by the estimated
+// standard deviation like SVGF (Schied et al. 2017). Texture detail is kept by filtering the color divided by
+// the albedo and multiplying it back afterwards
+pub fn denoise(width: usize, height: usize, colors: &[Vector4<f32>], pixels: &[AovPixel]) -> Vec<Vector4<f32>> {
+    let albedo: Vec<Vector3<f32>> = pixels
+        .iter()
+        .map(|f| (f.albedo / f.samples.max(1) as f32).map(|a| if a > 1e-3 { a } else { 1.0 }))
+        .collect();
+    let normals: Vec<Vector3<f32>> = pixels
+        .iter()
+        .map(|f| if f.normal.magnitude2() > 0.0 { f.normal.normalize() } else { Vector3::zero() })
+        .collect();
+    let depths: Vec<f32> = pixels.iter().map(|f| f.depth / f.samples.max(1) as f32).collect();
+
+    let mut irradiance: Vec<Vector3<f32>> = colors
+        .iter()
+        .zip(&albedo)
+        .map(|(color, albedo)| color.truncate().div_element_wise(*albedo))
+        .collect();
+    // Variance of the pixel mean, demodulated like the color
+    let mut variance: Vec<f32> = pixels
+        .iter()
+        .zip(&albedo)
+        .map(|(f, albedo)| f.variance() / luminance(*albedo).powi(2))
+        .collect();
+
+    // Offset, kernel weight and distance in pixels of each tap, before scaling by the step
+    let taps: Vec<(isize, isize, f32, f32)> = (-2isize..=2)
+        .flat_map(|dy| (-2isize..=2).map(move |dx| (dx, dy)))
+        .map(|(dx, dy)| (dx, dy, KERNEL[dx.unsigned_abs()] * KERNEL[dy.unsigned_abs()], ((dx * dx + dy * dy) as f32).sqrt()))
+        .collect();
+
+    for iteration in 0..ITERATIONS {
+        let step = 1isize << iteration;
+        let luminances: Vec<f32> = irradiance.iter().map(|c| luminance(*c)).collect();
+
+        let filtered: Vec<(Vector3<f32>, f32)> = (0..width * height)
+            .into_par_iter()
+            .map(|index| {
+                let (x, y) = ((index % width) as isize, (index / width) as isize);
+                let sigma = SIGMA_LUMINANCE * variance[index].sqrt() + 1e-4;
+
+                let mut sum = Vector3::zero();
+                let mut variance_sum = 0.0;
+                let mut weight_sum = 0.0;
+                for &(dx, dy, kernel, distance) in &taps {
+                    let (qx, qy) = (x + dx * step, y + dy * step);
+                    if qx < 0 || qy < 0 || qx >= width as isize || qy >= height as isize {
+                        continue;
+                    }
+                    let q = qx as usize + qy as usize * width;
+
+                    // The center pixel always counts fully, so a pixel can never lose all its weight
+                    let weight = if q == index {
+                        kernel
+                    } else {
+                        let normal_weight = normals[index].dot(normals[q]).max(0.0).powi(NORMAL_POWER);
+                        if normal_weight <= 0.0 {
+                            continue;
+                        }
+                        let luminance_weight = -(luminances[index] - luminances[q]).abs() / sigma;
+                        let depth_weight = -(depths[index] - depths[q]).abs()
+                            / (SIGMA_DEPTH * distance * step as f32 * depths[index] + 1e-4);
+                        kernel * normal_weight * (luminance_weight + depth_weight).exp()
+                    };
+
+                    sum += irradiance[q] * weight;
+                    variance_sum += variance[q] * weight * weight;
+                    weight_sum += weight;
+                }
+
+                (sum / weight_sum, variance_sum / (weight_sum * weight_sum))
+            })
+            .collect();
+
+        for (index, (color, pixel_variance)) in filtered.into_iter().enumerate() {
+            irradiance[index] = color;
+            variance[index] = pixel_variance;
+        }
+    }
+
+    irradiance
+        .iter()
+        .zip(&albedo)
+        .zip(colors)
+        .map(|((irradiance, albedo), color)| irradiance.mul_element_wise(*albedo).extend(color.w))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::vec3;
+
+    use super::*;
+
+    #[test]
+    fn keeps_a_constant_image() {
+        let (width, height) = (37, 23);
+        let color = vec3(0.3, 0.5, 0.8).extend(2.0);
+        let samples = 8;
+        let pixel = AovPixel {
+            depth: 4.0 * samples as f32,
+            normal: vec3(0.0, 0.0, samples as f32),
+            albedo: vec3(0.6, 0.6, 0.6) * samples as f32,
+            samples,
+            luminance: 0.45 * samples as f32,
+            luminance_squared: 0.25 * samples as f32,
+            ..AovPixel::default()
+        };
+
+        let denoised = denoise(width, height, &vec![color; width * height], &vec![pixel; width * height]);
+        assert_eq!(denoised.len(), width * height);
+        for value in denoised {
+            assert!((value - color).magnitude() < 1e-5, "{value:?}");
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use std::str::FromStr;
+
+use cgmath::{vec3, Vector3, Vector4};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ToneMapper {
+    Clamp,
+    Reinhard,
+    AcesFilmic,
+    Agx,
+}
+
+impl ToneMapper {
+    pub const fn next(self) -> Self {
+        match self {
+            ToneMapper::Clamp => ToneMapper::Reinhard,
+            ToneMapper::Reinhard => ToneMapper::AcesFilmic,
+            ToneMapper::AcesFilmic => ToneMapper::Agx,
+            ToneMapper::Agx => ToneMapper::Clamp,
+        }
+    }
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            ToneMapper::Clamp => "clamp",
+            ToneMapper::Reinhard => "reinhard",
+            ToneMapper::AcesFilmic => "aces",
+            ToneMapper::Agx => "agx",
+        }
+    }
+
+    // Maps linear scene radiance to linear display values in [0, 1]
+    fn apply(self, color: Vector3<f32>) -> Vector3<f32> {
+        match self {
+            ToneMapper::Clamp => color,
+            ToneMapper::Reinhard => color.map(|c| c / (1.0 + c)),
+            ToneMapper::AcesFilmic => aces_filmic(color),
+            ToneMapper::Agx => agx(color),
+        }
+    }
+}
+
+impl FromStr for ToneMapper {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [ToneMapper::Clamp, ToneMapper::Reinhard, ToneMapper::AcesFilmic, ToneMapper::Agx]
+            .into_iter()
+            .find(|t| t.name() == s)
+            .ok_or(())
+    }
+}
+
+// Turns accumulated linear radiance into displayable pixels, shared by the window and file output
+#[derive(Clone, Copy)]
+pub struct DisplayTransform {
+    // In stops, 0 leaves the radiance untouched
+    pub exposure: f32,
+    pub tone_mapper: ToneMapper,
+}
+
+impl Default for DisplayTransform {
+    fn default() -> Self {
+        Self {
+            exposure: 0.0,
+            tone_mapper: ToneMapper::AcesFilmic,
+        }
+    }
+}
+
+impl DisplayTransform {
+    pub fn encode(&self, color: Vector4<f32>) -> u32 {
+        let exposed = color.truncate() * self.exposure.exp2();
+        let mapped = self.tone_mapper.apply(exposed.map(|c| c.max(0.0)));
+
+        let r = encode_srgb(mapped.x);
+        let g = encode_srgb(mapped.y);
+        let b = encode_srgb(mapped.z);
+        let a = (color.w.clamp(0.0, 1.0) * 255.0) as u8;
+
+        ((a as u32) << 24) | ((r as u32) << 16) | ((g as u32) << 8) | (b as u32)
+    }
+}
+
+// Values that aren't radiance, like the visualized AOV passes, only get the sRGB encoding
+pub fn encode_data(value: Vector3<f32>) -> u32 {
+    let r = encode_srgb(value.x);
+    let g = encode_srgb(value.y);
+    let b = encode_srgb(value.z);
+
+    0xff00_0000 | ((r as u32) << 16) | ((g as u32) << 8) | (b as u32)
+}
+
+// sRGB OETF, quantized to 8 bits
+fn encode_srgb(linear: f32) -> u8 {
+    let linear = linear.clamp(0.0, 1.0);
+    let encoded = if linear <= 0.0031308 {
+        12.92 * linear
+    } else {
+        1.055 * linear.powf(1.0 / 2.4) - 0.055
+    };
+
+    (encoded * 255.0 + 0.5) as u8
+}
+
+fn mul(m: &[[f32; 3]; 3], v: Vector3<f32>) -> Vector3<f32> {
+    vec3(
+        m[0][0] * v.x + m[0][1] * v.y + m[0][2] * v.z,
+        m[1][0] * v.x + m[1][1] * v.y + m[1][2] * v.z,
+        m[2][0] * v.x + m[2][1] * v.y + m[2][2] * v.z,
+    )
+}
+
+// Stephen Hill's fit of the ACES RRT + sRGB ODT
+fn aces_filmic(color: Vector3<f32>) -> Vector3<f32> {
+    const INPUT: [[f32; 3]; 3] = [
+        [0.59719, 0.35458, 0.04823],
+        [0.07600, 0.90834, 0.01566],
+        [0.02840, 0.13383, 0.83777],
+    ];
+    const OUTPUT: [[f32; 3]; 3] = [
+        [1.60475, -0.53108, -0.07367],
+        [-0.10208, 1.10813, -0.00605],
+        [-0.00327, -0.07276, 1.07602],
+    ];
+
+    let v = mul(&INPUT, color);
+    let v = v.map(|c| (c * (c + 0.0245786) - 0.000090537) / (c * (0.983729 * c + 0.432951) + 0.238081));
+
+    mul(&OUTPUT, v)
+}
+
+// Minimal AgX with the default contrast curve approximated by a polynomial (Wrensch 2023)
+fn agx(color: Vector3<f32>) -> Vector3<f32> {
+    const INSET: [[f32; 3]; 3] = [
+        [0.84247906, 0.0784336, 0.07922375],
+        [0.04232824, 0.87846864, 0.07916613],
+        [0.04237565, 0.0784336, 0.879143],
+    ];
+    const OUTSET: [[f32; 3]; 3] = [
+        [1.196879, -0.09802088, -0.09902974],
+        [-0.05289685, 1.1519031, -0.09896118],
+        [-0.05297164, -0.09804345, 1.1510737],
+    ];
+    const MIN_EV: f32 = -12.47393;
+    const MAX_EV: f32 = 4.026069;
+
+    let v = mul(&INSET, color).map(|c| (c.max(1e-10).log2().clamp(MIN_EV, MAX_EV) - MIN_EV) / (MAX_EV - MIN_EV));
+
+    let v = v.map(|x| {
+        let x2 = x * x;
+        let x4 = x2 * x2;
+        15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x - 0.00232
+    });
+
+    // The curve outputs display encoded values, decode so the sRGB OETF can be applied uniformly
+    mul(&OUTSET, v).map(|c| c.max(0.0).powf(2.2))
+}
@@ -0,0 +1,432 @@
+use std::f32::consts::PI;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use cgmath::{vec3, InnerSpace, Vector3};
+
+use crate::utils::luminance;
+
+// Equirectangular environment light, importance sampled by luminance
+pub struct Environment {
+    width: usize,
+    height: usize,
+    pixels: Vec<Vector3<f32>>,
+
+    // Rotation around the up axis in radians
+    pub rotation: f32,
+    pub intensity: f32,
+
+    // Per row cumulative distributions over pixels, and over rows
+    conditional_cdf: Vec<f32>,
+    marginal_cdf: Vec<f32>,
+    // Sum of all pixel weights, normalizes the per pixel probability
+    total_weight: f32,
+}
+
+pub struct EnvironmentSample {
+    pub direction: Vector3<f32>,
+    pub radiance: Vector3<f32>,
+    // Solid angle density
+    pub pdf: f32,
+}
+
+impl Environment {
+    pub fn load(path: &Path, rotation: f32, intensity: f32) -> io::Result<Self> {
+        let (width, height, pixels) = decode_hdr(&fs::read(path)?)?;
+        Ok(Self::new(width, height, pixels, rotation, intensity))
+    }
+
+    pub fn new(width: usize, height: usize, pixels: Vec<Vector3<f32>>, rotation: f32, intensity: f32) -> Self {
+        let mut conditional_cdf = vec![0.0; width * height];
+        let mut marginal_cdf = vec![0.0; height];
+        let mut total_weight = 0.0;
+
+        for y in 0..height {
+            // Rows near the poles cover less solid angle
+            let sin_theta = (PI * (y as f32 + 0.5) / height as f32).sin();
+
+            let mut row_weight = 0.0;
+            for x in 0..width {
+                row_weight += luminance(pixels[x + y * width]) * sin_theta + 1e-6;
+                conditional_cdf[x + y * width] = row_weight;
+            }
+
+            total_weight += row_weight;
+            marginal_cdf[y] = total_weight;
+        }
+
+        Self {
+            width,
+            height,
+            pixels,
+            rotation,
+            intensity,
+            conditional_cdf,
+            marginal_cdf,
+            total_weight,
+        }
+    }
+
+    pub fn radiance(&self, direction: Vector3<f32>) -> Vector3<f32> {
+        let (x, y) = self.pixel_at(direction);
+        self.pixels[x + y * self.width] * self.intensity
+    }
+
+    pub fn pdf(&self, direction: Vector3<f32>) -> f32 {
+        let (x, y) = self.pixel_at(direction);
+        self.pixel_pdf(x, y, direction.y.clamp(-1.0, 1.0).acos())
+    }
+
+    pub fn sample(&self, u1: f32, u2: f32) -> Option<EnvironmentSample> {
+        let (y, offset_y) = sample_cdf(&self.marginal_cdf, u1);
+        let row = &self.conditional_cdf[y * self.width..(y + 1) * self.width];
+        let (x, offset_x) = sample_cdf(row, u2);
+
+        let u = (x as f32 + offset_x) / self.width as f32;
+        let v = (y as f32 + offset_y) / self.height as f32;
+
+        let theta = v * PI;
+        let phi = (u - 0.5) * 2.0 * PI - self.rotation;
+        let direction = vec3(theta.sin() * phi.sin(), theta.cos(), -theta.sin() * phi.cos()).normalize();
+
+        let pdf = self.pixel_pdf(x, y, theta);
+        if pdf <= 0.0 {
+            return None;
+        }
+
+        Some(EnvironmentSample {
+            direction,
+            radiance: self.pixels[x + y * self.width] * self.intensity,
+            pdf,
+        })
+    }
+
+    fn pixel_at(&self, direction: Vector3<f32>) -> (usize, usize) {
+        let phi = direction.x.atan2(-direction.z) + self.rotation;
+        let u = (phi / (2.0 * PI) + 0.5).rem_euclid(1.0);
+        let v = direction.y.clamp(-1.0, 1.0).acos() / PI;
+
+        let x = ((u * self.width as f32) as usize).min(self.width - 1);
+        let y = ((v * self.height as f32) as usize).min(self.height - 1);
+
+        (x, y)
+    }
+
+    fn pixel_pdf(&self, x: usize, y: usize, theta: f32) -> f32 {
+        let sin_theta = theta.sin();
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+
+        let row_start = if x == 0 { 0.0 } else { self.conditional_cdf[x - 1 + y * self.width] };
+        let weight = self.conditional_cdf[x + y * self.width] - row_start;
+
+        // Probability of the pixel, spread over the solid angle it covers
+        let pdf_uv = weight / self.total_weight * (self.width * self.height) as f32;
+        pdf_uv / (2.0 * PI * PI * sin_theta)
+    }
+}
+
+// Picks an entry of an unnormalized cumulative distribution, returns it with the position of `u` inside it
+fn sample_cdf(cdf: &[f32], u: f32) -> (usize, f32) {
+    let value = u * cdf[cdf.len() - 1];
+    let index = cdf.partition_point(|&c| c <= value).min(cdf.len() - 1);
+
+    let previous = if index == 0 { 0.0 } else { cdf[index - 1] };
+    let offset = ((value - previous) / (cdf[index] - previous)).clamp(0.0, 0.999_999);
+
+    (index, offset)
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+// Decodes a Radiance RGBE (.hdr) image, both flat and run length encoded scanlines
+fn decode_hdr(data: &[u8]) -> io::Result<(usize, usize, Vec<Vector3<f32>>)> {
+    let mut position = 0;
+    let next_line = |position: &mut usize| -> io::Result<String> {
+        let end = data[*position..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .ok_or_else(|| invalid_data("truncated header"))?;
+        let line = String::from_utf8_lossy(&data[*position..*position + end]).trim().to_string();
+        *position += end + 1;
+        Ok(line)
+    };
+
+    let magic = next_line(&mut position)?;
+    if !magic.starts_with("#?") {
+        return Err(invalid_data("not a Radiance HDR file"));
+    }
+
+    loop {
+        let line = next_line(&mut position)?;
+        if line.is_empty() {
+            break;
+        }
+        if let Some(format) = line.strip_prefix("FORMAT=") {
+            if format != "32-bit_rle_rgbe" {
+                return Err(invalid_data("unsupported pixel format"));
+            }
+        }
+    }
+
+    let resolution = next_line(&mut position)?;
+    let parts: Vec<&str> = resolution.split_whitespace().collect();
+    let (height, width) = match parts.as_slice() {
+        ["-Y", h, "+X", w] => (
+            h.parse::<usize>().map_err(|_| invalid_data("invalid height"))?,
+            w.parse::<usize>().map_err(|_| invalid_data("invalid width"))?,
+        ),
+        _ => return Err(invalid_data("unsupported image orientation")),
+    };
+    if width == 0 || height == 0 {
+        return Err(invalid_data("empty image"));
+    }
+
+    // Grows with the decoded scanlines, so a header claiming a huge image runs out of data before allocating for it
+    let mut rgbe = Vec::new();
+    for _ in 0..height {
+        position = read_scanline(data, position, width, &mut rgbe)?;
+    }
+
+    let pixels = rgbe
+        .iter()
+        .map(|&[r, g, b, e]| {
+            if e == 0 {
+                return vec3(0.0, 0.0, 0.0);
+            }
+            let scale = 2.0f32.powi(e as i32 - 136);
+            vec3((r as f32 + 0.5) * scale, (g as f32 + 0.5) * scale, (b as f32 + 0.5) * scale)
+        })
+        .collect();
+
+    Ok((width, height, pixels))
+}
+
+// Appends a scanline of `width` pixels to `rgbe`, returns the position after it
+fn read_scanline(data: &[u8], mut position: usize, width: usize, rgbe: &mut Vec<[u8; 4]>) -> io::Result<usize> {
+    let byte = |position: usize| data.get(position).copied().ok_or_else(|| invalid_data("truncated pixel data"));
+
+    let is_rle = (8..0x8000).contains(&width)
+        && byte(position)? == 2
+        && byte(position + 1)? == 2
+        && ((byte(position + 2)? as usize) << 8 | byte(position + 3)? as usize) == width;
+
+    if !is_rle {
+        // Flat pixels, with the old style (1, 1, 1, n) repeat markers
+        let mut x = 0;
+        let mut shift = 0;
+        while x < width {
+            let pixel = [byte(position)?, byte(position + 1)?, byte(position + 2)?, byte(position + 3)?];
+            position += 4;
+
+            if pixel[0] == 1 && pixel[1] == 1 && pixel[2] == 1 && x > 0 {
+                // Consecutive markers hold ever higher bytes of the count
+                let count = (pixel[3] as usize).checked_shl(shift).unwrap_or(usize::MAX).min(width - x);
+                let previous = rgbe[rgbe.len() - 1];
+                rgbe.extend(std::iter::repeat_n(previous, count));
+                x += count;
+                shift += 8;
+            } else {
+                rgbe.push(pixel);
+                x += 1;
+                shift = 0;
+            }
+        }
+        return Ok(position);
+    }
+
+    position += 4;
+
+    // Each channel is stored separately as runs and literals
+    let start = rgbe.len();
+    rgbe.resize(start + width, [0; 4]);
+    let scanline = &mut rgbe[start..];
+    for channel in 0..4 {
+        let mut x = 0;
+        while x < width {
+            let count = byte(position)? as usize;
+            position += 1;
+
+            if count > 128 {
+                let count = count - 128;
+                if x + count > width {
+                    return Err(invalid_data("run exceeds scanline"));
+                }
+                let value = byte(position)?;
+                position += 1;
+                for pixel in &mut scanline[x..x + count] {
+                    pixel[channel] = value;
+                }
+                x += count;
+            } else {
+                if count == 0 || x + count > width {
+                    return Err(invalid_data("invalid literal run"));
+                }
+                for pixel in &mut scanline[x..x + count] {
+                    pixel[channel] = byte(position)?;
+                    position += 1;
+                }
+                x += count;
+            }
+        }
+    }
+
+    Ok(position)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hdr(header: &str, pixels: &[u8]) -> Vec<u8> {
+        let mut data = header.as_bytes().to_vec();
+        data.extend_from_slice(pixels);
+        data
+    }
+
+    fn environment(rotation: f32) -> Environment {
+        let (width, height) = (8, 4);
+        let pixels = (0..width * height)
+            .map(|i| if i == 11 { vec3(50.0, 40.0, 30.0) } else { vec3(0.2, 0.3, 0.5) * (i % 3) as f32 })
+            .collect();
+        Environment::new(width, height, pixels, rotation, 2.0)
+    }
+
+    #[test]
+    fn decodes_flat_pixels_and_repeats() {
+        let data = hdr(
+            "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 2 +X 3\n",
+            &[128, 64, 32, 129, 1, 1, 1, 2, 0, 0, 0, 0, 10, 20, 30, 136, 1, 1, 1, 1],
+        );
+        let (width, height, pixels) = decode_hdr(&data).unwrap();
+        assert_eq!((width, height), (3, 2));
+
+        let first = vec3(128.5, 64.5, 32.5) / 128.0;
+        let second = vec3(10.5, 20.5, 30.5);
+        let expected = [first, first, first, vec3(0.0, 0.0, 0.0), second, second];
+        for (pixel, expected) in pixels.iter().zip(expected) {
+            assert!((pixel - expected).magnitude() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn decodes_run_length_scanlines() {
+        // Red as one run, green as literals, blue and exponent split into a literal and a run
+        let mut scanline = vec![2, 2, 0, 8, 128 + 8, 100, 8, 0, 10, 20, 30, 40, 50, 60, 70];
+        scanline.extend_from_slice(&[2, 5, 6, 128 + 6, 7, 128 + 8, 130]);
+        let data = hdr("#?RGBE\n\n-Y 1 +X 8\n", &scanline);
+
+        let (width, height, pixels) = decode_hdr(&data).unwrap();
+        assert_eq!((width, height), (8, 1));
+        for (x, pixel) in pixels.iter().enumerate() {
+            let blue = [5.0, 6.0, 7.0, 7.0, 7.0, 7.0, 7.0, 7.0][x];
+            let expected = vec3(100.5, x as f32 * 10.0 + 0.5, blue + 0.5) / 64.0;
+            assert!((pixel - expected).magnitude() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_files() {
+        let pixel = [128, 128, 128, 128];
+        let malformed = [
+            hdr("P6\n\n-Y 1 +X 1\n", &pixel),
+            hdr("#?RADIANCE\nFORMAT=32-bit_rle_xyze\n\n-Y 1 +X 1\n", &pixel),
+            hdr("#?RADIANCE\n\n+Y 1 +X 1\n", &pixel),
+            hdr("#?RADIANCE\n\n-Y 1 +X\n", &pixel),
+            hdr("#?RADIANCE\n\n-Y 0 +X 1\n", &pixel),
+            hdr("#?RADIANCE\n\n-Y 1 +X 1", &pixel),
+            hdr("#?RADIANCE\n\n-Y 1 +X 2\n", &pixel),
+            hdr("#?RADIANCE\n\n-Y 18446744073709551615 +X 2\n", &pixel),
+            hdr("#?RADIANCE\n\n-Y 100000000000 +X 1\n", &pixel),
+            hdr("#?RADIANCE\n\n-Y 1 +X 100000000000\n", &pixel),
+            hdr("#?RADIANCE\n\n-Y 1 +X 8\n", &[2, 2, 0, 8, 128 + 9, 1]),
+            hdr("#?RADIANCE\n\n-Y 1 +X 8\n", &[2, 2, 0, 8, 0, 1]),
+            hdr("#?RADIANCE\n\n-Y 1 +X 8\n", &[2, 2, 0, 8, 4, 1, 2, 3, 4, 5, 1, 2]),
+        ];
+
+        for data in malformed {
+            let error = decode_hdr(&data).err().unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn repeated_markers_fill_the_scanline() {
+        // Each marker shifts the count by another byte, far past the width of a real image
+        let mut pixels = vec![9, 9, 9, 129];
+        for _ in 0..12 {
+            pixels.extend_from_slice(&[1, 1, 1, 255]);
+        }
+        let data = hdr("#?RADIANCE\n\n-Y 1 +X 5\n", &pixels);
+
+        let (_, _, pixels) = decode_hdr(&data).unwrap();
+        assert!(pixels.iter().all(|&pixel| pixel == pixels[0]));
+    }
+
+    #[test]
+    fn sample_pdf_matches_pdf() {
+        for rotation in [0.0, 1.3] {
+            let environment = environment(rotation);
+            let n = 64;
+            for i in 0..n {
+                for j in 0..n {
+                    let u1 = (i as f32 + 0.5) / n as f32;
+                    let u2 = (j as f32 + 0.5) / n as f32;
+                    let sample = environment.sample(u1, u2).unwrap();
+
+                    assert!((sample.direction.magnitude() - 1.0).abs() < 1e-5);
+                    let pdf = environment.pdf(sample.direction);
+                    assert!((sample.pdf - pdf).abs() <= 1e-3 * pdf, "{} != {}", sample.pdf, pdf);
+                    assert!(sample.radiance == environment.radiance(sample.direction));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn pdf_integrates_to_one() {
+        for rotation in [0.0, 1.3] {
+            let environment = environment(rotation);
+            let (n_theta, n_phi) = (256, 512);
+            let (d_theta, d_phi) = (PI / n_theta as f32, 2.0 * PI / n_phi as f32);
+
+            let mut integral = 0.0;
+            for i in 0..n_theta {
+                let theta = (i as f32 + 0.5) * d_theta;
+                for j in 0..n_phi {
+                    let phi = (j as f32 + 0.5) * d_phi;
+                    let direction = vec3(theta.sin() * phi.cos(), theta.cos(), theta.sin() * phi.sin());
+                    integral += environment.pdf(direction) * theta.sin() * d_theta * d_phi;
+                }
+            }
+
+            assert!((integral - 1.0).abs() < 1e-2, "{}", integral);
+        }
+    }
+
+    #[test]
+    fn samples_follow_luminance() {
+        let environment = environment(0.0);
+        let bright = environment.pixels[11] * environment.intensity;
+
+        let n = 256;
+        let mut hits = 0;
+        for i in 0..n {
+            for j in 0..n {
+                let sample = environment.sample((i as f32 + 0.5) / n as f32, (j as f32 + 0.5) / n as f32).unwrap();
+                if sample.radiance == bright {
+                    hits += 1;
+                }
+            }
+        }
+
+        let (start, end) = (environment.conditional_cdf[10], environment.conditional_cdf[11]);
+        let expected = (end - start) / environment.total_weight;
+        let fraction = hits as f32 / (n * n) as f32;
+        assert!((fraction - expected).abs() < 1e-2, "{} != {}", fraction, expected);
+    }
+}
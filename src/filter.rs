@@ -0,0 +1,160 @@
+use std::f32::consts::PI;
+use std::str::FromStr;
+
+// Pixel reconstruction filters, separable and centered on the pixel, with offsets in pixels
+#[derive(Clone, Copy, PartialEq)]
+pub enum Filter {
+    Box,
+    Tent,
+    Gaussian,
+    Mitchell,
+    BlackmanHarris,
+}
+
+impl Filter {
+    pub const fn next(self) -> Self {
+        match self {
+            Filter::Box => Filter::Tent,
+            Filter::Tent => Filter::Gaussian,
+            Filter::Gaussian => Filter::Mitchell,
+            Filter::Mitchell => Filter::BlackmanHarris,
+            Filter::BlackmanHarris => Filter::Box,
+        }
+    }
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Filter::Box => "box",
+            Filter::Tent => "tent",
+            Filter::Gaussian => "gaussian",
+            Filter::Mitchell => "mitchell",
+            Filter::BlackmanHarris => "blackman-harris",
+        }
+    }
+
+    // Samples further than this from a pixel center along either axis don't contribute to it
+    pub const fn radius(self) -> f32 {
+        match self {
+            Filter::Box => 0.5,
+            Filter::Tent => 1.0,
+            Filter::Gaussian => 1.5,
+            Filter::Mitchell | Filter::BlackmanHarris => 2.0,
+        }
+    }
+
+    pub fn evaluate(self, x: f32, y: f32) -> f32 {
+        self.evaluate_1d(x) * self.evaluate_1d(y)
+    }
+
+    fn evaluate_1d(self, x: f32) -> f32 {
+        let radius = self.radius();
+        let x = x.abs();
+        if x > radius {
+            return 0.0;
+        }
+
+        match self {
+            Filter::Box => 1.0,
+            Filter::Tent => radius - x,
+            Filter::Gaussian => {
+                // Shifted down so it reaches zero at the radius
+                const SIGMA: f32 = 0.5;
+                let gaussian = |x: f32| (-x * x / (2.0 * SIGMA * SIGMA)).exp();
+                (gaussian(x) - gaussian(radius)).max(0.0)
+            }
+            Filter::Mitchell => {
+                // B = C = 1/3 as recommended by Mitchell and Netravali, negative lobes sharpen
+                const B: f32 = 1.0 / 3.0;
+                const C: f32 = 1.0 / 3.0;
+                let x = 2.0 * x / radius;
+                let value = if x < 1.0 {
+                    (12.0 - 9.0 * B - 6.0 * C) * x * x * x + (-18.0 + 12.0 * B + 6.0 * C) * x * x + (6.0 - 2.0 * B)
+                } else {
+                    (-B - 6.0 * C) * x * x * x + (6.0 * B + 30.0 * C) * x * x + (-12.0 * B - 48.0 * C) * x + (8.0 * B + 24.0 * C)
+                };
+                value / 6.0
+            }
+            Filter::BlackmanHarris => {
+                let t = 0.5 + x / (2.0 * radius);
+                0.35875 - 0.48829 * (2.0 * PI * t).cos() + 0.14128 * (4.0 * PI * t).cos() - 0.01168 * (6.0 * PI * t).cos()
+            }
+        }
+    }
+}
+
+impl FromStr for Filter {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "box" => Ok(Filter::Box),
+            "tent" => Ok(Filter::Tent),
+            "gaussian" => Ok(Filter::Gaussian),
+            "mitchell" => Ok(Filter::Mitchell),
+            "blackman-harris" => Ok(Filter::BlackmanHarris),
+            _ => Err(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILTERS: [Filter; 5] = [Filter::Box, Filter::Tent, Filter::Gaussian, Filter::Mitchell, Filter::BlackmanHarris];
+
+    // Midpoint rule over the support
+    fn integral(filter: Filter) -> f32 {
+        let steps = 4000;
+        let width = 2.0 * filter.radius() / steps as f32;
+        (0..steps).map(|i| filter.evaluate_1d(-filter.radius() + (i as f32 + 0.5) * width) * width).sum()
+    }
+
+    #[test]
+    fn weights_vanish_outside_the_radius() {
+        for filter in FILTERS {
+            let radius = filter.radius();
+            assert_eq!(filter.evaluate(radius + 0.01, 0.0), 0.0, "{}", filter.name());
+            assert_eq!(filter.evaluate(0.0, -radius - 0.01), 0.0, "{}", filter.name());
+            if filter != Filter::Box {
+                assert!(filter.evaluate_1d(radius - 1e-3).abs() < 1e-3, "{}", filter.name());
+            }
+        }
+    }
+
+    #[test]
+    fn weights_are_symmetric_separable_and_peak_at_the_center() {
+        for filter in FILTERS {
+            let center = filter.evaluate(0.0, 0.0);
+            assert!(center > 0.0, "{}", filter.name());
+            for i in 0..20 {
+                let x = filter.radius() * i as f32 / 20.0;
+                let y = 0.3 * filter.radius();
+                assert_eq!(filter.evaluate_1d(x), filter.evaluate_1d(-x), "{}", filter.name());
+                assert_eq!(filter.evaluate(x, y), filter.evaluate_1d(x) * filter.evaluate_1d(y), "{}", filter.name());
+                assert!(filter.evaluate(x, 0.0) <= center, "{}", filter.name());
+            }
+        }
+    }
+
+    #[test]
+    fn mitchell_is_normalized_with_negative_lobes() {
+        assert!((integral(Filter::Mitchell) - 1.0).abs() < 1e-3);
+        assert!(Filter::Mitchell.evaluate_1d(1.5) < 0.0);
+        for filter in [Filter::Box, Filter::Tent, Filter::Gaussian, Filter::BlackmanHarris] {
+            assert!(integral(filter) > 0.0, "{}", filter.name());
+            assert!((0..100).all(|i| filter.evaluate_1d(filter.radius() * i as f32 / 100.0) >= 0.0), "{}", filter.name());
+        }
+    }
+
+    #[test]
+    fn names_round_trip_and_next_cycles_through_all() {
+        let mut filter = Filter::Box;
+        for expected in FILTERS {
+            assert!(filter == expected);
+            assert!(filter.name().parse::<Filter>() == Ok(filter));
+            filter = filter.next();
+        }
+        assert!(filter == Filter::Box);
+    }
+}
@@ -0,0 +1,373 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use cgmath::{vec3, Vector3};
+
+fn extension(path: &Path) -> Option<String> {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase())
+}
+
+fn unsupported_format(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("unsupported image format '{}'", path.display()),
+    )
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+// Writes an ARGB buffer (as produced by `Renderer::render`) to disk, picking the format from the extension
+pub fn write_image(path: &Path, width: usize, height: usize, buffer: &[u32]) -> io::Result<()> {
+    match extension(path).as_deref() {
+        Some("ppm") => write_ppm(path, width, height, buffer),
+        Some("png") => write_png(path, width, height, buffer),
+        _ => Err(unsupported_format(path)),
+    }
+}
+
+// Whether the extension is one `write_float_image` handles rather than `write_image`
+pub fn is_float_image(path: &Path) -> bool {
+    extension(path).as_deref() == Some("pfm")
+}
+
+// Writes linear RGB values without any display transform, bottom row first like the renderer stores them
+pub fn write_float_image(path: &Path, width: usize, height: usize, pixels: &[Vector3<f32>]) -> io::Result<()> {
+    match extension(path).as_deref() {
+        Some("pfm") => write_pfm(path, width, height, pixels),
+        _ => Err(unsupported_format(path)),
+    }
+}
+
+// Reads a PPM, PNG or TGA image as RGB values in [0, 1], top row first and still in the file's encoding
+pub fn read_image(path: &Path) -> io::Result<(usize, usize, Vec<Vector3<f32>>)> {
+    let (width, height, rgb) = match extension(path).as_deref() {
+        Some("ppm") => read_ppm(&fs::read(path)?)?,
+        Some("png") => read_png(path)?,
+        Some("tga") => read_tga(&fs::read(path)?)?,
+        _ => return Err(unsupported_format(path)),
+    };
+
+    let pixels = rgb
+        .chunks_exact(3)
+        .map(|c| vec3(c[0] as f32, c[1] as f32, c[2] as f32) / 255.0)
+        .collect();
+
+    Ok((width, height, pixels))
+}
+
+// Binary (P6) and plain (P3) pixmaps, scaled to 8 bits
+fn read_ppm(data: &[u8]) -> io::Result<(usize, usize, Vec<u8>)> {
+    let next_token = |position: &mut usize| -> io::Result<String> {
+        loop {
+            match data.get(*position) {
+                Some(b'#') => {
+                    while data.get(*position).is_some_and(|&b| b != b'\n') {
+                        *position += 1;
+                    }
+                }
+                Some(b) if b.is_ascii_whitespace() => *position += 1,
+                Some(_) => break,
+                None => return Err(invalid_data("truncated header")),
+            }
+        }
+
+        let start = *position;
+        while data.get(*position).is_some_and(|b| !b.is_ascii_whitespace()) {
+            *position += 1;
+        }
+        Ok(String::from_utf8_lossy(&data[start..*position]).to_string())
+    };
+    let number = |position: &mut usize| -> io::Result<usize> {
+        next_token(position)?.parse().map_err(|_| invalid_data("invalid header"))
+    };
+
+    let mut position = 0;
+    let magic = next_token(&mut position)?;
+    let (width, height, max_value) = (number(&mut position)?, number(&mut position)?, number(&mut position)?);
+    if max_value == 0 || max_value > 65535 {
+        return Err(invalid_data("invalid maximum value"));
+    }
+    if width == 0 || height == 0 {
+        return Err(invalid_data("empty image"));
+    }
+
+    let count = width
+        .checked_mul(height)
+        .and_then(|pixels| pixels.checked_mul(3))
+        .ok_or_else(|| invalid_data("image too large"))?;
+    let scale = |value: usize| (value.min(max_value) * 255 / max_value) as u8;
+
+    let rgb = match magic.as_str() {
+        "P3" => (0..count).map(|_| number(&mut position).map(scale)).collect::<io::Result<Vec<u8>>>()?,
+        "P6" => {
+            // A single whitespace byte separates the header from the pixels
+            let start = position + 1;
+            let bytes_per_value = if max_value < 256 { 1 } else { 2 };
+            let pixels = count
+                .checked_mul(bytes_per_value)
+                .and_then(|length| data.get(start..start.checked_add(length)?))
+                .ok_or_else(|| invalid_data("truncated pixel data"))?;
+
+            if bytes_per_value == 1 {
+                pixels.iter().map(|&v| scale(v as usize)).collect()
+            } else {
+                pixels.chunks_exact(2).map(|v| scale((v[0] as usize) << 8 | v[1] as usize)).collect()
+            }
+        }
+        _ => return Err(invalid_data("not a PPM file")),
+    };
+
+    Ok((width, height, rgb))
+}
+
+fn read_png(path: &Path) -> io::Result<(usize, usize, Vec<u8>)> {
+    let mut decoder = png::Decoder::new(File::open(path)?);
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+
+    let mut reader = decoder.read_info()?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer)?;
+    let data = &buffer[..info.buffer_size()];
+
+    let rgb = match info.color_type {
+        png::ColorType::Rgb => data.to_vec(),
+        png::ColorType::Rgba => data.chunks_exact(4).flat_map(|p| [p[0], p[1], p[2]]).collect(),
+        png::ColorType::Grayscale => data.iter().flat_map(|&g| [g, g, g]).collect(),
+        png::ColorType::GrayscaleAlpha => data.chunks_exact(2).flat_map(|p| [p[0], p[0], p[0]]).collect(),
+        png::ColorType::Indexed => return Err(invalid_data("unexpanded palette")),
+    };
+
+    Ok((info.width as usize, info.height as usize, rgb))
+}
+
+// Uncompressed and run length encoded true color and grayscale targas
+fn read_tga(data: &[u8]) -> io::Result<(usize, usize, Vec<u8>)> {
+    let header = data.get(..18).ok_or_else(|| invalid_data("truncated header"))?;
+    let id_length = header[0] as usize;
+    let color_map_type = header[1];
+    let image_type = header[2];
+    let width = u16::from_le_bytes([header[12], header[13]]) as usize;
+    let height = u16::from_le_bytes([header[14], header[15]]) as usize;
+    let bits_per_pixel = header[16];
+    let top_down = header[17] & 0x20 != 0;
+
+    if width == 0 || height == 0 {
+        return Err(invalid_data("empty image"));
+    }
+    if color_map_type != 0 {
+        return Err(invalid_data("color mapped targas are not supported"));
+    }
+
+    let (grayscale, rle) = match image_type {
+        2 => (false, false),
+        3 => (true, false),
+        10 => (false, true),
+        11 => (true, true),
+        _ => return Err(invalid_data("unsupported targa image type")),
+    };
+    let bytes_per_pixel = match (grayscale, bits_per_pixel) {
+        (true, 8) => 1,
+        (false, 24) => 3,
+        (false, 32) => 4,
+        _ => return Err(invalid_data("unsupported targa pixel depth")),
+    };
+
+    let mut position = 18 + id_length;
+    let pixel = |position: &mut usize| -> io::Result<[u8; 3]> {
+        let p = data
+            .get(*position..*position + bytes_per_pixel)
+            .ok_or_else(|| invalid_data("truncated pixel data"))?;
+        *position += bytes_per_pixel;
+        // Stored as BGR(A)
+        Ok(if grayscale { [p[0], p[0], p[0]] } else { [p[2], p[1], p[0]] })
+    };
+
+    // Uncompressed pixels must all be there before allocating for them, run length encoded ones grow with the data
+    let count = width * height;
+    let mut pixels = if rle {
+        Vec::new()
+    } else if count * bytes_per_pixel <= data.len().saturating_sub(position) {
+        Vec::with_capacity(count)
+    } else {
+        return Err(invalid_data("truncated pixel data"));
+    };
+    while pixels.len() < count {
+        if !rle {
+            pixels.push(pixel(&mut position)?);
+            continue;
+        }
+
+        // Packets are a header byte followed by one repeated pixel or a run of literal ones
+        let packet = *data.get(position).ok_or_else(|| invalid_data("truncated pixel data"))?;
+        position += 1;
+        let length = (packet & 0x7f) as usize + 1;
+        if packet & 0x80 != 0 {
+            let value = pixel(&mut position)?;
+            pixels.extend(std::iter::repeat_n(value, length));
+        } else {
+            for _ in 0..length {
+                pixels.push(pixel(&mut position)?);
+            }
+        }
+    }
+    pixels.truncate(count);
+
+    // Bottom row first unless the descriptor says otherwise
+    if !top_down {
+        let rows: Vec<&[[u8; 3]]> = pixels.chunks(width).rev().collect();
+        pixels = rows.concat();
+    }
+
+    Ok((width, height, pixels.concat()))
+}
+
+fn to_rgb(buffer: &[u32]) -> Vec<u8> {
+    buffer
+        .iter()
+        .flat_map(|pixel| [(pixel >> 16) as u8, (pixel >> 8) as u8, *pixel as u8])
+        .collect()
+}
+
+fn write_ppm(path: &Path, width: usize, height: usize, buffer: &[u32]) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+
+    write!(writer, "P6\n{} {}\n255\n", width, height)?;
+    writer.write_all(&to_rgb(buffer))?;
+
+    writer.flush()
+}
+
+// Portable float map, a negative scale marks little endian. Rows go from the bottom up
+fn write_pfm(path: &Path, width: usize, height: usize, pixels: &[Vector3<f32>]) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+
+    write!(writer, "PF\n{} {}\n-1.0\n", width, height)?;
+    for pixel in pixels {
+        for c in [pixel.x, pixel.y, pixel.z] {
+            writer.write_all(&c.to_le_bytes())?;
+        }
+    }
+
+    writer.flush()
+}
+
+fn write_png(path: &Path, width: usize, height: usize, buffer: &[u32]) -> io::Result<()> {
+    let writer = BufWriter::new(File::create(path)?);
+
+    let mut encoder = png::Encoder::new(writer, width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&to_rgb(buffer))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_plain_and_binary_ppm() {
+        let plain = b"P3\n# a comment\n2 1\n255\n255 0 0  0 128 255\n";
+        assert_eq!(read_ppm(plain).unwrap(), (2, 1, vec![255, 0, 0, 0, 128, 255]));
+
+        let mut binary = b"P6\n1 2\n255\n".to_vec();
+        binary.extend([10, 20, 30, 40, 50, 60]);
+        assert_eq!(read_ppm(&binary).unwrap(), (1, 2, vec![10, 20, 30, 40, 50, 60]));
+    }
+
+    #[test]
+    fn scales_ppm_to_8_bits() {
+        assert_eq!(read_ppm(b"P3 1 1 1 1 0 1").unwrap().2, vec![255, 0, 255]);
+
+        let mut wide = b"P6 1 1 65535\n".to_vec();
+        wide.extend([0xff, 0xff, 0x00, 0x00, 0x80, 0x00]);
+        assert_eq!(read_ppm(&wide).unwrap().2, vec![255, 0, 127]);
+    }
+
+    #[test]
+    fn rejects_malformed_ppm() {
+        for data in [
+            &b""[..],
+            b"P6",
+            b"P6 1 1",
+            b"P5 1 1 255 \x00",
+            b"P6 x 1 255 \x00\x00\x00",
+            b"P6 1 1 0 \x00\x00\x00",
+            b"P6 0 1 255 ",
+            b"P3 1 0 255",
+            b"P6 2 1 255 \x00\x00\x00",
+            b"P3 1 1 255 1 2",
+            b"P6 18446744073709551615 2 255 \x00",
+            b"P6 4294967296 4294967296 255 \x00",
+        ] {
+            let error = read_ppm(data).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{}", String::from_utf8_lossy(data));
+        }
+    }
+
+    fn tga(image_type: u8, width: u16, height: u16, bits_per_pixel: u8, descriptor: u8, pixels: &[u8]) -> Vec<u8> {
+        let mut data = vec![0; 18];
+        data[2] = image_type;
+        data[12..14].copy_from_slice(&width.to_le_bytes());
+        data[14..16].copy_from_slice(&height.to_le_bytes());
+        data[16] = bits_per_pixel;
+        data[17] = descriptor;
+        data.extend(pixels);
+        data
+    }
+
+    #[test]
+    fn reads_uncompressed_tga() {
+        // Bottom row first and BGR unless the descriptor says otherwise
+        let bottom_up = tga(2, 2, 2, 24, 0, &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]);
+        assert_eq!(read_tga(&bottom_up).unwrap(), (2, 2, vec![9, 8, 7, 12, 11, 10, 3, 2, 1, 6, 5, 4]));
+
+        let top_down = tga(2, 1, 2, 32, 0x20, &[1, 2, 3, 255, 4, 5, 6, 255]);
+        assert_eq!(read_tga(&top_down).unwrap(), (1, 2, vec![3, 2, 1, 6, 5, 4]));
+
+        let grayscale = tga(3, 2, 1, 8, 0, &[7, 9]);
+        assert_eq!(read_tga(&grayscale).unwrap(), (2, 1, vec![7, 7, 7, 9, 9, 9]));
+    }
+
+    #[test]
+    fn reads_run_length_encoded_tga() {
+        // A run of three and a literal packet of one
+        let data = tga(10, 4, 1, 24, 0x20, &[0x82, 1, 2, 3, 0x00, 4, 5, 6]);
+        assert_eq!(read_tga(&data).unwrap(), (4, 1, vec![3, 2, 1, 3, 2, 1, 3, 2, 1, 6, 5, 4]));
+
+        // Runs may cross the end of the image
+        let data = tga(11, 2, 1, 8, 0x20, &[0x83, 5]);
+        assert_eq!(read_tga(&data).unwrap(), (2, 1, vec![5; 6]));
+    }
+
+    #[test]
+    fn rejects_malformed_tga() {
+        let mut color_mapped = tga(2, 1, 1, 24, 0, &[0, 0, 0]);
+        color_mapped[1] = 1;
+
+        for data in [
+            vec![0; 17],
+            tga(2, 0, 1, 24, 0, &[]),
+            tga(10, 1, 0, 24, 0, &[]),
+            color_mapped,
+            tga(1, 1, 1, 8, 0, &[0]),
+            tga(2, 1, 1, 16, 0, &[0, 0]),
+            tga(3, 1, 1, 24, 0, &[0, 0, 0]),
+            tga(2, 2, 1, 24, 0, &[0, 0, 0]),
+            tga(10, 2, 1, 24, 0, &[0x81]),
+            // Claims 12 GB of pixels in an 18 byte file
+            tga(2, 65535, 65535, 24, 0, &[]),
+            tga(10, 65535, 65535, 24, 0, &[0xff, 1, 2, 3]),
+        ] {
+            assert_eq!(read_tga(&data).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
@@ -0,0 +1,37 @@
+use std::str::FromStr;
+
+// How light transport is estimated for each pixel sample
+#[derive(Clone, Copy, PartialEq)]
+pub enum Integrator {
+    // Paths from the camera with next-event estimation
+    Path,
+    // Paths from the camera and from the lights, joined at every pair of vertices
+    Bidirectional,
+}
+
+impl Integrator {
+    pub const fn next(self) -> Self {
+        match self {
+            Integrator::Path => Integrator::Bidirectional,
+            Integrator::Bidirectional => Integrator::Path,
+        }
+    }
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Integrator::Path => "path",
+            Integrator::Bidirectional => "bidirectional",
+        }
+    }
+}
+
+impl FromStr for Integrator {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [Integrator::Path, Integrator::Bidirectional]
+            .into_iter()
+            .find(|integrator| integrator.name() == s)
+            .ok_or(())
+    }
+}
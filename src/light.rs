@@ -0,0 +1,342 @@
+use cgmath::{InnerSpace, MetricSpace, Vector2, Vector3};
+
+use crate::scene::{Primitive, Scene, Sphere};
+use crate::shape::Shape;
+use crate::sampler::Sampler;
+use crate::utils::{
+    cosine_hemisphere_pdf, from_local, sample_cosine_hemisphere, sample_uniform_cone, sample_uniform_triangle, uniform_cone_pdf,
+};
+
+pub struct LightSample {
+    pub direction: Vector3<f32>,
+    pub distance: f32,
+    pub primitive_index: usize,
+    // Outward facing, at the sampled point on the light
+    pub normal: Vector3<f32>,
+    pub radiance: Vector3<f32>,
+    // Solid angle density, including the probability of picking this light
+    pub pdf: f32,
+}
+
+// Where a path traced from the lights starts and the direction it leaves in
+pub struct EmissionSample {
+    pub position: Vector3<f32>,
+    pub normal: Vector3<f32>,
+    pub direction: Vector3<f32>,
+    pub primitive_index: usize,
+    pub radiance: Vector3<f32>,
+    // Area density of the position, including the probability of picking this light, and solid angle density of
+    // the direction
+    pub position_pdf: f32,
+    pub direction_pdf: f32,
+}
+
+// Samples a direction towards a uniformly chosen light. Spheres are sampled uniformly within the cone they
+// subtend, other shapes and triangles uniformly by area
+pub fn sample(scene: &Scene, position: Vector3<f32>, time: f32, sampler: &mut dyn Sampler) -> Option<LightSample> {
+    if scene.lights.is_empty() {
+        return None;
+    }
+
+    let pick = ((sampler.get_1d() * scene.lights.len() as f32) as usize).min(scene.lights.len() - 1);
+    let primitive_index = scene.lights[pick];
+
+    let sample = match scene.primitives[primitive_index] {
+        Primitive::Sphere(sphere_index) => sample_sphere(scene, primitive_index, &scene.spheres[sphere_index].at_time(time), position, sampler),
+        _ => sample_area(scene, primitive_index, time, position, sampler),
+    }?;
+
+    Some(LightSample {
+        pdf: sample.pdf / scene.lights.len() as f32,
+        ..sample
+    })
+}
+
+fn sample_sphere(scene: &Scene, primitive_index: usize, sphere: &Sphere, position: Vector3<f32>, sampler: &mut dyn Sampler) -> Option<LightSample> {
+
+    let to_center = sphere.position - position;
+    let distance_squared = to_center.magnitude2();
+    let radius_squared = sphere.radius * sphere.radius;
+    if distance_squared <= radius_squared {
+        return None;
+    }
+
+    let distance = distance_squared.sqrt();
+    let axis = to_center / distance;
+    let cos_theta_max = (1.0 - radius_squared / distance_squared).max(0.0).sqrt();
+
+    let direction = from_local(sample_uniform_cone(sampler.get_2d(), cos_theta_max), axis);
+
+    // Distance to the near intersection with the sphere along the sampled direction. The discriminant goes through
+    // the distance of the center from the ray, b² - |to_center|² cancels out for small spheres far away
+    let b = direction.dot(to_center);
+    let perpendicular = to_center - direction * b;
+    let hit_distance = b - (radius_squared - perpendicular.magnitude2()).max(0.0).sqrt();
+
+    let light_position = position + direction * hit_distance;
+    let surface = sphere.surface(light_position, 0);
+    let material = scene.materials[sphere.material_index].resolve(&scene.textures, surface.uv, light_position);
+
+    Some(LightSample {
+        direction,
+        distance: hit_distance,
+        primitive_index,
+        normal: surface.normal,
+        radiance: material.get_emission(),
+        pdf: uniform_cone_pdf(cos_theta_max),
+    })
+}
+
+// A point uniformly distributed over the area of a light
+struct AreaSample {
+    position: Vector3<f32>,
+    // Outward facing
+    normal: Vector3<f32>,
+    uv: Vector2<f32>,
+    material_index: usize,
+}
+
+fn sample_point(scene: &Scene, primitive_index: usize, time: f32, u: Vector2<f32>) -> Option<AreaSample> {
+    let on_shape = |shape: &dyn Shape, material_index: usize| {
+        let surface = shape.sample_surface(u)?;
+        Some(AreaSample {
+            position: surface.position,
+            normal: surface.normal,
+            uv: shape.surface(surface.position, surface.part).uv,
+            material_index,
+        })
+    };
+
+    match scene.primitives[primitive_index] {
+        Primitive::Sphere(sphere_index) => {
+            let sphere = scene.spheres[sphere_index].at_time(time);
+            on_shape(&sphere, sphere.material_index)
+        }
+        Primitive::Object(object_index) => {
+            let object = &scene.objects[object_index];
+            on_shape(object.shape.as_ref(), object.material_index)
+        }
+        Primitive::Triangle { mesh, triangle } => {
+            let mesh = &scene.meshes[mesh];
+            let b = sample_uniform_triangle(u);
+            let surface = mesh.surface(triangle, b.y, b.z);
+            Some(AreaSample {
+                position: mesh.position(triangle, b.y, b.z),
+                normal: surface.geometric_normal,
+                uv: surface.uv,
+                material_index: mesh.triangles[triangle].material_index,
+            })
+        }
+        Primitive::Instance(_) => None,
+    }
+}
+
+// Zero for instances, which aren't sampled, see `Scene::rebuild`
+fn area(scene: &Scene, primitive_index: usize, time: f32) -> f32 {
+    match scene.primitives[primitive_index] {
+        Primitive::Sphere(sphere_index) => scene.spheres[sphere_index].at_time(time).area(),
+        Primitive::Object(object_index) => scene.objects[object_index].shape.area(),
+        Primitive::Triangle { mesh, triangle } => scene.meshes[mesh].triangle_area(triangle),
+        Primitive::Instance(_) => 0.0,
+    }
+}
+
+// Shapes and triangles only emit from their outward facing side
+fn sample_area(scene: &Scene, primitive_index: usize, time: f32, position: Vector3<f32>, sampler: &mut dyn Sampler) -> Option<LightSample> {
+    let surface = sample_point(scene, primitive_index, time, sampler.get_2d())?;
+
+    let to_light = surface.position - position;
+    let distance_squared = to_light.magnitude2();
+    let distance = distance_squared.sqrt();
+    let direction = to_light / distance;
+
+    let cos_light = -surface.normal.dot(direction);
+    if cos_light <= 0.0 {
+        return None;
+    }
+
+    let material = scene.materials[surface.material_index].resolve(&scene.textures, surface.uv, surface.position);
+
+    Some(LightSample {
+        direction,
+        distance,
+        primitive_index,
+        normal: surface.normal,
+        radiance: material.get_emission(),
+        pdf: area_pdf(area(scene, primitive_index, time), distance_squared, cos_light),
+    })
+}
+
+// Starts a path on a uniformly chosen light: a point uniform by area, leaving cosine weighted from the outward side
+pub fn sample_emission(scene: &Scene, time: f32, sampler: &mut dyn Sampler) -> Option<EmissionSample> {
+    if scene.lights.is_empty() {
+        return None;
+    }
+
+    let pick = ((sampler.get_1d() * scene.lights.len() as f32) as usize).min(scene.lights.len() - 1);
+    let primitive_index = scene.lights[pick];
+    let (u_position, u_direction) = (sampler.get_2d(), sampler.get_2d());
+
+    let surface = sample_point(scene, primitive_index, time, u_position)?;
+    let local = sample_cosine_hemisphere(u_direction);
+    let material = scene.materials[surface.material_index].resolve(&scene.textures, surface.uv, surface.position);
+
+    Some(EmissionSample {
+        position: surface.position,
+        normal: surface.normal,
+        direction: from_local(local, surface.normal),
+        primitive_index,
+        radiance: material.get_emission(),
+        position_pdf: 1.0 / (area(scene, primitive_index, time) * scene.lights.len() as f32),
+        direction_pdf: cosine_hemisphere_pdf(local.z),
+    })
+}
+
+// Area density `sample_emission` starts a path at a point on this primitive with, zero if it never does
+pub fn origin_pdf(scene: &Scene, time: f32, primitive_index: usize) -> f32 {
+    if !scene.lights.contains(&primitive_index) {
+        return 0.0;
+    }
+
+    1.0 / (area(scene, primitive_index, time) * scene.lights.len() as f32)
+}
+
+// Density `sample` would have produced for a direction from `position` that hits the given light at
+// `light_position`, where the light's outward normal is `light_normal`
+pub fn pdf(
+    scene: &Scene,
+    position: Vector3<f32>,
+    time: f32,
+    primitive_index: usize,
+    light_position: Vector3<f32>,
+    light_normal: Vector3<f32>,
+) -> f32 {
+    let pdf = match scene.primitives[primitive_index] {
+        Primitive::Sphere(sphere_index) => sphere_pdf(&scene.spheres[sphere_index].at_time(time), position),
+        Primitive::Object(_) | Primitive::Triangle { .. } => {
+            let to_light = light_position - position;
+            let distance_squared = to_light.magnitude2();
+            let cos_light = light_normal.dot(to_light).abs() / distance_squared.sqrt();
+            area_pdf(area(scene, primitive_index, time), distance_squared, cos_light)
+        }
+        Primitive::Instance(_) => 0.0,
+    };
+
+    pdf / scene.lights.len().max(1) as f32
+}
+
+fn sphere_pdf(sphere: &Sphere, position: Vector3<f32>) -> f32 {
+    let distance_squared = sphere.position.distance2(position);
+    let radius_squared = sphere.radius * sphere.radius;
+    if distance_squared <= radius_squared {
+        return 0.0;
+    }
+
+    let cos_theta_max = (1.0 - radius_squared / distance_squared).max(0.0).sqrt();
+
+    uniform_cone_pdf(cos_theta_max)
+}
+
+// Converts the uniform area density to solid angle
+fn area_pdf(area: f32, distance_squared: f32, cos_light: f32) -> f32 {
+    if cos_light <= 0.0 {
+        return 0.0;
+    }
+
+    distance_squared / (area * cos_light)
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::{vec3, Zero};
+
+    use super::*;
+    use crate::mesh::{Mesh, Triangle};
+    use crate::sampler::SamplerKind;
+    use crate::scene::{Material, Object};
+    use crate::shape::Quad;
+
+    // Around the origin: a sphere above, a square to the side and a triangle behind, all facing it
+    fn lit_scene() -> Scene {
+        let mut scene = Scene::default();
+        scene.materials.push(Material::lambertian(vec3(1.0, 1.0, 1.0)).emissive(1.0));
+        scene.spheres.push(Sphere { position: vec3(0.0, 3.0, 0.0), motion: Vector3::zero(), radius: 1.0, material_index: 0 });
+        scene.objects.push(Object {
+            shape: Box::new(Quad {
+                corner: vec3(2.0, -1.0, -1.0),
+                edge_u: vec3(0.0, 0.0, 2.0),
+                edge_v: vec3(0.0, 2.0, 0.0),
+            }),
+            material_index: 0,
+        });
+        scene.meshes.push(Mesh {
+            positions: vec![vec3(-1.0, -1.0, -2.0), vec3(1.0, -1.0, -2.0), vec3(0.0, 1.0, -2.0)],
+            triangles: vec![Triangle { positions: [0, 1, 2], normals: None, uvs: None, material_index: 0 }],
+            ..Mesh::default()
+        });
+        scene.rebuild();
+        scene
+    }
+
+    // Solid angle of a triangle seen from the origin (Van Oosterom and Strackee 1983)
+    fn triangle_solid_angle(a: Vector3<f32>, b: Vector3<f32>, c: Vector3<f32>) -> f32 {
+        let (la, lb, lc) = (a.magnitude(), b.magnitude(), c.magnitude());
+        let numerator = a.dot(b.cross(c)).abs();
+        let denominator = la * lb * lc + a.dot(b) * lc + a.dot(c) * lb + b.dot(c) * la;
+        2.0 * numerator.atan2(denominator)
+    }
+
+    #[test]
+    fn pdf_matches_the_density_of_samples() {
+        let scene = lit_scene();
+        assert_eq!(scene.lights.len(), 3);
+        let position = Vector3::zero();
+
+        let mut sampler = SamplerKind::Independent.create();
+        let count = 50_000;
+        // Summing one over the density estimates the solid angle each light covers
+        let mut solid_angles = vec![0.0; scene.primitives.len()];
+        for index in 0..count {
+            sampler.start_pixel_sample(0, 0, index);
+            let sample = sample(&scene, position, 0.0, sampler.as_mut()).unwrap();
+            let light_position = position + sample.direction * sample.distance;
+            let expected = pdf(&scene, position, 0.0, sample.primitive_index, light_position, sample.normal);
+            assert!((sample.pdf - expected).abs() <= 1e-3 * expected, "{} against {expected}", sample.pdf);
+            solid_angles[sample.primitive_index] += 1.0 / (sample.pdf * count as f32);
+        }
+
+        let quad = [vec3(2.0, -1.0, -1.0), vec3(2.0, -1.0, 1.0), vec3(2.0, 1.0, 1.0), vec3(2.0, 1.0, -1.0)];
+        for (primitive_index, primitive) in scene.primitives.iter().enumerate() {
+            let expected = match primitive {
+                Primitive::Sphere(_) => 2.0 * std::f32::consts::PI * (1.0 - (1.0f32 - 1.0 / 9.0).sqrt()),
+                Primitive::Object(_) => {
+                    triangle_solid_angle(quad[0], quad[1], quad[2]) + triangle_solid_angle(quad[0], quad[2], quad[3])
+                }
+                Primitive::Triangle { .. } => {
+                    let positions = &scene.meshes[0].positions;
+                    triangle_solid_angle(positions[0], positions[1], positions[2])
+                }
+                Primitive::Instance(_) => unreachable!(),
+            };
+            let measured = solid_angles[primitive_index];
+            assert!((measured / expected - 1.0).abs() < 0.02, "{measured} against {expected}");
+        }
+    }
+
+    #[test]
+    fn emission_starts_with_the_origin_density() {
+        let scene = lit_scene();
+        let mut sampler = SamplerKind::Independent.create();
+        let mut picked = vec![0; scene.primitives.len()];
+        for index in 0..3000 {
+            sampler.start_pixel_sample(0, 0, index);
+            let emission = sample_emission(&scene, 0.0, sampler.as_mut()).unwrap();
+            let origin = origin_pdf(&scene, 0.0, emission.primitive_index);
+            assert!((emission.position_pdf - origin).abs() <= 1e-5 * origin);
+            assert!(emission.direction.dot(emission.normal) >= 0.0);
+            assert!((emission.direction_pdf - emission.direction.dot(emission.normal) / std::f32::consts::PI).abs() < 1e-4);
+            picked[emission.primitive_index] += 1;
+        }
+        assert!(picked.iter().all(|&count| count > 900));
+    }
+}
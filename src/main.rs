@@ -1,28 +1,179 @@
-use std::time::Instant;
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{Duration, Instant};
 
 use minifb::{Key, Window, WindowOptions};
 
+use crate::aov::Aov;
 use crate::app::App;
+use crate::environment::Environment;
+use crate::integrator::Integrator;
+use crate::options::Options;
 
+mod aabb;
+mod aov;
 mod app;
+mod bdpt;
+mod bench;
+mod bsdf;
+mod bvh;
 mod camera;
+mod denoise;
+mod display;
+mod environment;
+mod filter;
+mod image;
+mod integrator;
+mod light;
+mod medium;
+mod mesh;
+mod obj;
+mod options;
 mod renderer;
+mod sampler;
 mod scene;
+mod shape;
+mod simd;
+mod spectrum;
 mod ray;
 mod text;
+mod texture;
+mod tile;
 mod utils;
 
 const WIDTH: usize = 712;
 const HEIGHT: usize = 400;
+const BENCH_RAYS: usize = 1_000_000;
 
 fn main() {
-    let mut app = App::new(WIDTH, HEIGHT);
-    let mut buffer: Vec<u32> = vec![0; WIDTH * HEIGHT];
+    let options = Options::new(WIDTH, HEIGHT)
+        .parse(std::env::args().skip(1))
+        .unwrap_or_else(|e| {
+            eprintln!("error: {}", e);
+            process::exit(2);
+        });
+
+    if options.bench {
+        run_bench(options);
+    } else if options.headless {
+        run_headless(options);
+    } else {
+        run_window(options);
+    }
+}
+
+fn create_app(options: &Options) -> App {
+    let mut app = App::new(options);
+
+    for path in &options.meshes {
+        app.load_obj(path, options.mesh_instances).unwrap_or_else(|e| {
+            eprintln!("error: failed to load {}: {}", path.display(), e);
+            process::exit(1);
+        });
+    }
+    if app.has_emissive_instances() {
+        eprintln!("warning: emissive instances aren't sampled as lights, only paths hitting them pick up their light");
+    }
+
+    if let Some(path) = &options.environment {
+        let environment = Environment::load(
+            path,
+            options.environment_rotation.to_radians(),
+            options.environment_intensity,
+        )
+            .unwrap_or_else(|e| {
+                eprintln!("error: failed to load {}: {}", path.display(), e);
+                process::exit(1);
+            });
+        app.set_environment(environment);
+    }
+
+    app
+}
+
+fn run_headless(options: Options) {
+    let mut app = create_app(&options);
+    let mut buffer: Vec<u32> = vec![0; options.width * options.height];
+
+    let ts = Instant::now();
+    // Redrawn in place, so only worth it when someone is watching
+    let show_progress = io::stderr().is_terminal();
+    let frames = app.render_offline(options.samples, &mut buffer, |frames, progress| {
+        if show_progress {
+            eprint!("\rSample {}/{}, {:.0}% of the next", frames, options.samples, progress * 100.0);
+        }
+    });
+    if show_progress {
+        eprintln!();
+    }
+    eprintln!(
+        "Rendered {} samples in {:.2?}, average path length {:.2}",
+        frames,
+        ts.elapsed(),
+        app.get_stats().average_path_length()
+    );
+
+    let written = if image::is_float_image(&options.output) {
+        app.write_aov(Aov::Beauty, &options.output)
+    } else {
+        image::write_image(&options.output, options.width, options.height, &buffer)
+    };
+    written.unwrap_or_else(|e| {
+        eprintln!("error: failed to write {}: {}", options.output.display(), e);
+        process::exit(1);
+    });
+
+    for &aov in &options.aovs {
+        let path = aov_path(&options.output, aov);
+        app.write_aov(aov, &path).unwrap_or_else(|e| {
+            eprintln!("error: failed to write {}: {}", path.display(), e);
+            process::exit(1);
+        });
+    }
+}
+
+fn run_bench(options: Options) {
+    let mut app = create_app(&options);
+    let result = app.bench(BENCH_RAYS);
+
+    let report = |name: &str, time| {
+        eprintln!("{:<28} {:>8.2?} {:>8.2} Mrays/s", name, time, result.rays_per_second(time) / 1e6);
+    };
+    let speedup = |baseline: Duration| baseline.as_secs_f64() / result.wide.as_secs_f64();
+    eprintln!("{} rays, single threaded", result.rays);
+    report("linear scan", result.linear);
+    report("binary BVH, scalar", result.scalar);
+    report("4-wide BVH, sphere packets", result.wide);
+    eprintln!(
+        "{:.2}x faster than the binary BVH and {:.0}x than the linear scan, {} rays with a different closest hit",
+        speedup(result.scalar),
+        speedup(result.linear),
+        result.mismatches
+    );
+}
+
+// Next to the beauty output, with the pass name before the extension: render.png becomes render.depth.png
+fn aov_path(output: &Path, aov: Aov) -> PathBuf {
+    let stem = output.file_stem().and_then(|s| s.to_str()).unwrap_or("render");
+    let name = match output.extension().and_then(|e| e.to_str()) {
+        Some(extension) => format!("{}.{}.{}", stem, aov.name(), extension),
+        None => format!("{}.{}", stem, aov.name()),
+    };
+
+    output.with_file_name(name)
+}
+
+fn run_window(options: Options) {
+    let (width, height) = (options.width, options.height);
+
+    let mut app = create_app(&options);
+    let mut buffer: Vec<u32> = vec![0; width * height];
 
     let mut window = Window::new(
         "Test - ESC to exit",
-        WIDTH,
-        HEIGHT,
+        width,
+        height,
         WindowOptions::default(),
     )
         .unwrap_or_else(|e| {
@@ -40,10 +191,39 @@ fn main() {
 
         // We unwrap here as we want this code to exit if it fails. Real applications may want to handle this in a different way
         window
-            .update_with_buffer(&buffer, WIDTH, HEIGHT)
+            .update_with_buffer(&buffer, width, height)
             .unwrap();
         app.render(&mut buffer);
 
+        let display = app.get_display_transform();
+        let lens = app.get_lens();
+        window.set_title(&format!(
+            "Test - ESC to exit - {} ms - average path length {:.2} - {} {:+.1} EV - aperture {:.2} focus {:.2} - {} filter - {} sampler{}{}{}{}{}{}",
+            app.get_last_render_time().as_millis(),
+            app.get_stats().average_path_length(),
+            display.tone_mapper.name(),
+            display.exposure,
+            lens.aperture_radius,
+            lens.focus_distance,
+            app.get_filter().name(),
+            app.get_sampler().name(),
+            match app.get_integrator() {
+                Integrator::Path => "",
+                Integrator::Bidirectional => " - bidirectional",
+            },
+            if app.get_spectral() { " - spectral" } else { "" },
+            if app.get_denoise() { " - denoised" } else { "" },
+            if app.is_converged() { " - converged" } else { "" },
+            match app.get_frame_progress() {
+                0.0 => String::new(),
+                progress => format!(" - {} tiles {:.0}%", app.get_tile_order().name(), progress * 100.0),
+            },
+            match app.get_aov() {
+                Aov::Beauty => String::new(),
+                aov => format!(" - {} pass", aov.name()),
+            }
+        ));
+
         ts = Instant::now();
     }
 }
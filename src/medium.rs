@@ -0,0 +1,172 @@
+use std::f32::consts::PI;
+
+use cgmath::{vec3, InnerSpace, Vector2, Vector3, Zero};
+
+use crate::utils::{from_local, sample_uniform_sphere, uniform_sphere_pdf};
+
+// A homogeneous participating medium, coefficients are per unit distance and per color channel
+#[derive(Clone, Copy)]
+pub struct Medium {
+    pub absorption: Vector3<f32>,
+    pub scattering: Vector3<f32>,
+    // Henyey-Greenstein asymmetry, positive values scatter forwards
+    pub anisotropy: f32,
+}
+
+// What happens at a tentative collision found by delta tracking
+pub enum Collision {
+    Absorbed,
+    Scattered,
+    // Fictitious collision with the part of the majorant the medium doesn't have
+    Null,
+}
+
+impl Medium {
+    pub fn extinction(&self) -> Vector3<f32> {
+        self.absorption + self.scattering
+    }
+
+    // The largest extinction over the channels, tentative collisions are sampled with it
+    pub fn majorant(&self) -> f32 {
+        let extinction = self.extinction();
+        extinction.x.max(extinction.y).max(extinction.z)
+    }
+
+    pub fn transmittance(&self, distance: f32) -> Vector3<f32> {
+        (-self.extinction() * distance).map(f32::exp)
+    }
+
+    // Picks the kind of a tentative collision with probabilities from the channel averages and returns
+    // the weight that makes each outcome unbiased for every channel
+    pub fn collide(&self, u: f32) -> (Collision, Vector3<f32>) {
+        let majorant = self.majorant();
+        let null = Vector3::new(majorant, majorant, majorant) - self.extinction();
+
+        let null_probability = average(null) / majorant;
+        let scatter_probability = average(self.scattering) / majorant;
+
+        if u < null_probability {
+            (Collision::Null, null / (majorant * null_probability))
+        } else if u < 1.0 - scatter_probability || scatter_probability <= 0.0 {
+            (Collision::Absorbed, Vector3::zero())
+        } else {
+            (Collision::Scattered, self.scattering / (majorant * scatter_probability))
+        }
+    }
+
+    // Phase function value for light travelling along `incoming` and leaving along `outgoing`
+    pub fn phase(&self, incoming: Vector3<f32>, outgoing: Vector3<f32>) -> f32 {
+        henyey_greenstein(incoming.dot(outgoing), self.anisotropy)
+    }
+
+    // Importance samples the phase function, which makes the weight exactly one
+    pub fn sample_phase(&self, incoming: Vector3<f32>, u: Vector2<f32>) -> (Vector3<f32>, f32) {
+        let g = self.anisotropy;
+        if g.abs() < 1e-3 {
+            return (sample_uniform_sphere(u), uniform_sphere_pdf());
+        }
+
+        let s = (1.0 - g * g) / (1.0 - g + 2.0 * g * u.x);
+        let cos_theta = ((1.0 + g * g - s * s) / (2.0 * g)).clamp(-1.0, 1.0);
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * PI * u.y;
+
+        let direction = from_local(vec3(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta), incoming);
+        (direction, henyey_greenstein(cos_theta, g))
+    }
+}
+
+fn henyey_greenstein(cos_theta: f32, g: f32) -> f32 {
+    let denominator = 1.0 + g * g - 2.0 * g * cos_theta;
+    (1.0 - g * g) / (4.0 * PI * denominator * denominator.max(1e-8).sqrt())
+}
+
+fn average(v: Vector3<f32>) -> f32 {
+    (v.x + v.y + v.z) / 3.0
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::ElementWise;
+
+    use super::*;
+    use crate::renderer::{delta_track, FreeFlight};
+    use crate::sampler::SamplerKind;
+
+    const ANISOTROPIES: [f32; 6] = [-0.9, -0.5, 0.0, 0.3, 0.7, 0.95];
+
+    #[test]
+    fn henyey_greenstein_integrates_to_one() {
+        let steps = 100_000;
+        for g in ANISOTROPIES {
+            // Over the sphere, as a function of the cosine alone
+            let integral: f32 = (0..steps)
+                .map(|i| {
+                    let cos_theta = -1.0 + 2.0 * (i as f32 + 0.5) / steps as f32;
+                    2.0 * PI * henyey_greenstein(cos_theta, g) * 2.0 / steps as f32
+                })
+                .sum();
+            assert!((integral - 1.0).abs() < 1e-3, "{integral} for {g}");
+        }
+    }
+
+    #[test]
+    fn phase_samples_match_their_density() {
+        let incoming = vec3(0.0, 0.6, 0.8);
+        let mut sampler = SamplerKind::Independent.create();
+        let count = 50_000;
+        for g in ANISOTROPIES {
+            let medium = Medium { absorption: Vector3::zero(), scattering: vec3(1.0, 1.0, 1.0), anisotropy: g };
+            let mut mean_cosine = 0.0;
+            for index in 0..count {
+                sampler.start_pixel_sample(0, 0, index);
+                let (direction, pdf) = medium.sample_phase(incoming, sampler.get_2d());
+                let phase = medium.phase(incoming, direction);
+                assert!((pdf - phase).abs() <= 1e-3 * phase, "{pdf} against {phase} for {g}");
+                mean_cosine += incoming.dot(direction) / count as f32;
+            }
+            // The anisotropy is the mean cosine of the scattering angle
+            assert!((mean_cosine - g).abs() < 0.01, "{mean_cosine} for {g}");
+        }
+    }
+
+    #[test]
+    fn free_flights_follow_the_transmittance() {
+        let mut sampler = SamplerKind::Independent.create();
+        let count = 50_000;
+
+        // Collisions in a gray medium are exponentially distributed
+        let medium = Medium { absorption: Vector3::zero(), scattering: vec3(0.7, 0.7, 0.7), anisotropy: 0.0 };
+        let thresholds = [0.25, 1.0, 2.0, 4.0];
+        let mut below = [0.0; 4];
+        for index in 0..count {
+            sampler.start_pixel_sample(0, 0, index);
+            let mut contribution = vec3(1.0, 1.0, 1.0);
+            let FreeFlight::Scattered(distance) = delta_track(&medium, f32::MAX, &mut contribution, sampler.as_mut()) else {
+                panic!("an unbounded segment of a purely scattering medium always scatters");
+            };
+            for (threshold, below) in thresholds.iter().zip(&mut below) {
+                if distance < *threshold {
+                    *below += 1.0 / count as f32;
+                }
+            }
+        }
+        for (threshold, below) in thresholds.iter().zip(below) {
+            let expected = 1.0 - medium.transmittance(*threshold).x;
+            assert!((below - expected).abs() < 0.01, "{below} against {expected} within {threshold}");
+        }
+
+        // In a chromatic one, what gets through the segment on average is the transmittance of each channel
+        let medium = Medium { absorption: vec3(0.1, 0.5, 1.0), scattering: vec3(0.3, 0.3, 0.3), anisotropy: 0.0 };
+        let mut transmitted = Vector3::zero();
+        for index in 0..count {
+            sampler.start_pixel_sample(0, 0, index);
+            let mut contribution = vec3(1.0, 1.0, 1.0);
+            if let FreeFlight::Reached = delta_track(&medium, 1.5, &mut contribution, sampler.as_mut()) {
+                transmitted += contribution / count as f32;
+            }
+        }
+        let ratio = transmitted.div_element_wise(medium.transmittance(1.5));
+        assert!((ratio - vec3(1.0, 1.0, 1.0)).magnitude() < 0.03, "{transmitted:?}");
+    }
+}
@@ -0,0 +1,149 @@
+use cgmath::{vec2, InnerSpace, Vector2, Vector3, Zero};
+
+use crate::aabb::Aabb;
+use crate::ray::Ray;
+
+// Indices into the vertex buffers of the owning mesh
+pub struct Triangle {
+    pub positions: [usize; 3],
+    pub normals: Option<[usize; 3]>,
+    pub uvs: Option<[usize; 3]>,
+    pub material_index: usize,
+}
+
+#[derive(Default)]
+pub struct Mesh {
+    pub positions: Vec<Vector3<f32>>,
+    pub normals: Vec<Vector3<f32>>,
+    pub uvs: Vec<Vector2<f32>>,
+    pub triangles: Vec<Triangle>,
+}
+
+pub struct TriangleHit {
+    pub distance: f32,
+    // Barycentric weights of the second and third vertex
+    pub b1: f32,
+    pub b2: f32,
+}
+
+pub struct SurfacePoint {
+    // Outward facing, following the winding order
+    pub geometric_normal: Vector3<f32>,
+    // Interpolated vertex normal, or the geometric normal without vertex normals
+    pub shading_normal: Vector3<f32>,
+    pub uv: Vector2<f32>,
+}
+
+impl Mesh {
+    pub fn triangle_bounds(&self, index: usize) -> Aabb {
+        let [a, b, c] = self.vertices(index);
+        Aabb::new(a, b).grow(c)
+    }
+
+    fn vertices(&self, index: usize) -> [Vector3<f32>; 3] {
+        self.triangles[index].positions.map(|i| self.positions[i])
+    }
+
+    pub fn triangle_area(&self, index: usize) -> f32 {
+        let [a, b, c] = self.vertices(index);
+        (b - a).cross(c - a).magnitude() / 2.0
+    }
+
+    pub fn position(&self, index: usize, b1: f32, b2: f32) -> Vector3<f32> {
+        let [a, b, c] = self.vertices(index);
+        a * (1.0 - b1 - b2) + b * b1 + c * b2
+    }
+
+    // Watertight ray/triangle intersection (Woop, Benthin and Wald 2013), so rays can't slip through shared edges
+    pub fn intersect(&self, index: usize, ray: &Ray, t_max: f32) -> Option<TriangleHit> {
+        let d = ray.direction;
+
+        // Permute axes so the largest direction component becomes z
+        let kz = if d.x.abs() > d.y.abs() {
+            if d.x.abs() > d.z.abs() { 0 } else { 2 }
+        } else if d.y.abs() > d.z.abs() {
+            1
+        } else {
+            2
+        };
+        let (mut kx, mut ky) = ((kz + 1) % 3, (kz + 2) % 3);
+        if d[kz] < 0.0 {
+            std::mem::swap(&mut kx, &mut ky);
+        }
+
+        let sz = 1.0 / d[kz];
+        let sx = d[kx] * sz;
+        let sy = d[ky] * sz;
+
+        let [a, b, c] = self.vertices(index).map(|v| v - ray.origin);
+
+        let ax = a[kx] - sx * a[kz];
+        let ay = a[ky] - sy * a[kz];
+        let bx = b[kx] - sx * b[kz];
+        let by = b[ky] - sy * b[kz];
+        let cx = c[kx] - sx * c[kz];
+        let cy = c[ky] - sy * c[kz];
+
+        let mut u = cx * by - cy * bx;
+        let mut v = ax * cy - ay * cx;
+        let mut w = bx * ay - by * ax;
+
+        // Fall back to double precision on edges so neighbouring triangles agree
+        if u == 0.0 || v == 0.0 || w == 0.0 {
+            u = (cx as f64 * by as f64 - cy as f64 * bx as f64) as f32;
+            v = (ax as f64 * cy as f64 - ay as f64 * cx as f64) as f32;
+            w = (bx as f64 * ay as f64 - by as f64 * ax as f64) as f32;
+        }
+
+        if (u < 0.0 || v < 0.0 || w < 0.0) && (u > 0.0 || v > 0.0 || w > 0.0) {
+            return None;
+        }
+
+        let det = u + v + w;
+        if det == 0.0 {
+            return None;
+        }
+
+        let az = sz * a[kz];
+        let bz = sz * b[kz];
+        let cz = sz * c[kz];
+        let t = (u * az + v * bz + w * cz) / det;
+
+        if t <= 0.0 || t >= t_max {
+            return None;
+        }
+
+        Some(TriangleHit {
+            distance: t,
+            b1: v / det,
+            b2: w / det,
+        })
+    }
+
+    pub fn surface(&self, index: usize, b1: f32, b2: f32) -> SurfacePoint {
+        let triangle = &self.triangles[index];
+        let [a, b, c] = self.vertices(index);
+        let b0 = 1.0 - b1 - b2;
+
+        let geometric_normal = (b - a).cross(c - a).normalize();
+
+        let shading_normal = match triangle.normals {
+            Some([n0, n1, n2]) => {
+                let n = self.normals[n0] * b0 + self.normals[n1] * b1 + self.normals[n2] * b2;
+                if n.is_zero() { geometric_normal } else { n.normalize() }
+            }
+            None => geometric_normal,
+        };
+
+        let uv = match triangle.uvs {
+            Some([t0, t1, t2]) => self.uvs[t0] * b0 + self.uvs[t1] * b1 + self.uvs[t2] * b2,
+            None => vec2(b1, b2),
+        };
+
+        SurfacePoint {
+            geometric_normal,
+            shading_normal,
+            uv,
+        }
+    }
+}
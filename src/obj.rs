@@ -0,0 +1,347 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use cgmath::{vec2, vec3, Vector3};
+
+use crate::mesh::{Mesh, Triangle};
+use crate::scene::Material;
+use crate::texture::Texture;
+
+const DEFAULT_MATERIAL: Material = Material::lambertian(vec3(0.8, 0.8, 0.8));
+
+fn invalid_data(line_number: usize, message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", line_number, message))
+}
+
+// Loads a Wavefront OBJ file, appending the materials of its MTL libraries and their textures to
+// `materials` and `textures`
+pub fn load_obj(path: &Path, materials: &mut Vec<Material>, textures: &mut Vec<Texture>) -> io::Result<Mesh> {
+    let source = fs::read_to_string(path)?;
+    let directory = path.parent().unwrap_or(Path::new("."));
+
+    let mut mesh = Mesh::default();
+    let mut material_indices: HashMap<String, usize> = HashMap::new();
+    let mut current_material = None;
+
+    for (line_number, line) in source.lines().enumerate().map(|(i, l)| (i + 1, l)) {
+        let mut tokens = line.split_whitespace();
+        let Some(keyword) = tokens.next() else {
+            continue;
+        };
+        let arguments: Vec<&str> = tokens.collect();
+
+        match keyword {
+            "v" => mesh.positions.push(parse_vector(&arguments, line_number)?),
+            "vn" => mesh.normals.push(parse_vector(&arguments, line_number)?),
+            "vt" => {
+                let u = parse_float(arguments.first(), line_number)?;
+                let v = arguments.get(1).map_or(Ok(0.0), |v| parse_float(Some(v), line_number))?;
+                mesh.uvs.push(vec2(u, v));
+            }
+            "f" => {
+                let material_index = *current_material.get_or_insert_with(|| {
+                    materials.push(DEFAULT_MATERIAL);
+                    materials.len() - 1
+                });
+                add_face(&mut mesh, &arguments, material_index, line_number)?;
+            }
+            "mtllib" => {
+                for library in &arguments {
+                    let library_materials = load_mtl(&directory.join(library), textures)?;
+                    for (name, material) in library_materials {
+                        materials.push(material);
+                        material_indices.insert(name, materials.len() - 1);
+                    }
+                }
+            }
+            "usemtl" => {
+                let name = arguments.first().copied().unwrap_or_default();
+                current_material = material_indices.get(name).copied();
+            }
+            _ => {}
+        }
+    }
+
+    Ok(mesh)
+}
+
+// Triangulates a polygon as a fan around its first vertex
+fn add_face(mesh: &mut Mesh, arguments: &[&str], material_index: usize, line_number: usize) -> io::Result<()> {
+    if arguments.len() < 3 {
+        return Err(invalid_data(line_number, "face with less than three vertices"));
+    }
+
+    let mut vertices = Vec::with_capacity(arguments.len());
+    for vertex in arguments {
+        let mut indices = vertex.split('/');
+        let position = resolve_index(indices.next(), mesh.positions.len(), line_number)?
+            .ok_or_else(|| invalid_data(line_number, "face vertex without position"))?;
+        let uv = resolve_index(indices.next(), mesh.uvs.len(), line_number)?;
+        let normal = resolve_index(indices.next(), mesh.normals.len(), line_number)?;
+        vertices.push((position, uv, normal));
+    }
+
+    for i in 1..vertices.len() - 1 {
+        let corners = [vertices[0], vertices[i], vertices[i + 1]];
+
+        let uvs = corners.iter().map(|c| c.1).collect::<Option<Vec<_>>>();
+        let normals = corners.iter().map(|c| c.2).collect::<Option<Vec<_>>>();
+
+        mesh.triangles.push(Triangle {
+            positions: corners.map(|c| c.0),
+            normals: normals.map(|n| [n[0], n[1], n[2]]),
+            uvs: uvs.map(|t| [t[0], t[1], t[2]]),
+            material_index,
+        });
+    }
+
+    Ok(())
+}
+
+// OBJ indices are 1-based, negative ones count back from the last element
+fn resolve_index(token: Option<&str>, count: usize, line_number: usize) -> io::Result<Option<usize>> {
+    let Some(token) = token.filter(|t| !t.is_empty()) else {
+        return Ok(None);
+    };
+
+    let index: i64 = token
+        .parse()
+        .map_err(|_| invalid_data(line_number, "invalid index"))?;
+
+    let resolved = if index < 0 { count as i64 + index } else { index - 1 };
+    if resolved < 0 || resolved >= count as i64 {
+        return Err(invalid_data(line_number, "index out of range"));
+    }
+
+    Ok(Some(resolved as usize))
+}
+
+fn parse_float(token: Option<&&str>, line_number: usize) -> io::Result<f32> {
+    token
+        .and_then(|t| t.parse().ok())
+        .ok_or_else(|| invalid_data(line_number, "expected a number"))
+}
+
+fn parse_vector(arguments: &[&str], line_number: usize) -> io::Result<Vector3<f32>> {
+    Ok(vec3(
+        parse_float(arguments.first(), line_number)?,
+        parse_float(arguments.get(1), line_number)?,
+        parse_float(arguments.get(2), line_number)?,
+    ))
+}
+
+#[derive(Default)]
+struct MtlMaterial {
+    diffuse: Option<Vector3<f32>>,
+    specular: Option<Vector3<f32>>,
+    emission: Option<Vector3<f32>>,
+    shininess: Option<f32>,
+    ior: Option<f32>,
+    dissolve: Option<f32>,
+    illumination: Option<u32>,
+    roughness: Option<f32>,
+    metallic: Option<f32>,
+
+    // Indices into the scene's textures
+    diffuse_map: Option<usize>,
+    emission_map: Option<usize>,
+    roughness_map: Option<usize>,
+}
+
+fn load_mtl(path: &Path, textures: &mut Vec<Texture>) -> io::Result<Vec<(String, Material)>> {
+    let source = fs::read_to_string(path)?;
+    let directory = path.parent().unwrap_or(Path::new("."));
+
+    // Materials of one library often share their maps
+    let mut loaded: HashMap<String, usize> = HashMap::new();
+    let mut load_map = |arguments: &[&str], srgb: bool, line_number: usize| -> io::Result<usize> {
+        // Map options such as `-s` or `-bm` come first, the file name is last
+        let name = arguments
+            .last()
+            .ok_or_else(|| invalid_data(line_number, "missing texture file name"))?;
+
+        if let Some(&index) = loaded.get(*name) {
+            return Ok(index);
+        }
+
+        let texture = Texture::load_image(&directory.join(name), srgb)
+            .map_err(|e| invalid_data(line_number, &format!("failed to load {}: {}", name, e)))?;
+        textures.push(texture);
+        loaded.insert(name.to_string(), textures.len() - 1);
+
+        Ok(textures.len() - 1)
+    };
+
+    let mut materials: Vec<(String, MtlMaterial)> = Vec::new();
+
+    for (line_number, line) in source.lines().enumerate().map(|(i, l)| (i + 1, l)) {
+        let mut tokens = line.split_whitespace();
+        let Some(keyword) = tokens.next() else {
+            continue;
+        };
+        let arguments: Vec<&str> = tokens.collect();
+
+        if keyword == "newmtl" {
+            materials.push((arguments.join(" "), MtlMaterial::default()));
+            continue;
+        }
+
+        let Some((_, material)) = materials.last_mut() else {
+            continue;
+        };
+
+        match keyword {
+            "Kd" => material.diffuse = Some(parse_vector(&arguments, line_number)?),
+            "Ks" => material.specular = Some(parse_vector(&arguments, line_number)?),
+            "Ke" => material.emission = Some(parse_vector(&arguments, line_number)?),
+            "Ns" => material.shininess = Some(parse_float(arguments.first(), line_number)?),
+            "Ni" => material.ior = Some(parse_float(arguments.first(), line_number)?),
+            "d" => material.dissolve = Some(parse_float(arguments.first(), line_number)?),
+            "Tr" => material.dissolve = Some(1.0 - parse_float(arguments.first(), line_number)?),
+            "Pr" => material.roughness = Some(parse_float(arguments.first(), line_number)?),
+            "Pm" => material.metallic = Some(parse_float(arguments.first(), line_number)?),
+            "map_Kd" => material.diffuse_map = Some(load_map(&arguments, true, line_number)?),
+            "map_Ke" => material.emission_map = Some(load_map(&arguments, true, line_number)?),
+            "map_Pr" => material.roughness_map = Some(load_map(&arguments, false, line_number)?),
+            "illum" => {
+                material.illumination = Some(
+                    arguments
+                        .first()
+                        .and_then(|t| t.parse().ok())
+                        .ok_or_else(|| invalid_data(line_number, "expected an illumination model"))?,
+                )
+            }
+            _ => {}
+        }
+    }
+
+    Ok(materials
+        .into_iter()
+        .map(|(name, material)| (name, convert_material(&material)))
+        .collect())
+}
+
+fn max_component(v: Vector3<f32>) -> f32 {
+    v.x.max(v.y).max(v.z)
+}
+
+// Maps the Phong style MTL parameters onto the closest physically based material. Maps multiply the
+// matching constant, which defaults to one when only the map is given
+fn convert_material(mtl: &MtlMaterial) -> Material {
+    let white = vec3(1.0, 1.0, 1.0);
+    let diffuse = mtl
+        .diffuse
+        .unwrap_or(if mtl.diffuse_map.is_some() { white } else { DEFAULT_MATERIAL.albedo });
+    let specular = mtl.specular.unwrap_or(vec3(0.0, 0.0, 0.0));
+    // Blinn-Phong exponent to an approximately equivalent GGX roughness
+    let roughness = match (mtl.roughness, mtl.roughness_map) {
+        (Some(roughness), _) => roughness,
+        (None, Some(_)) => 1.0,
+        (None, None) => (2.0 / (mtl.shininess.unwrap_or(0.0) + 2.0)).sqrt().sqrt(),
+    }
+    .clamp(0.0, 1.0);
+
+    let transparent = mtl.dissolve.is_some_and(|d| d < 1.0) || matches!(mtl.illumination, Some(4 | 6 | 7 | 9));
+
+    let mut material = if transparent {
+        Material::dielectric(diffuse, mtl.ior.unwrap_or(1.5))
+    } else if let Some(metallic) = mtl.metallic {
+        Material {
+            metallic: metallic.clamp(0.0, 1.0),
+            ..Material::metal(diffuse, roughness)
+        }
+    } else if max_component(specular) > max_component(diffuse) {
+        Material::metal(specular, roughness)
+    } else {
+        Material::lambertian(diffuse)
+    };
+
+    let emission = mtl
+        .emission
+        .or(mtl.emission_map.map(|_| white))
+        .filter(|e| max_component(*e) > 0.0);
+    if let Some(emission) = emission {
+        let power = max_component(emission);
+        material.emission_color = emission / power;
+        material.emission_power = power;
+    }
+
+    material.albedo_texture = mtl.diffuse_map;
+    material.roughness_texture = mtl.roughness_map;
+    material.emission_texture = mtl.emission_map;
+
+    material
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mesh(positions: usize, uvs: usize, normals: usize) -> Mesh {
+        Mesh {
+            positions: vec![vec3(0.0, 0.0, 0.0); positions],
+            normals: vec![vec3(0.0, 1.0, 0.0); normals],
+            uvs: vec![vec2(0.0, 0.0); uvs],
+            triangles: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn negative_indices_count_back_from_the_last_element() {
+        let mut mesh = mesh(5, 3, 2);
+        add_face(&mut mesh, &["-4/-3/-1", "-3/-2/-1", "-2/-1/-2", "-1/1/2"], 7, 1).unwrap();
+
+        assert_eq!(mesh.triangles.len(), 2);
+        assert_eq!(mesh.triangles[0].positions, [1, 2, 3]);
+        assert_eq!(mesh.triangles[0].uvs, Some([0, 1, 2]));
+        assert_eq!(mesh.triangles[0].normals, Some([1, 1, 0]));
+        assert_eq!(mesh.triangles[1].positions, [1, 3, 4]);
+        assert_eq!(mesh.triangles[1].uvs, Some([0, 2, 0]));
+        assert_eq!(mesh.triangles[1].normals, Some([1, 0, 1]));
+        assert!(mesh.triangles.iter().all(|t| t.material_index == 7));
+    }
+
+    #[test]
+    fn missing_attributes_are_dropped_per_triangle() {
+        let mut mesh = mesh(4, 1, 1);
+        add_face(&mut mesh, &["1//1", "2//1", "-1//-1"], 0, 1).unwrap();
+        add_face(&mut mesh, &["1/1", "2", "3/-1"], 0, 2).unwrap();
+
+        assert_eq!(mesh.triangles[0].positions, [0, 1, 3]);
+        assert!(mesh.triangles[0].uvs.is_none());
+        assert_eq!(mesh.triangles[0].normals, Some([0, 0, 0]));
+        assert!(mesh.triangles[1].uvs.is_none() && mesh.triangles[1].normals.is_none());
+    }
+
+    #[test]
+    fn rejects_invalid_indices() {
+        for face in [["0", "1", "2"], ["-4", "1", "2"], ["4", "1", "2"], ["1/2", "1", "2"], ["x", "1", "2"], ["/1", "1", "2"]] {
+            let mut mesh = mesh(3, 1, 1);
+            let error = add_face(&mut mesh, &face, 0, 5).err().unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+            assert!(error.to_string().starts_with("line 5:"));
+            assert!(mesh.triangles.is_empty());
+        }
+
+        let mut mesh = mesh(3, 0, 0);
+        assert!(add_face(&mut mesh, &["1", "2"], 0, 1).is_err());
+    }
+
+    #[test]
+    fn negative_indices_follow_the_vertices_read_so_far() {
+        let path = std::env::temp_dir().join(format!("rust-raytracer-{}.obj", std::process::id()));
+        let source = "v 0 0 0\nv 1 0 0\nv 0 1 0\nf -3 -2 -1\nv 1 1 0\nf -3 -1 -2\nf 1 2 -1\n";
+        fs::write(&path, source).unwrap();
+
+        let (mut materials, mut textures) = (Vec::new(), Vec::new());
+        let mesh = load_obj(&path, &mut materials, &mut textures);
+        fs::remove_file(&path).unwrap();
+        let mesh = mesh.unwrap();
+
+        let positions: Vec<[usize; 3]> = mesh.triangles.iter().map(|t| t.positions).collect();
+        assert_eq!(positions, [[0, 1, 2], [1, 3, 2], [0, 1, 3]]);
+        assert_eq!(materials.len(), 1);
+    }
+}
@@ -0,0 +1,212 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::aov::Aov;
+use crate::app::SceneVariant;
+use crate::display::ToneMapper;
+use crate::filter::Filter;
+use crate::integrator::Integrator;
+use crate::sampler::SamplerKind;
+use crate::tile::TileOrder;
+
+pub struct Options {
+    pub width: usize,
+    pub height: usize,
+    pub scene: SceneVariant,
+    pub max_depth: u32,
+    pub exposure: f32,
+    pub tone_mapper: ToneMapper,
+    pub filter: Filter,
+    pub denoise: bool,
+    pub sampler: SamplerKind,
+    // Error at which pixels stop being sampled, zero disables adaptive sampling
+    pub noise_target: f32,
+    pub spectral: bool,
+    pub integrator: Integrator,
+    pub tile_order: TileOrder,
+
+    pub aperture_radius: f32,
+    // Scenes pick their own focus distance unless given
+    pub focus_distance: Option<f32>,
+    pub aperture_blades: u32,
+    // Open from time zero to this, spheres move over [0, 1]
+    pub shutter: f32,
+
+    // Scattering coefficient of a fog filling the whole scene, none when zero
+    pub fog: f32,
+    pub fog_anisotropy: f32,
+
+    pub meshes: Vec<PathBuf>,
+    // Scatters this many instances of each mesh instead of placing it once
+    pub mesh_instances: usize,
+    pub environment: Option<PathBuf>,
+    // In degrees around the up axis
+    pub environment_rotation: f32,
+    pub environment_intensity: f32,
+
+    pub headless: bool,
+    // Times ray traversal instead of rendering
+    pub bench: bool,
+    pub samples: usize,
+    pub output: PathBuf,
+    // Extra passes written next to the output
+    pub aovs: Vec<Aov>,
+}
+
+impl Options {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            scene: SceneVariant::Rtiaw,
+            max_depth: 16,
+            exposure: 0.0,
+            tone_mapper: ToneMapper::AcesFilmic,
+            filter: Filter::Box,
+            denoise: false,
+            sampler: SamplerKind::Sobol,
+            noise_target: 0.0,
+            spectral: false,
+            integrator: Integrator::Path,
+            tile_order: TileOrder::Hilbert,
+            aperture_radius: 0.0,
+            focus_distance: None,
+            aperture_blades: 0,
+            shutter: 0.0,
+            fog: 0.0,
+            fog_anisotropy: 0.5,
+            meshes: Vec::new(),
+            mesh_instances: 0,
+            environment: None,
+            environment_rotation: 0.0,
+            environment_intensity: 1.0,
+            headless: false,
+            bench: false,
+            samples: 64,
+            output: PathBuf::from("render.png"),
+            aovs: Vec::new(),
+        }
+    }
+
+    pub fn parse(mut self, args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--headless" => self.headless = true,
+                "--bench" => self.bench = true,
+                "--samples" => self.samples = parse_value(&arg, args.next())?,
+                "--output" => self.output = parse_value(&arg, args.next())?,
+                "--aov" => self.aovs.push(parse_value(&arg, args.next())?),
+                "--width" => self.width = parse_value(&arg, args.next())?,
+                "--height" => self.height = parse_value(&arg, args.next())?,
+                "--scene" => self.scene = parse_value(&arg, args.next())?,
+                "--max-depth" => self.max_depth = parse_value(&arg, args.next())?,
+                "--exposure" => self.exposure = parse_value(&arg, args.next())?,
+                "--tonemap" => self.tone_mapper = parse_value(&arg, args.next())?,
+                "--filter" => self.filter = parse_value(&arg, args.next())?,
+                "--denoise" => self.denoise = true,
+                "--sampler" => self.sampler = parse_value(&arg, args.next())?,
+                "--noise-target" => self.noise_target = parse_value(&arg, args.next())?,
+                "--spectral" => self.spectral = true,
+                "--integrator" => self.integrator = parse_value(&arg, args.next())?,
+                "--tile-order" => self.tile_order = parse_value(&arg, args.next())?,
+                "--aperture" => self.aperture_radius = parse_value(&arg, args.next())?,
+                "--focus-distance" => self.focus_distance = Some(parse_value(&arg, args.next())?),
+                "--aperture-blades" => self.aperture_blades = parse_value(&arg, args.next())?,
+                "--shutter" => self.shutter = parse_value(&arg, args.next())?,
+                "--fog" => self.fog = parse_value(&arg, args.next())?,
+                "--fog-anisotropy" => self.fog_anisotropy = parse_value(&arg, args.next())?,
+                "--obj" => self.meshes.push(parse_value(&arg, args.next())?),
+                "--obj-instances" => self.mesh_instances = parse_value(&arg, args.next())?,
+                "--environment" => self.environment = Some(parse_value(&arg, args.next())?),
+                "--environment-rotation" => self.environment_rotation = parse_value(&arg, args.next())?,
+                "--environment-intensity" => self.environment_intensity = parse_value(&arg, args.next())?,
+                _ => return Err(format!("unknown argument '{}'", arg)),
+            }
+        }
+
+        if self.width == 0 || self.height == 0 {
+            return Err("width and height must be greater than zero".to_string());
+        }
+
+        if self.samples == 0 {
+            return Err("samples must be greater than zero".to_string());
+        }
+
+        if self.max_depth == 0 {
+            return Err("max depth must be greater than zero".to_string());
+        }
+
+        if !(0.0..f32::INFINITY).contains(&self.environment_intensity) {
+            return Err("environment intensity must be a finite non-negative number".to_string());
+        }
+
+        if !(0.0..f32::INFINITY).contains(&self.aperture_radius) {
+            return Err("aperture must be a finite non-negative number".to_string());
+        }
+
+        if !(0.0..f32::INFINITY).contains(&self.shutter) {
+            return Err("shutter must be a finite non-negative number".to_string());
+        }
+
+        if !(0.0..).contains(&self.noise_target) {
+            return Err("noise target must not be negative".to_string());
+        }
+
+        if !(0.0..1.0).contains(&self.fog_anisotropy.abs()) {
+            return Err("fog anisotropy must be between -1 and 1".to_string());
+        }
+
+        // NaN is outside every range, and an infinite density would never let a ray leave its starting point
+        if !(0.0..f32::INFINITY).contains(&self.fog) {
+            return Err("fog density must be a finite non-negative number".to_string());
+        }
+
+        Ok(self)
+    }
+}
+
+fn parse_value<T: FromStr>(name: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("missing value for '{}'", name))?;
+
+    value
+        .parse()
+        .map_err(|_| format!("invalid value '{}' for '{}'", value, name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        Options::new(64, 64).parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn accepts_defaults_and_boundaries() {
+        assert!(parse(&[]).is_ok());
+        assert!(parse(&["--fog", "0", "--fog-anisotropy", "-0.99"]).is_ok());
+    }
+
+    #[test]
+    fn rejects_out_of_range_values() {
+        for args in [
+            ["--noise-target", "-0.01"],
+            ["--noise-target", "NaN"],
+            ["--fog", "-0.1"],
+            ["--fog", "NaN"],
+            ["--fog", "inf"],
+            ["--fog-anisotropy", "1"],
+            ["--fog-anisotropy", "NaN"],
+            ["--environment-intensity", "-1"],
+            ["--environment-intensity", "NaN"],
+            ["--aperture", "-0.1"],
+            ["--aperture", "NaN"],
+            ["--shutter", "-1"],
+            ["--shutter", "NaN"],
+        ] {
+            assert!(parse(&args).is_err(), "{:?} was accepted", args);
+        }
+    }
+}
@@ -2,5 +2,7 @@ use cgmath::Vector3;
 
 pub struct Ray {
     pub origin: Vector3<f32>,
-    pub direction: Vector3<f32>
+    pub direction: Vector3<f32>,
+    // Within the camera's shutter interval, moving objects are intersected where they are at this time
+    pub time: f32,
 }
@@ -1,31 +1,275 @@
-use cgmath::prelude::*;
-use cgmath::{vec3, ElementWise, InnerSpace, Vector3, Vector4, Zero};
-use rayon::prelude::*;
+use std::mem;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
+use cgmath::{vec2, vec3, ElementWise, InnerSpace, Vector2, Vector3, Vector4, Zero};
+
+use crate::aov::{Aov, AovPixel};
+use crate::bdpt;
+use crate::bsdf;
 use crate::camera::Camera;
+use crate::denoise;
+use crate::display::{self, DisplayTransform};
+use crate::filter::Filter;
+use crate::integrator::Integrator;
+use crate::light;
+use crate::medium::{Collision, Medium};
+use crate::mesh::Mesh;
 use crate::ray::Ray;
-use crate::scene::Scene;
-use crate::utils::{
-    pcg_vec3, random_in_unit_sphere, random_vector3, random_vector3_in_range, reflect,
-};
+use crate::sampler::{Sampler, SamplerKind};
+use crate::scene::{Hit, Material, Primitive, Prototype, Scene, SurfaceHit};
+use crate::shape::Shape;
+use crate::spectrum::{Channels, Dispersion};
+use crate::tile::{Tile, TileOrder, TILE_SIZE};
+use crate::utils::{luminance, power_heuristic};
+
+pub struct HitPayload {
+    pub hit_distance: f32,
+    pub world_position: Vector3<f32>,
+    // Both normals always face against the incoming ray, the shading one may be interpolated
+    pub world_normal: Vector3<f32>,
+    pub geometric_normal: Vector3<f32>,
+    pub front_face: bool,
+    pub uv: Vector2<f32>,
+    // Index into `Scene::primitives`
+    pub object_index: usize,
+    pub material_index: usize,
+}
+
+// One path traced through a pixel
+pub struct PixelSample {
+    pub film_position: Vector2<f32>,
+    pub color: Vector3<f32>,
+    pub path_length: u32,
+    pub aov: AovPixel,
+    // Radiance of light paths that reached the camera by themselves, with the film position each landed on
+    pub splats: Vec<(Vector2<f32>, Vector3<f32>)>,
+}
+
+// The pixels of the accumulation buffers a tile job owns for a frame, one slice per row, so tiles trace and write
+// in parallel without locking
+struct TileView<'a> {
+    tile: Tile,
+    accumulation: Vec<&'a mut [Vector4<f32>]>,
+    aov: Vec<&'a mut [AovPixel]>,
+}
+
+// What a worker keeps from tile to tile and frame to frame, so tiles don't allocate
+struct TileWorker {
+    sampler_kind: SamplerKind,
+    sampler: Box<dyn Sampler>,
+    // Filtered samples landing on pixels of other tiles, by pixel index, added once every worker is done
+    border_splats: Vec<(usize, Vector4<f32>)>,
+    // Anywhere on the film, so they are merged into the whole image
+    light_splats: Vec<(Vector2<f32>, Vector3<f32>)>,
+    light_paths: u64,
+    stats: RenderStats,
+}
+
+// Light paths splatted straight onto the film by the bidirectional integrator, unfiltered and apart from the
+// accumulation since they land anywhere regardless of which pixels were sampled
+#[derive(Default)]
+struct LightImage {
+    data: Vec<Vector3<f32>>,
+    paths: u64,
+}
+
+impl<'a> TileView<'a> {
+    // Splits the buffers along the tile grid and hands each of `tiles` its part, in the same order
+    fn split(
+        tiles: &[Tile],
+        width: usize,
+        accumulation: &'a mut [Vector4<f32>],
+        aov: &'a mut [AovPixel],
+    ) -> Vec<Self> {
+        let mut views: Vec<Self> = tiles
+            .iter()
+            .map(|&tile| TileView {
+                tile,
+                accumulation: Vec::with_capacity(tile.y1 - tile.y0),
+                aov: Vec::with_capacity(tile.y1 - tile.y0),
+            })
+            .collect();
+
+        if views.is_empty() {
+            return views;
+        }
+
+        // Tiles sit on a grid of `TILE_SIZE`, so every row splits into the same columns
+        let columns = width.div_ceil(TILE_SIZE);
+        let rows = (accumulation.len() / width).div_ceil(TILE_SIZE);
+        let mut grid = vec![None; columns * rows];
+        for (index, tile) in tiles.iter().enumerate() {
+            grid[tile.x0 / TILE_SIZE + tile.y0 / TILE_SIZE * columns] = Some(index);
+        }
+
+        for (y, (accumulation_row, aov_row)) in accumulation.chunks_mut(width).zip(aov.chunks_mut(width)).enumerate() {
+            let row_tiles = accumulation_row.chunks_mut(TILE_SIZE).zip(aov_row.chunks_mut(TILE_SIZE));
+            for (column, (accumulation, aov)) in row_tiles.enumerate() {
+                if let Some(index) = grid[column + y / TILE_SIZE * columns] {
+                    views[index].accumulation.push(accumulation);
+                    views[index].aov.push(aov);
+                }
+            }
+        }
+
+        views
+    }
+
+    // Adds a sample to every pixel whose filter footprint contains it, weighted by the filter. Pixels outside the
+    // tile are left to `border`
+    fn splat(
+        &mut self,
+        filter: Filter,
+        film_position: Vector2<f32>,
+        color: Vector3<f32>,
+        width: usize,
+        height: usize,
+        border: &mut Vec<(usize, Vector4<f32>)>,
+    ) {
+        let radius = filter.radius();
+        let tile = self.tile;
+
+        // Pixel centers are at half integer film coordinates
+        let x0 = (film_position.x - radius - 0.5).ceil().max(0.0) as usize;
+        let y0 = (film_position.y - radius - 0.5).ceil().max(0.0) as usize;
+        let x1 = ((film_position.x + radius - 0.5).floor() as usize).min(width - 1);
+        let y1 = ((film_position.y + radius - 0.5).floor() as usize).min(height - 1);
+
+        for y in y0..=y1 {
+            for x in x0..=x1 {
+                let weight = filter.evaluate(x as f32 + 0.5 - film_position.x, y as f32 + 0.5 - film_position.y);
+                if weight == 0.0 {
+                    continue;
+                }
+
+                let value = (color * weight).extend(weight);
+                if (tile.x0..tile.x1).contains(&x) && (tile.y0..tile.y1).contains(&y) {
+                    self.accumulation[y - tile.y0][x - tile.x0] += value;
+                } else {
+                    border.push((x + y * width, value));
+                }
+            }
+        }
+    }
+}
+
+impl TileWorker {
+    fn new(sampler_kind: SamplerKind) -> Self {
+        Self {
+            sampler_kind,
+            sampler: sampler_kind.create(),
+            border_splats: Vec::new(),
+            light_splats: Vec::new(),
+            light_paths: 0,
+            stats: RenderStats::default(),
+        }
+    }
+
+    fn set_sampler(&mut self, sampler_kind: SamplerKind) {
+        if sampler_kind != self.sampler_kind {
+            self.sampler_kind = sampler_kind;
+            self.sampler = sampler_kind.create();
+        }
+    }
+
+    // Adds what the tiles left outside of themselves, and empties the worker for the next call
+    fn merge_into(&mut self, width: usize, accumulation: &mut [Vector4<f32>], light: &mut LightImage, stats: &mut RenderStats) {
+        for (index, value) in self.border_splats.drain(..) {
+            accumulation[index] += value;
+        }
+
+        for (film_position, color) in self.light_splats.drain(..) {
+            light.data[film_position.x as usize + film_position.y as usize * width] += color;
+        }
+        light.paths += mem::take(&mut self.light_paths);
+
+        let worker_stats = mem::take(&mut self.stats);
+        stats.paths += worker_stats.paths;
+        stats.bounces += worker_stats.bounces;
+    }
+}
 
-struct HitPayload {
-    hit_distance: f32,
-    world_position: Vector3<f32>,
-    world_normal: Vector3<f32>,
-    object_index: usize,
+#[derive(Default, Clone, Copy)]
+pub struct RenderStats {
+    pub paths: u64,
+    pub bounces: u64,
+}
+
+impl RenderStats {
+    pub fn average_path_length(&self) -> f32 {
+        if self.paths == 0 {
+            return 0.0;
+        }
+
+        self.bounces as f32 / self.paths as f32
+    }
 }
 
 pub struct Renderer {
     frame_index: usize,
     accumulation_data: Vec<Vector4<f32>>,
+    // Summed per pixel rather than splatted, for the denoiser, the AOV passes and adaptive sampling, which also
+    // needs the second moment of the luminance alongside each accumulated color
+    aov_data: Vec<AovPixel>,
+    light_image: LightImage,
+    workers: Vec<TileWorker>,
+
+    // A frame is traced tile by tile and may be spread over several calls, `next_tile` is where it continues
+    tiles: Vec<Tile>,
+    tile_order: TileOrder,
+    next_tile: usize,
+
+    integrator: Integrator,
+    max_depth: u32,
+    // Paths are only terminated by Russian roulette from this depth on
+    russian_roulette_depth: u32,
+    stats: RenderStats,
+    // Of the frame still being traced
+    frame_stats: RenderStats,
+
+    display: DisplayTransform,
+    filter: Filter,
+    sampler: SamplerKind,
+    denoise: bool,
+    // Traces wavelengths instead of RGB, for dispersion
+    spectral: bool,
+    // Error below which a pixel stops being sampled, zero samples every pixel every frame
+    noise_target: f32,
+    // The pass written to the buffer by `render`
+    aov: Aov,
 }
 
+// Film position, time, lens position and wavelength
+pub const CAMERA_DIMENSIONS: u32 = 6;
+// Reserved for each path segment, so a dimension means the same decision whatever earlier segments consumed
+pub const SEGMENT_DIMENSIONS: u32 = 16;
+// Variance estimates from fewer samples are too unreliable to stop sampling on
+const MIN_ADAPTIVE_SAMPLES: u32 = 16;
+
 impl Default for Renderer {
     fn default() -> Self {
         Self {
             frame_index: 1,
             accumulation_data: Vec::new(),
+            aov_data: Vec::new(),
+            light_image: LightImage::default(),
+            workers: Vec::new(),
+            tiles: Vec::new(),
+            tile_order: TileOrder::Hilbert,
+            next_tile: 0,
+            integrator: Integrator::Path,
+            max_depth: 16,
+            russian_roulette_depth: 3,
+            stats: RenderStats::default(),
+            frame_stats: RenderStats::default(),
+            display: DisplayTransform::default(),
+            filter: Filter::Box,
+            sampler: SamplerKind::Sobol,
+            denoise: false,
+            spectral: false,
+            noise_target: 0.0,
+            aov: Aov::Beauty,
         }
     }
 }
@@ -33,166 +277,818 @@ impl Default for Renderer {
 impl Renderer {
     pub fn reset_frame_index(&mut self) {
         self.frame_index = 1;
+        self.next_tile = 0;
+    }
+
+    pub const fn get_max_depth(&self) -> u32 { self.max_depth }
+
+    pub fn set_max_depth(&mut self, max_depth: u32) {
+        self.max_depth = max_depth;
+    }
+
+    pub const fn get_integrator(&self) -> Integrator { self.integrator }
+
+    // Both converge to the same image, but the light image of one can't be continued by the other
+    pub fn set_integrator(&mut self, integrator: Integrator) {
+        if integrator != self.integrator {
+            self.integrator = integrator;
+            self.reset_frame_index();
+        }
+    }
+
+    pub const fn get_display_transform(&self) -> DisplayTransform { self.display }
+
+    // Only affects how accumulated radiance is shown, so it doesn't restart accumulation
+    pub fn set_display_transform(&mut self, display: DisplayTransform) {
+        self.display = display;
+    }
+
+    pub const fn get_filter(&self) -> Filter { self.filter }
+
+    // Samples already splatted with the old filter can't be separated, so accumulation restarts
+    pub fn set_filter(&mut self, filter: Filter) {
+        if filter != self.filter {
+            self.filter = filter;
+            self.reset_frame_index();
+        }
+    }
+
+    pub const fn get_sampler(&self) -> SamplerKind { self.sampler }
+
+    // Different sequences can't continue each other's samples, so accumulation restarts
+    pub fn set_sampler(&mut self, sampler: SamplerKind) {
+        if sampler != self.sampler {
+            self.sampler = sampler;
+            self.reset_frame_index();
+        }
+    }
+
+    pub const fn get_denoise(&self) -> bool { self.denoise }
+
+    // Only applied to what is displayed, the accumulated samples are left untouched
+    pub fn set_denoise(&mut self, denoise: bool) {
+        self.denoise = denoise;
+    }
+
+    // Pixels keep what they have accumulated, a lower target just samples some of them again
+    pub fn set_noise_target(&mut self, noise_target: f32) {
+        self.noise_target = noise_target;
+    }
+
+    // Whether every pixel is below the noise target, never without one
+    pub fn is_converged(&self) -> bool {
+        self.frame_index > 1 && self.next_tile == 0 && !self.aov_data.iter().any(|pixel| self.needs_samples(pixel))
+    }
+
+    pub const fn get_spectral(&self) -> bool { self.spectral }
+
+    // The two converge to slightly different images, so accumulation restarts
+    pub fn set_spectral(&mut self, spectral: bool) {
+        if spectral != self.spectral {
+            self.spectral = spectral;
+            self.reset_frame_index();
+        }
+    }
+
+    pub const fn get_aov(&self) -> Aov { self.aov }
+
+    // Every pass is accumulated all the time, so switching doesn't restart accumulation
+    pub fn set_aov(&mut self, aov: Aov) {
+        self.aov = aov;
+    }
+
+    pub const fn get_tile_order(&self) -> TileOrder { self.tile_order }
+
+    // Only changes where the next frame starts, the one in progress finishes in the old order
+    pub fn set_tile_order(&mut self, tile_order: TileOrder) {
+        self.tile_order = tile_order;
+    }
+
+    // Statistics of the last completed frame
+    pub const fn get_stats(&self) -> RenderStats { self.stats }
+
+    // Fraction of the tiles of the current frame that have been traced
+    pub fn get_frame_progress(&self) -> f32 {
+        self.next_tile as f32 / self.tiles.len().max(1) as f32
     }
 
     pub fn on_resize(&mut self, width: usize, height: usize) {
         self.accumulation_data
             .resize(width * height, Vector4::zero());
+        self.aov_data.resize(width * height, AovPixel::default());
+        self.light_image.data.resize(width * height, Vector3::zero());
     }
 
-    fn render_pixels_in_parallel(
-        &self,
-        scene: &Scene,
-        camera: &Camera,
-    ) -> Vec<(usize, usize, Vector4<f32>)> {
-        (0..camera.viewport_height)
-            .into_par_iter()
-            .flat_map_iter(|y| {
-                (0..camera.viewport_width).map(move |x| (x, y, self.per_pixel(scene, camera, x, y)))
-            })
-            .collect()
+    // Light paths land all over the film, so with them no pixel is ever done
+    fn needs_samples(&self, pixel: &AovPixel) -> bool {
+        self.noise_target <= 0.0
+            || self.integrator == Integrator::Bidirectional
+            || pixel.samples < MIN_ADAPTIVE_SAMPLES || pixel.display_error() > self.noise_target
     }
 
-    pub fn render(&mut self, scene: &Scene, camera: &Camera, buffer: &mut Vec<u32>) {
-        if self.frame_index == 1 {
-            self.accumulation_data.fill(Vector4::zero());
+    // Traces for as long as `budget` allows and shows whatever has been accumulated, finished tiles or not
+    pub fn render(&mut self, scene: &Scene, camera: &Camera, budget: Duration, buffer: &mut [u32]) {
+        self.accumulate(scene, camera, Some(Instant::now() + budget));
+        self.encode_aov(self.aov, camera.viewport_width, camera.viewport_height, buffer);
+    }
+
+    // Traces one more sample through every pixel that still needs one, or stops handing out tiles at `deadline`
+    // and continues the same frame on the next call. Returns whether a frame was completed
+    pub fn accumulate(&mut self, scene: &Scene, camera: &Camera, deadline: Option<Instant>) -> bool {
+        let (width, height) = (camera.viewport_width, camera.viewport_height);
+
+        if self.next_tile == 0 {
+            if self.frame_index == 1 {
+                self.accumulation_data.fill(Vector4::zero());
+                self.aov_data.fill(AovPixel::default());
+                self.light_image.data.fill(Vector3::zero());
+                self.light_image.paths = 0;
+            }
+            self.tiles = self.tile_order.tiles(width, height);
+            self.frame_stats = RenderStats::default();
+        }
+
+        // Every tile still to trace in this frame gets its own pixels, which it writes as soon as it traced them
+        let mut accumulation_data = mem::take(&mut self.accumulation_data);
+        let mut aov_data = mem::take(&mut self.aov_data);
+        let mut workers = mem::take(&mut self.workers);
+        workers.resize_with(rayon::current_num_threads(), || TileWorker::new(self.sampler));
+        for worker in &mut workers {
+            worker.set_sampler(self.sampler);
         }
 
-        let pixels = self.render_pixels_in_parallel(scene, camera);
+        let views = TileView::split(&self.tiles[self.next_tile..], width, &mut accumulation_data, &mut aov_data);
+        let remaining = views.len();
+        let queue = Mutex::new(views.into_iter());
+
+        // Every worker keeps taking the next tile in order, so tiles finish roughly in order whatever they cost
+        let renderer = &*self;
+        rayon::scope(|s| {
+            for worker in &mut workers {
+                let queue = &queue;
+                s.spawn(move |_| loop {
+                    if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                        break;
+                    }
+                    let Some(mut view) = queue.lock().unwrap().next() else {
+                        break;
+                    };
 
-        for (x, y, color) in pixels {
-            self.accumulation_data[x + y * camera.viewport_width] += color;
-            let mut acc_color = self.accumulation_data[x + y * camera.viewport_width];
+                    renderer.render_tile(scene, camera, &mut view, worker);
+                });
+            }
+        });
 
-            acc_color /= self.frame_index as f32;
+        // Only the splats across tile borders and the light paths are left to merge
+        let traced = remaining - queue.into_inner().unwrap().len();
+        for worker in &mut workers {
+            worker.merge_into(width, &mut accumulation_data, &mut self.light_image, &mut self.frame_stats);
+        }
+        (self.accumulation_data, self.aov_data, self.workers) = (accumulation_data, aov_data, workers);
+        self.next_tile += traced;
 
-            write_to_buffer_inverted(
-                camera.viewport_width,
-                camera.viewport_height,
-                buffer,
-                x,
-                y,
-                acc_color,
-            );
+        if self.next_tile < self.tiles.len() {
+            return false;
         }
 
+        self.next_tile = 0;
+        self.stats = self.frame_stats;
         self.frame_index += 1;
+        true
     }
 
-    fn per_pixel(&self, scene: &Scene, camera: &Camera, x: usize, y: usize) -> Vector4<f32> {
-        let mut ray = Ray {
-            origin: camera.get_position(),
-            direction: camera.get_ray_directions()[x + y * camera.viewport_width],
+    fn render_tile(&self, scene: &Scene, camera: &Camera, view: &mut TileView, worker: &mut TileWorker) {
+        let (width, height) = (camera.viewport_width, camera.viewport_height);
+        let tile = view.tile;
+        let TileWorker { sampler, border_splats, light_splats, light_paths, stats, .. } = worker;
+        let sampler = sampler.as_mut();
+
+        for y in tile.y0..tile.y1 {
+            for x in tile.x0..tile.x1 {
+                let pixel = &view.aov[y - tile.y0][x - tile.x0];
+                if !self.needs_samples(pixel) {
+                    continue;
+                }
+                let sample_index = pixel.samples;
+
+                let sample = match self.integrator {
+                    Integrator::Path => self.per_pixel(scene, camera, sampler, x, y, sample_index),
+                    Integrator::Bidirectional => {
+                        *light_paths += 1;
+                        bdpt::per_pixel(self, scene, camera, sampler, x, y, sample_index)
+                    }
+                };
+                stats.paths += 1;
+                stats.bounces += sample.path_length as u64;
+
+                view.splat(self.filter, sample.film_position, sample.color, width, height, border_splats);
+                view.aov[y - tile.y0][x - tile.x0] += sample.aov;
+                light_splats.extend(sample.splats);
+            }
+        }
+    }
+
+    // Per pixel values of a pass, bottom row first. The beauty pass is the filtered and possibly denoised radiance
+    pub fn get_aov_values(&self, aov: Aov, width: usize, height: usize) -> Vec<Vector3<f32>> {
+        if aov != Aov::Beauty {
+            return aov.values(&self.aov_data);
+        }
+
+        // The weight sum lives in w, it can end up negative with the negative lobes of some filters
+        let colors: Vec<Vector4<f32>> = self
+            .accumulation_data
+            .iter()
+            .map(|acc_color| if acc_color.w > 0.0 { acc_color / acc_color.w } else { Vector4::zero() })
+            .collect();
+
+        // Every light path is an estimate for the whole film, which each pixel covers a share of
+        let colors = if self.light_image.paths > 0 {
+            let scale = (width * height) as f32 / self.light_image.paths as f32;
+            colors
+                .iter()
+                .zip(&self.light_image.data)
+                .map(|(color, light)| color + (light * scale).extend(0.0))
+                .collect()
+        } else {
+            colors
+        };
+        let colors = if self.denoise {
+            denoise::denoise(width, height, &colors, &self.aov_data)
+        } else {
+            colors
+        };
+
+        colors.iter().map(|color| color.truncate()).collect()
+    }
+
+    // Writes a pass to an ARGB buffer, radiance through the display transform and data passes visualized
+    pub fn encode_aov(&self, aov: Aov, width: usize, height: usize, buffer: &mut [u32]) {
+        let values = self.get_aov_values(aov, width, height);
+        let values = if aov.is_radiance() { values } else { aov.visualize(&values) };
+
+        for y in 0..height {
+            for x in 0..width {
+                let value = values[x + y * width];
+                let color = if aov.is_radiance() {
+                    self.display.encode(value.extend(1.0))
+                } else {
+                    display::encode_data(value)
+                };
+
+                write_to_buffer_inverted(width, height, buffer, x, y, color);
+            }
+        }
+    }
+
+    // Returns where on the film the sample was taken, its radiance and the number of times the path scattered
+    // Pixels are sampled at their own pace, so `sample_index` is how many samples this one already has
+    fn per_pixel(&self, scene: &Scene, camera: &Camera, sampler: &mut dyn Sampler, x: usize, y: usize, sample_index: u32) -> PixelSample {
+        sampler.start_pixel_sample(x, y, sample_index);
+
+        // Jittered anywhere inside the pixel, the filter decides how much it counts for each pixel
+        let film_position = vec2(x as f32, y as f32) + sampler.get_2d();
+        let mut ray = camera.get_ray(film_position.x, film_position.y, sampler);
+        let mut channels = if self.spectral {
+            Channels::sample_wavelengths(sampler.get_1d())
+        } else {
+            Channels::Rgb
         };
 
         let mut light = Vector3::zero();
         let mut contribution = vec3::<f32>(1.0, 1.0, 1.0);
-        let mut seed: u32 = (x + y * camera.viewport_width).wrapping_mul(self.frame_index) as u32;
 
-        let bounces = 5;
+        // Emission found by following the BSDF is only weighted against light sampling after a non-delta bounce
+        let mut specular_bounce = true;
+        let mut bsdf_pdf = 0.0;
+        // Where the path last scattered, crossing an interface doesn't move it
+        let mut scatter_position = ray.origin;
+        let mut medium = scene.atmosphere;
 
-        for i in 0..bounces {
-            seed = seed.wrapping_add(i);
-            match self.trace_ray(&ray, scene) {
-                Some(payload) => {
-                    let sphere = &scene.spheres[payload.object_index];
-                    let material = &scene.materials[sphere.material_index];
+        let mut path_length = 0;
+        let mut depth = 0;
+        let mut segment = 0;
 
-                    contribution = contribution.mul_element_wise(material.albedo);
-                    light += material.get_emission();
+        // Taken at the first vertex that isn't an interface
+        let mut aov: Option<AovPixel> = None;
+        let mut travelled = 0.0;
+        // Light gathered up to and including what reaches the first vertex straight from an emitter
+        let mut direct: Option<Vector3<f32>> = None;
 
-                    ray.origin = payload.world_position + payload.world_normal * 0.0001;
+        while depth < self.max_depth {
+            sampler.start_dimension(CAMERA_DIMENSIONS + segment * SEGMENT_DIMENSIONS);
+            segment += 1;
 
-                    // ray.direction = reflect(ray.direction, payload.world_normal + material.roughness * random_vector3(-0.5, 0.5)).normalize()
-                    ray.direction = (payload.world_normal + pcg_vec3(&mut seed)).normalize()
-                }
-                _ => {
-                    if scene.global_illumination {
-                        light += vec3(0.6, 0.7, 0.9).mul_element_wise(contribution);
+            let hit = self.trace_ray(&ray, scene);
+
+            if let Some(medium_index) = medium {
+                let hit_distance = hit.as_ref().map_or(f32::MAX, |payload| payload.hit_distance);
+                let current = channels.medium(&scene.media[medium_index]);
+                match delta_track(&current, hit_distance, &mut contribution, sampler) {
+                    FreeFlight::Reached => {}
+                    FreeFlight::Absorbed => break,
+                    FreeFlight::Scattered(distance) => {
+                        depth += 1;
+                        path_length += 1;
+                        if depth == 2 {
+                            direct.get_or_insert(light);
+                        }
+
+                        let position = ray.origin + ray.direction * distance;
+                        let rgb = &scene.media[medium_index];
+                        aov.get_or_insert(AovPixel {
+                            albedo: rgb.scattering.zip(rgb.extinction(), |s, t| if t > 0.0 { s / t } else { 1.0 }),
+                            normal: -ray.direction,
+                            // Where the medium starts, the scattering distance itself is too noisy to guide with
+                            depth: travelled,
+                            position,
+                            ..AovPixel::default()
+                        });
+                        let vertex = Vertex::Medium { position, medium: &current };
+
+                        let direct = self.sample_direct_light(scene, &ray, &vertex, medium, channels, sampler)
+                            + self.sample_environment_light(scene, &ray, &vertex, medium, channels, sampler);
+                        light += direct.mul_element_wise(contribution);
+
+                        let (direction, phase_pdf) = current.sample_phase(ray.direction, sampler.get_2d());
+                        specular_bounce = false;
+                        bsdf_pdf = phase_pdf;
+
+                        if !self.russian_roulette(depth, &mut contribution, sampler) {
+                            break;
+                        }
+
+                        scatter_position = position;
+                        ray.origin = position;
+                        ray.direction = direction;
+                        continue;
                     }
-                    break;
                 }
             }
-        }
 
-        light.extend(1.0)
-    }
+            let Some(payload) = hit else {
+                aov.get_or_insert(AovPixel {
+                    albedo: vec3(1.0, 1.0, 1.0),
+                    ..AovPixel::default()
+                });
+                if let Some(environment) = &scene.environment {
+                    let weight = if specular_bounce {
+                        1.0
+                    } else {
+                        power_heuristic(bsdf_pdf, environment.pdf(ray.direction))
+                    };
+                    light += channels.color(environment.radiance(ray.direction)).mul_element_wise(contribution) * weight;
+                } else if scene.global_illumination {
+                    light += channels.color(vec3(0.6, 0.7, 0.9)).mul_element_wise(contribution);
+                }
+                break;
+            };
 
-    fn trace_ray(&self, ray: &Ray, scene: &Scene) -> Option<HitPayload> {
-        let mut closest = None;
+            let resolved = scene.materials[payload.material_index].resolve(&scene.textures, payload.uv, payload.world_position);
+            let material = &channels.material(&resolved);
 
-        let mut hit_distance = f32::MAX;
+            // Boundaries of media only change what the ray travels through
+            if material.is_interface() {
+                contribution = contribution.mul_element_wise(material.albedo);
+                medium = medium_behind(scene, material, payload.front_face);
+                ray.origin = offset_origin(&payload, ray.direction);
+                travelled += payload.hit_distance;
+                continue;
+            }
 
-        for sphere_index in 0..scene.spheres.len() {
-            let sphere = &scene.spheres[sphere_index];
+            aov.get_or_insert(AovPixel {
+                albedo: resolved.albedo,
+                normal: payload.world_normal,
+                depth: travelled + payload.hit_distance,
+                position: payload.world_position,
+                material_index: Some(payload.material_index),
+                object_index: Some(payload.object_index),
+                ..AovPixel::default()
+            });
 
-            let origin = ray.origin - sphere.position;
+            depth += 1;
+            path_length += 1;
 
-            let a = ray.direction.dot(ray.direction);
-            let b = 2.0 * origin.dot(ray.direction);
-            let c = origin.dot(origin) - sphere.radius * sphere.radius;
+            if payload.front_face && material.emission_power > 0.0 {
+                let weight = if specular_bounce {
+                    1.0
+                } else {
+                    let light_pdf = light::pdf(
+                        scene,
+                        scatter_position,
+                        ray.time,
+                        payload.object_index,
+                        payload.world_position,
+                        payload.geometric_normal,
+                    );
+                    power_heuristic(bsdf_pdf, light_pdf)
+                };
+                light += material.get_emission().mul_element_wise(contribution) * weight;
+            }
+            if depth == 2 {
+                direct.get_or_insert(light);
+            }
 
-            let discriminant = b * b - 4.0 * a * c;
+            if material.dispersion != Dispersion::None {
+                channels.terminate_secondary(&mut contribution);
+            }
 
-            if discriminant < 0.0 {
-                continue;
+            if !bsdf::is_specular(material) {
+                let vertex = Vertex::Surface { payload: &payload, material };
+                let direct = self.sample_direct_light(scene, &ray, &vertex, medium, channels, sampler)
+                    + self.sample_environment_light(scene, &ray, &vertex, medium, channels, sampler);
+                light += direct.mul_element_wise(contribution);
             }
 
-            let closest_t = (-b - discriminant.sqrt()) / (2.0 * a);
+            let Some(sample) = bsdf::sample(material, ray.direction, payload.world_normal, payload.front_face, sampler) else {
+                break;
+            };
+            contribution = contribution.mul_element_wise(sample.weight);
+            specular_bounce = sample.specular;
+            bsdf_pdf = sample.pdf;
 
-            if closest_t > 0.0 && closest_t < hit_distance {
-                hit_distance = closest_t;
-                closest = Some(sphere_index)
+            if !self.russian_roulette(depth, &mut contribution, sampler) {
+                break;
             }
+
+            // Transmitted rays continue in whatever fills the other side
+            if sample.direction.dot(payload.geometric_normal) < 0.0 {
+                medium = medium_behind(scene, material, payload.front_face);
+            }
+
+            scatter_position = payload.world_position;
+            ray.origin = offset_origin(&payload, sample.direction);
+            ray.direction = sample.direction;
+        }
+
+        let direct = channels.to_rgb(direct.unwrap_or(light));
+        let light = channels.to_rgb(light);
+        let aov = AovPixel {
+            direct,
+            indirect: light - direct,
+            samples: 1,
+            luminance: luminance(light),
+            luminance_squared: luminance(light).powi(2),
+            ..aov.unwrap_or_default()
+        };
+
+        PixelSample {
+            film_position,
+            color: light,
+            path_length,
+            aov,
+            splats: Vec::new(),
+        }
+    }
+
+    // Russian roulette: keeps paths with probability proportional to their throughput, returns whether it survived
+    pub fn russian_roulette(&self, depth: u32, contribution: &mut Vector3<f32>, sampler: &mut dyn Sampler) -> bool {
+        if depth < self.russian_roulette_depth {
+            return true;
+        }
+
+        let survival = contribution.x.max(contribution.y).max(contribution.z).min(0.95);
+        if sampler.get_1d() >= survival {
+            return false;
         }
+        *contribution /= survival;
 
-        closest.map(|hit| self.closest_hit(ray, scene, hit_distance, hit))
+        true
     }
 
-    fn closest_hit(
+    // Next-event estimation: a shadow ray towards a sampled emissive sphere, weighted against BSDF sampling
+    fn sample_direct_light(
         &self,
+        scene: &Scene,
         ray: &Ray,
+        vertex: &Vertex,
+        medium: Option<usize>,
+        channels: Channels,
+        sampler: &mut dyn Sampler,
+    ) -> Vector3<f32> {
+        let Some(light_sample) = light::sample(scene, vertex.position(), ray.time, sampler) else {
+            return Vector3::zero();
+        };
+        let Some((bsdf_value, bsdf_pdf)) = vertex.evaluate(ray.direction, light_sample.direction) else {
+            return Vector3::zero();
+        };
+
+        let shadow_ray = Ray {
+            origin: vertex.shadow_origin(light_sample.direction),
+            direction: light_sample.direction,
+            time: ray.time,
+        };
+        let transmittance = self.transmittance(
+            scene,
+            shadow_ray,
+            light_sample.distance * 1.001,
+            Some(light_sample.primitive_index),
+            medium,
+            channels,
+        );
+
+        if transmittance.is_zero() {
+            return Vector3::zero();
+        }
+
+        let weight = power_heuristic(light_sample.pdf, bsdf_pdf) / light_sample.pdf;
+        channels.color(light_sample.radiance).mul_element_wise(bsdf_value).mul_element_wise(transmittance) * weight
+    }
+
+    // Next-event estimation towards the environment, a shadow ray that has to escape the scene
+    pub fn sample_environment_light(
+        &self,
         scene: &Scene,
-        hit_distance: f32,
-        object_index: usize,
-    ) -> HitPayload {
-        let closest_sphere = &scene.spheres[object_index];
+        ray: &Ray,
+        vertex: &Vertex,
+        medium: Option<usize>,
+        channels: Channels,
+        sampler: &mut dyn Sampler,
+    ) -> Vector3<f32> {
+        let Some(environment) = &scene.environment else {
+            return Vector3::zero();
+        };
+
+        let u = sampler.get_2d();
+        let Some(environment_sample) = environment.sample(u.x, u.y) else {
+            return Vector3::zero();
+        };
+        let Some((bsdf_value, bsdf_pdf)) = vertex.evaluate(ray.direction, environment_sample.direction) else {
+            return Vector3::zero();
+        };
 
-        let origin = ray.origin - closest_sphere.position;
-        let world_position = origin + ray.direction * hit_distance;
+        let shadow_ray = Ray {
+            origin: vertex.shadow_origin(environment_sample.direction),
+            direction: environment_sample.direction,
+            time: ray.time,
+        };
+        let transmittance = self.transmittance(scene, shadow_ray, f32::MAX, None, medium, channels);
+
+        if transmittance.is_zero() {
+            return Vector3::zero();
+        }
 
-        let world_normal = world_position.normalize() + closest_sphere.position;
+        let weight = power_heuristic(environment_sample.pdf, bsdf_pdf) / environment_sample.pdf;
+        channels.color(environment_sample.radiance).mul_element_wise(bsdf_value).mul_element_wise(transmittance) * weight
+    }
+
+    // Fraction of light arriving along a shadow ray that passes through interfaces and is attenuated by the media
+    // between them. With a target it has to end on that primitive, otherwise it has to escape within `distance`
+    pub fn transmittance(
+        &self,
+        scene: &Scene,
+        mut ray: Ray,
+        distance: f32,
+        target: Option<usize>,
+        mut medium: Option<usize>,
+        channels: Channels,
+    ) -> Vector3<f32> {
+        let mut transmittance = vec3(1.0, 1.0, 1.0);
+        let mut remaining = distance;
+
+        loop {
+            let hit = scene.intersect(&ray, remaining);
+            if let Some(index) = medium {
+                let segment = hit.map_or(remaining, |hit| hit.distance);
+                transmittance = transmittance.mul_element_wise(channels.medium(&scene.media[index]).transmittance(segment));
+            }
+
+            let Some(hit) = hit else {
+                return if target.is_some() { Vector3::zero() } else { transmittance };
+            };
+            if Some(hit.primitive) == target {
+                return transmittance;
+            }
+
+            let payload = self.closest_hit(&ray, scene, &hit);
+            let material = scene.materials[payload.material_index].resolve(&scene.textures, payload.uv, payload.world_position);
+            if !material.is_interface() {
+                return Vector3::zero();
+            }
+
+            transmittance = transmittance.mul_element_wise(channels.color(material.albedo));
+            medium = medium_behind(scene, &material, payload.front_face);
+            ray.origin = offset_origin(&payload, ray.direction);
+            remaining -= hit.distance;
+        }
+    }
+
+    pub fn trace_ray(&self, ray: &Ray, scene: &Scene) -> Option<HitPayload> {
+        scene
+            .intersect(ray, f32::MAX)
+            .map(|hit| self.closest_hit(ray, scene, &hit))
+    }
+
+    fn closest_hit(&self, ray: &Ray, scene: &Scene, hit: &Hit) -> HitPayload {
+        let hit_distance = hit.distance;
+        let world_position = ray.origin + ray.direction * hit_distance;
+
+        let (outward_normal, shading_normal, uv, material_index) = match scene.primitives[hit.primitive] {
+            Primitive::Sphere(sphere_index) => {
+                let sphere = scene.spheres[sphere_index].at_time(ray.time);
+                let (normal, uv) = shape_surface(&sphere, hit.surface, world_position);
+                (normal, normal, uv, sphere.material_index)
+            }
+            Primitive::Object(object_index) => {
+                let object = &scene.objects[object_index];
+                let (normal, uv) = shape_surface(object.shape.as_ref(), hit.surface, world_position);
+                (normal, normal, uv, object.material_index)
+            }
+            Primitive::Triangle { mesh, .. } => mesh_surface(&scene.meshes[mesh], hit.surface),
+            Primitive::Instance(instance_index) => {
+                let instance = &scene.instances[instance_index];
+                let object_ray = instance.to_object_space(ray);
+                let object_position = object_ray.origin + object_ray.direction * hit_distance;
+
+                let (outward_normal, shading_normal, uv, material_index) = match &scene.prototypes[instance.prototype] {
+                    Prototype::Mesh { mesh, .. } => mesh_surface(mesh, hit.surface),
+                    Prototype::Shape { shape, material_index } => {
+                        let (normal, uv) = shape_surface(shape.as_ref(), hit.surface, object_position);
+                        (normal, normal, uv, *material_index)
+                    }
+                };
+
+                (
+                    instance.normal_to_world(outward_normal).normalize(),
+                    instance.normal_to_world(shading_normal).normalize(),
+                    uv,
+                    material_index,
+                )
+            }
+        };
+
+        let front_face = ray.direction.dot(outward_normal) < 0.0;
+        let (world_normal, geometric_normal) = if front_face {
+            (shading_normal, outward_normal)
+        } else {
+            (-shading_normal, -outward_normal)
+        };
 
         HitPayload {
             hit_distance,
-            object_index,
+            object_index: hit.primitive,
+            material_index,
             world_position,
             world_normal,
+            geometric_normal,
+            front_face,
+            uv,
+        }
+    }
+}
+
+// A point where a path scatters, off a surface or inside a medium
+pub enum Vertex<'a> {
+    Surface { payload: &'a HitPayload, material: &'a Material },
+    Medium { position: Vector3<f32>, medium: &'a Medium },
+}
+
+impl Vertex<'_> {
+    fn position(&self) -> Vector3<f32> {
+        match self {
+            Vertex::Surface { payload, .. } => payload.world_position,
+            Vertex::Medium { position, .. } => *position,
+        }
+    }
+
+    // Scattered throughput towards `direction` and its sampling density, like `bsdf::evaluate`
+    fn evaluate(&self, incoming: Vector3<f32>, direction: Vector3<f32>) -> Option<(Vector3<f32>, f32)> {
+        match self {
+            Vertex::Surface { payload, material } => bsdf::evaluate(material, -incoming, direction, payload.world_normal),
+            Vertex::Medium { medium, .. } => {
+                let phase = medium.phase(incoming, direction);
+                Some((vec3(phase, phase, phase), phase))
+            }
+        }
+    }
+
+    fn shadow_origin(&self, direction: Vector3<f32>) -> Vector3<f32> {
+        match self {
+            Vertex::Surface { payload, .. } => offset_origin(payload, direction),
+            Vertex::Medium { position, .. } => *position,
         }
     }
 }
 
+pub enum FreeFlight {
+    // Got to the end of the segment without a real collision
+    Reached,
+    Absorbed,
+    Scattered(f32),
+}
+
+// Delta tracking: tentative collisions are sampled with the majorant and the ones that turn out to be null are
+// stepped over, weighting the throughput so chromatic media stay unbiased
+pub fn delta_track(medium: &Medium, distance: f32, contribution: &mut Vector3<f32>, sampler: &mut dyn Sampler) -> FreeFlight {
+    let majorant = medium.majorant();
+    if majorant <= 0.0 {
+        return FreeFlight::Reached;
+    }
+
+    let mut t = 0.0;
+    loop {
+        t -= (1.0 - sampler.get_1d()).ln() / majorant;
+        if t >= distance {
+            return FreeFlight::Reached;
+        }
+
+        let (collision, weight) = medium.collide(sampler.get_1d());
+        *contribution = contribution.mul_element_wise(weight);
+        match collision {
+            Collision::Absorbed => return FreeFlight::Absorbed,
+            Collision::Scattered => return FreeFlight::Scattered(t),
+            Collision::Null => {}
+        }
+    }
+}
+
+// The medium a ray is in after crossing a surface with this material
+pub fn medium_behind(scene: &Scene, material: &Material, front_face: bool) -> Option<usize> {
+    if front_face {
+        material.medium
+    } else {
+        scene.atmosphere
+    }
+}
+
+// Outward normal and UV of a shape at the part that was hit
+fn shape_surface<S: Shape + ?Sized>(shape: &S, hit: SurfaceHit, position: Vector3<f32>) -> (Vector3<f32>, Vector2<f32>) {
+    let SurfaceHit::Shape { part } = hit else {
+        unreachable!("shapes are only hit as shapes");
+    };
+    let surface = shape.surface(position, part);
+
+    (surface.normal, surface.uv)
+}
+
+// Outward and shading normal, UV and material of the triangle that was hit
+fn mesh_surface(mesh: &Mesh, hit: SurfaceHit) -> (Vector3<f32>, Vector3<f32>, Vector2<f32>, usize) {
+    let SurfaceHit::Triangle { triangle, b1, b2 } = hit else {
+        unreachable!("meshes are only hit as triangles");
+    };
+    let surface = mesh.surface(triangle, b1, b2);
+
+    (surface.geometric_normal, surface.shading_normal, surface.uv, mesh.triangles[triangle].material_index)
+}
+
+// Offset to the side the new ray leaves from, so refracted rays start inside
+pub fn offset_origin(payload: &HitPayload, direction: Vector3<f32>) -> Vector3<f32> {
+    let offset = payload.geometric_normal * 0.0001;
+
+    if direction.dot(payload.geometric_normal) >= 0.0 {
+        payload.world_position + offset
+    } else {
+        payload.world_position - offset
+    }
+}
+
 fn write_to_buffer_inverted(
     width: usize,
     height: usize,
-    buffer: &mut Vec<u32>,
+    buffer: &mut [u32],
     x: usize,
     y: usize,
-    mut acc_color: Vector4<f32>,
+    color: u32,
 ) {
-    acc_color.x = acc_color.x.clamp(0.0, 1.0);
-    acc_color.y = acc_color.y.clamp(0.0, 1.0);
-    acc_color.z = acc_color.z.clamp(0.0, 1.0);
-    acc_color.w = acc_color.w.clamp(0.0, 1.0);
-
-    buffer[x + (height - y - 1) * width] = convert_to_rgba(acc_color);
+    buffer[x + (height - y - 1) * width] = color;
 }
 
-fn convert_to_rgba(color: Vector4<f32>) -> u32 {
-    let r = (color.x * 255.0) as u8;
-    let g = (color.y * 255.0) as u8;
-    let b = (color.z * 255.0) as u8;
-    let a = (color.w * 255.0) as u8;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene::Sphere;
+
+    #[test]
+    fn adaptive_sampling_stops_at_converged_pixels() {
+        let mut camera = Camera::new(45.0, 0.1, 100.0);
+        camera.on_resize(16, 16);
+        let (position, forward) = (camera.get_position(), camera.get_direction(8.0, 8.0).normalize());
+        let side = forward.cross(vec3(0.0, 1.0, 0.0)).normalize();
 
-    ((a as u32) << 24) | ((r as u32) << 16) | ((g as u32) << 8) | (b as u32)
+        // A diffuse sphere in the middle of the view, lit by a small light off to the side, and black around it
+        let mut scene = Scene::default();
+        scene.materials.push(Material::lambertian(vec3(0.5, 0.5, 0.5)));
+        scene.materials.push(Material::lambertian(vec3(1.0, 1.0, 1.0)).emissive(10.0));
+        scene.spheres.push(Sphere { position: position + forward * 5.0, motion: Vector3::zero(), radius: 1.0, material_index: 0 });
+        scene.spheres.push(Sphere { position: position + forward * 3.0 + side * 3.0, motion: Vector3::zero(), radius: 0.3, material_index: 1 });
+        scene.rebuild();
+
+        let mut renderer = Renderer::default();
+        renderer.on_resize(16, 16);
+        renderer.set_noise_target(0.001);
+        let frames = 48;
+        for _ in 0..frames {
+            while !renderer.accumulate(&scene, &camera, None) {}
+        }
+
+        let samples = renderer.get_aov_values(Aov::SampleCount, 16, 16);
+        assert_eq!(samples[0].x as u32, MIN_ADAPTIVE_SAMPLES);
+        assert_eq!(samples[15 * 16 + 15].x as u32, MIN_ADAPTIVE_SAMPLES);
+        assert_eq!(samples[8 * 16 + 8].x as u32, frames);
+        assert!(!renderer.is_converged());
+
+        // Once the rest is good enough too the whole image is done
+        renderer.set_noise_target(1e6);
+        while !renderer.accumulate(&scene, &camera, None) {}
+        assert!(renderer.is_converged());
+    }
 }
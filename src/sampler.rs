@@ -0,0 +1,588 @@
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use cgmath::{vec2, Vector2};
+
+use crate::utils::{pcg_float, pcg_hash};
+
+// Source of the random numbers of one path. Every pixel sample restarts at dimension zero, and the integrator
+// jumps to fixed dimensions per path segment so the same dimension always means the same decision
+pub trait Sampler: Send + Sync {
+    fn start_pixel_sample(&mut self, x: usize, y: usize, index: u32);
+    fn start_dimension(&mut self, dimension: u32);
+    // Both in [0, 1), a 2D sample uses two consecutive dimensions
+    fn get_1d(&mut self) -> f32;
+    fn get_2d(&mut self) -> Vector2<f32>;
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum SamplerKind {
+    Independent,
+    Stratified,
+    Halton,
+    Sobol,
+    BlueNoise,
+}
+
+impl SamplerKind {
+    pub const fn next(self) -> Self {
+        match self {
+            SamplerKind::Independent => SamplerKind::Stratified,
+            SamplerKind::Stratified => SamplerKind::Halton,
+            SamplerKind::Halton => SamplerKind::Sobol,
+            SamplerKind::Sobol => SamplerKind::BlueNoise,
+            SamplerKind::BlueNoise => SamplerKind::Independent,
+        }
+    }
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            SamplerKind::Independent => "independent",
+            SamplerKind::Stratified => "stratified",
+            SamplerKind::Halton => "halton",
+            SamplerKind::Sobol => "sobol",
+            SamplerKind::BlueNoise => "blue-noise",
+        }
+    }
+
+    pub fn create(self) -> Box<dyn Sampler> {
+        match self {
+            SamplerKind::Independent => Box::new(IndependentSampler::default()),
+            SamplerKind::Stratified => Box::new(StratifiedSampler::default()),
+            SamplerKind::Halton => Box::new(HaltonSampler::default()),
+            SamplerKind::Sobol => Box::new(SobolSampler::default()),
+            SamplerKind::BlueNoise => Box::new(BlueNoiseSampler::default()),
+        }
+    }
+}
+
+impl FromStr for SamplerKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "independent" => Ok(SamplerKind::Independent),
+            "stratified" => Ok(SamplerKind::Stratified),
+            "halton" => Ok(SamplerKind::Halton),
+            "sobol" => Ok(SamplerKind::Sobol),
+            "blue-noise" => Ok(SamplerKind::BlueNoise),
+            _ => Err(()),
+        }
+    }
+}
+
+// Where in the sequence a sampler is, shared by all implementations
+#[derive(Default, Clone, Copy)]
+struct Position {
+    x: u32,
+    y: u32,
+    // Hash of the pixel coordinates, decorrelates pixels
+    pixel: u32,
+    index: u32,
+    dimension: u32,
+}
+
+impl Position {
+    fn start(&mut self, x: usize, y: usize, index: u32) {
+        self.x = x as u32;
+        self.y = y as u32;
+        self.pixel = pcg_hash(self.x ^ pcg_hash(self.y));
+        self.index = index;
+        self.dimension = 0;
+    }
+
+    // Seed unique to this pixel and dimension, the same for all of the pixel's samples
+    fn dimension_seed(&self) -> u32 {
+        pcg_hash(self.pixel ^ pcg_hash(self.dimension))
+    }
+
+    fn advance(&mut self, dimensions: u32) -> u32 {
+        let dimension = self.dimension;
+        self.dimension += dimensions;
+        dimension
+    }
+}
+
+// Plain PCG random numbers, hashed from pixel, sample and dimension so neighbouring pixels and frames don't correlate
+#[derive(Default)]
+pub struct IndependentSampler {
+    position: Position,
+    state: u32,
+}
+
+impl IndependentSampler {
+    fn reseed(&mut self) {
+        self.state = pcg_hash(self.position.dimension_seed() ^ pcg_hash(self.position.index));
+    }
+}
+
+impl Sampler for IndependentSampler {
+    fn start_pixel_sample(&mut self, x: usize, y: usize, index: u32) {
+        self.position.start(x, y, index);
+        self.reseed();
+    }
+
+    fn start_dimension(&mut self, dimension: u32) {
+        self.position.dimension = dimension;
+        self.reseed();
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        self.position.advance(1);
+        pcg_float(&mut self.state).min(ONE_MINUS_EPSILON)
+    }
+
+    fn get_2d(&mut self) -> Vector2<f32> {
+        vec2(self.get_1d(), self.get_1d())
+    }
+}
+
+// Jittered strata, with every round of `STRATA` samples covering each stratum once in a shuffled order. The
+// number of samples isn't known up front, so progressive rendering stays stratified per round
+#[derive(Default)]
+pub struct StratifiedSampler {
+    position: Position,
+}
+
+impl StratifiedSampler {
+    const STRATA: u32 = 16;
+    const STRATA_2D: u32 = 4;
+
+    // Which stratum this sample falls in and a seed for jittering within it
+    fn stratum(&self, dimension: u32) -> (u32, u32) {
+        let round = self.position.index / Self::STRATA;
+        let seed = pcg_hash(self.position.pixel ^ pcg_hash(dimension ^ pcg_hash(round)));
+        (permute(self.position.index % Self::STRATA, Self::STRATA, seed), pcg_hash(seed ^ self.position.index))
+    }
+}
+
+impl Sampler for StratifiedSampler {
+    fn start_pixel_sample(&mut self, x: usize, y: usize, index: u32) {
+        self.position.start(x, y, index);
+    }
+
+    fn start_dimension(&mut self, dimension: u32) {
+        self.position.dimension = dimension;
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        let dimension = self.position.advance(1);
+        let (stratum, jitter) = self.stratum(dimension);
+        ((stratum as f32 + unit_float(jitter)) / Self::STRATA as f32).min(ONE_MINUS_EPSILON)
+    }
+
+    fn get_2d(&mut self) -> Vector2<f32> {
+        let dimension = self.position.advance(2);
+        let (stratum, jitter) = self.stratum(dimension);
+        let cell = vec2((stratum % Self::STRATA_2D) as f32, (stratum / Self::STRATA_2D) as f32);
+        let jitter = vec2(unit_float(jitter), unit_float(pcg_hash(jitter)));
+        ((cell + jitter) / Self::STRATA_2D as f32).map(|v| v.min(ONE_MINUS_EPSILON))
+    }
+}
+
+// Radical inverses in successive prime bases, with the digits randomly permuted per pixel and dimension so the
+// large bases don't cluster at low sample counts. Dimensions past the prime table are padded with independent
+// random numbers
+#[derive(Default)]
+pub struct HaltonSampler {
+    position: Position,
+}
+
+impl HaltonSampler {
+    const PRIMES: [u32; 64] = [
+        2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97, 101, 103, 107,
+        109, 113, 127, 131, 137, 139, 149, 151, 157, 163, 167, 173, 179, 181, 191, 193, 197, 199, 211, 223, 227, 229,
+        233, 239, 241, 251, 257, 263, 269, 271, 277, 281, 283, 293, 307, 311,
+    ];
+
+    fn sample(&self, dimension: u32) -> f32 {
+        let seed = pcg_hash(self.position.pixel ^ pcg_hash(dimension));
+        let Some(&base) = Self::PRIMES.get(dimension as usize) else {
+            return unit_float(pcg_hash(seed ^ pcg_hash(self.position.index)));
+        };
+
+        scrambled_radical_inverse(base, self.position.index, seed).min(ONE_MINUS_EPSILON)
+    }
+}
+
+impl Sampler for HaltonSampler {
+    fn start_pixel_sample(&mut self, x: usize, y: usize, index: u32) {
+        self.position.start(x, y, index);
+    }
+
+    fn start_dimension(&mut self, dimension: u32) {
+        self.position.dimension = dimension;
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        let dimension = self.position.advance(1);
+        self.sample(dimension)
+    }
+
+    fn get_2d(&mut self) -> Vector2<f32> {
+        let dimension = self.position.advance(2);
+        vec2(self.sample(dimension), self.sample(dimension + 1))
+    }
+}
+
+// The first two Sobol dimensions with hash based Owen scrambling, and the sample order shuffled per pixel and
+// dimension to pad them to any number of dimensions (Burley 2020)
+#[derive(Default)]
+pub struct SobolSampler {
+    position: Position,
+}
+
+impl Sampler for SobolSampler {
+    fn start_pixel_sample(&mut self, x: usize, y: usize, index: u32) {
+        self.position.start(x, y, index);
+    }
+
+    fn start_dimension(&mut self, dimension: u32) {
+        self.position.dimension = dimension;
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        let seed = self.position.dimension_seed();
+        self.position.advance(1);
+        shuffled_scrambled_sobol(self.position.index, seed).x
+    }
+
+    fn get_2d(&mut self) -> Vector2<f32> {
+        let seed = self.position.dimension_seed();
+        self.position.advance(2);
+        shuffled_scrambled_sobol(self.position.index, seed)
+    }
+}
+
+// The same scrambled Sobol points in every pixel, toroidally shifted by a blue noise mask so the error left at
+// low sample counts is spread as high frequency noise (Georgiev and Fajardo 2016)
+#[derive(Default)]
+pub struct BlueNoiseSampler {
+    position: Position,
+}
+
+impl BlueNoiseSampler {
+    // Each dimension reads the mask at its own offset so the shifts of different dimensions don't correlate
+    fn shift(&self, dimension: u32) -> f32 {
+        let mask = blue_noise_mask();
+        let offset = pcg_hash(dimension.wrapping_add(0x9e3779b9));
+        let x = (self.position.x + (offset & 0xffff)) as usize % BLUE_NOISE_SIZE;
+        let y = (self.position.y + (offset >> 16)) as usize % BLUE_NOISE_SIZE;
+        mask[x + y * BLUE_NOISE_SIZE]
+    }
+}
+
+impl Sampler for BlueNoiseSampler {
+    fn start_pixel_sample(&mut self, x: usize, y: usize, index: u32) {
+        self.position.start(x, y, index);
+    }
+
+    fn start_dimension(&mut self, dimension: u32) {
+        self.position.dimension = dimension;
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        let dimension = self.position.advance(1);
+        let point = shuffled_scrambled_sobol(self.position.index, pcg_hash(dimension));
+        (point.x + self.shift(dimension)).fract().min(ONE_MINUS_EPSILON)
+    }
+
+    fn get_2d(&mut self) -> Vector2<f32> {
+        let dimension = self.position.advance(2);
+        let point = shuffled_scrambled_sobol(self.position.index, pcg_hash(dimension));
+        let shift = vec2(self.shift(dimension), self.shift(dimension + 1));
+        (point + shift).map(|v| v.fract().min(ONE_MINUS_EPSILON))
+    }
+}
+
+const ONE_MINUS_EPSILON: f32 = 1.0 - f32::EPSILON / 2.0;
+
+fn unit_float(bits: u32) -> f32 {
+    (bits >> 8) as f32 / (1u32 << 24) as f32
+}
+
+// Each digit goes through its own permutation, including the trailing zeros up to float precision
+fn scrambled_radical_inverse(base: u32, mut index: u32, seed: u32) -> f32 {
+    let inverse_base = 1.0 / base as f64;
+    let mut inverse = inverse_base;
+    let mut result = 0.0;
+    let mut digit_index = 0;
+    while inverse > 1e-8 {
+        let digit = permute(index % base, base, pcg_hash(seed ^ digit_index));
+        result += digit as f64 * inverse;
+        index /= base;
+        inverse *= inverse_base;
+        digit_index += 1;
+    }
+
+    result as f32
+}
+
+// Random permutation of [0, length) picked by `seed`, evaluated one element at a time (Kensler 2013)
+fn permute(mut i: u32, length: u32, seed: u32) -> u32 {
+    let mut mask = length - 1;
+    mask |= mask >> 1;
+    mask |= mask >> 2;
+    mask |= mask >> 4;
+    mask |= mask >> 8;
+    mask |= mask >> 16;
+
+    // The hash is a bijection on [0, mask], so walking its cycle always comes back into [0, length)
+    loop {
+        i ^= seed;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= seed >> 16;
+        i ^= (i & mask) >> 4;
+        i ^= seed >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= seed >> 23;
+        i ^= (i & mask) >> 1;
+        i = i.wrapping_mul(1 | seed >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & mask) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & mask) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & mask) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= mask;
+        i ^= i >> 5;
+        if i < length {
+            return i.wrapping_add(seed) % length;
+        }
+    }
+}
+
+// Generator matrix columns of the second Sobol dimension, the first one is the bit reversal
+const fn sobol_directions() -> [u32; 32] {
+    let mut directions = [0; 32];
+    directions[0] = 1 << 31;
+    let mut i = 1;
+    while i < 32 {
+        directions[i] = directions[i - 1] ^ (directions[i - 1] >> 1);
+        i += 1;
+    }
+    directions
+}
+
+const SOBOL_DIRECTIONS: [u32; 32] = sobol_directions();
+
+fn sobol_2d(index: u32) -> (u32, u32) {
+    let mut y = 0;
+    for (bit, direction) in SOBOL_DIRECTIONS.iter().enumerate() {
+        if index & (1 << bit) != 0 {
+            y ^= direction;
+        }
+    }
+
+    (index.reverse_bits(), y)
+}
+
+// Hash that only lets each bit depend on the bits below it, a random base 2 permutation (Laine and Karras 2011)
+fn laine_karras_permutation(mut x: u32, seed: u32) -> u32 {
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50b47c);
+    x ^= x.wrapping_mul(0xb82f1e52);
+    x ^= x.wrapping_mul(0xc7afe638);
+    x ^= x.wrapping_mul(0x8d22f6e6);
+    x
+}
+
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+fn shuffled_scrambled_sobol(index: u32, seed: u32) -> Vector2<f32> {
+    let (x, y) = sobol_2d(nested_uniform_scramble(index, seed));
+    vec2(
+        unit_float(nested_uniform_scramble(x, pcg_hash(seed ^ 0xa511e9b3))),
+        unit_float(nested_uniform_scramble(y, pcg_hash(seed ^ 0x63d83595))),
+    )
+}
+
+const BLUE_NOISE_SIZE: usize = 64;
+
+// Ranks in [0, 1) of a tileable blue noise mask, built once by void and cluster (Ulichney 1993)
+fn blue_noise_mask() -> &'static [f32] {
+    static MASK: OnceLock<Vec<f32>> = OnceLock::new();
+    MASK.get_or_init(build_blue_noise_mask)
+}
+
+fn build_blue_noise_mask() -> Vec<f32> {
+    const SIGMA: f32 = 1.5;
+    let size = BLUE_NOISE_SIZE;
+    let count = size * size;
+
+    // Toroidal Gaussian energy each set pixel adds to the others
+    let kernel: Vec<f32> = (0..count)
+        .map(|i| {
+            let wrap = |d: usize| d.min(size - d) as f32;
+            let (dx, dy) = (wrap(i % size), wrap(i / size));
+            (-(dx * dx + dy * dy) / (2.0 * SIGMA * SIGMA)).exp()
+        })
+        .collect();
+
+    let splat = |energy: &mut [f32], pixel: usize, sign: f32| {
+        let (px, py) = (pixel % size, pixel / size);
+        for (i, e) in energy.iter_mut().enumerate() {
+            let dx = (i % size + size - px) % size;
+            let dy = (i / size + size - py) % size;
+            *e += sign * kernel[dx + dy * size];
+        }
+    };
+    // Tightest cluster among set pixels, or largest void among unset ones
+    let extreme = |energy: &[f32], set: &[bool], want_set: bool| -> usize {
+        let candidates = (0..count).filter(|&i| set[i] == want_set);
+        if want_set {
+            candidates.max_by(|&a, &b| energy[a].total_cmp(&energy[b])).unwrap_or(0)
+        } else {
+            candidates.min_by(|&a, &b| energy[a].total_cmp(&energy[b])).unwrap_or(0)
+        }
+    };
+
+    // A random initial pattern with a tenth of the pixels set, relaxed until no set pixel wants to move. That takes
+    // far fewer swaps than there are pixels, the cap only stops ties trading the same pixels back and forth forever
+    let mut set = vec![false; count];
+    let mut energy = vec![0.0; count];
+    let mut state = 0x1234_5678;
+    let mut initial = 0;
+    while initial < count / 10 {
+        let pixel = (pcg_float(&mut state) * count as f32) as usize % count;
+        if !set[pixel] {
+            set[pixel] = true;
+            splat(&mut energy, pixel, 1.0);
+            initial += 1;
+        }
+    }
+    for _ in 0..count {
+        let cluster = extreme(&energy, &set, true);
+        set[cluster] = false;
+        splat(&mut energy, cluster, -1.0);
+
+        let void = extreme(&energy, &set, false);
+        set[void] = true;
+        splat(&mut energy, void, 1.0);
+
+        if void == cluster {
+            break;
+        }
+    }
+
+    let mut ranks = vec![0; count];
+
+    // Removing the tightest clusters ranks the initial pixels from the top down
+    let (mut removed_set, mut removed_energy) = (set.clone(), energy.clone());
+    for rank in (0..initial).rev() {
+        let cluster = extreme(&removed_energy, &removed_set, true);
+        removed_set[cluster] = false;
+        splat(&mut removed_energy, cluster, -1.0);
+        ranks[cluster] = rank;
+    }
+
+    // Filling the largest voids ranks the rest
+    for rank in initial..count {
+        let void = extreme(&energy, &set, false);
+        set[void] = true;
+        splat(&mut energy, void, 1.0);
+        ranks[void] = rank;
+    }
+
+    ranks.iter().map(|&rank| (rank as f32 + 0.5) / count as f32).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KINDS: [SamplerKind; 5] = [
+        SamplerKind::Independent,
+        SamplerKind::Stratified,
+        SamplerKind::Halton,
+        SamplerKind::Sobol,
+        SamplerKind::BlueNoise,
+    ];
+
+    #[test]
+    fn stays_in_the_unit_interval() {
+        for kind in KINDS {
+            let mut sampler = kind.create();
+            for (x, y) in [(0, 0), (7, 3), (1023, 511)] {
+                for index in 0..256 {
+                    sampler.start_pixel_sample(x, y, index);
+                    // Past the end of the Halton prime table too
+                    for _ in 0..40 {
+                        let (a, b) = (sampler.get_1d(), sampler.get_2d());
+                        assert!([a, b.x, b.y].iter().all(|v| (0.0..1.0).contains(v)), "{} gave {a}, {b:?}", kind.name());
+                    }
+                }
+            }
+        }
+    }
+
+    // The first `count` samples of each dimension put one sample into each of `count` equal intervals
+    fn assert_stratified_1d(sampler: &mut dyn Sampler, dimension: u32, count: u32) {
+        let mut strata = vec![0; count as usize];
+        for index in 0..count {
+            sampler.start_pixel_sample(5, 9, index);
+            sampler.start_dimension(dimension);
+            strata[(sampler.get_1d() * count as f32) as usize] += 1;
+        }
+        assert!(strata.iter().all(|&n| n == 1), "dimension {dimension}: {strata:?}");
+    }
+
+    #[test]
+    fn stratified_per_dimension() {
+        for kind in [SamplerKind::Stratified, SamplerKind::Sobol] {
+            let mut sampler = kind.create();
+            for dimension in 0..12 {
+                assert_stratified_1d(sampler.as_mut(), dimension, 16);
+            }
+
+            // Pairs of dimensions fill a 4 by 4 grid
+            let mut cells = [0; 16];
+            for index in 0..16 {
+                sampler.start_pixel_sample(5, 9, index);
+                sampler.start_dimension(4);
+                let cell = (sampler.get_2d() * 4.0).map(|v| v as usize);
+                cells[cell.x + cell.y * 4] += 1;
+            }
+            assert!(cells.iter().all(|&n| n == 1), "{}: {cells:?}", kind.name());
+        }
+
+        // Halton dimensions are stratified in powers of their own base
+        let mut sampler = SamplerKind::Halton.create();
+        for (dimension, &base) in HaltonSampler::PRIMES.iter().enumerate().take(12) {
+            let count = (1..).map(|k| base.pow(k)).take_while(|&n| n <= 1000).last().unwrap();
+            assert_stratified_1d(sampler.as_mut(), dimension as u32, count);
+        }
+    }
+
+    // Root mean square error over many pixels of estimating the area of a quarter disc, whose edge cuts through
+    // the strata like the edges of a scene do
+    fn quarter_disc_rmse(kind: SamplerKind, samples: u32) -> f32 {
+        let mut sampler = kind.create();
+        let pixels = 256;
+        let mut squared_error = 0.0;
+        for pixel in 0..pixels {
+            let mut estimate = 0.0;
+            for index in 0..samples {
+                sampler.start_pixel_sample(pixel % 16, pixel / 16, index);
+                let u = sampler.get_2d();
+                if u.x * u.x + u.y * u.y < 1.0 {
+                    estimate += 1.0 / samples as f32;
+                }
+            }
+            squared_error += (estimate - std::f32::consts::FRAC_PI_4).powi(2) / pixels as f32;
+        }
+        squared_error.sqrt()
+    }
+
+    #[test]
+    fn lower_error_than_independent_samples() {
+        let independent = quarter_disc_rmse(SamplerKind::Independent, 64);
+        for kind in [SamplerKind::Stratified, SamplerKind::Halton, SamplerKind::Sobol, SamplerKind::BlueNoise] {
+            let rmse = quarter_disc_rmse(kind, 64);
+            assert!(rmse < 0.7 * independent, "{} {rmse} against {independent}", kind.name());
+        }
+    }
+}
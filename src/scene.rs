@@ -1,12 +1,38 @@
-use cgmath::{vec3, Vector3};
+use cgmath::{vec3, ElementWise, InnerSpace, Matrix, Matrix3, Matrix4, SquareMatrix, Vector2, Vector3, Zero};
 
+use crate::aabb::Aabb;
+use crate::bvh::{Bvh, WideBvh};
+use crate::environment::Environment;
+use crate::medium::Medium;
+use crate::mesh::Mesh;
+use crate::ray::Ray;
+use crate::shape::{Shape, ShapeHit};
+use crate::simd::F32x4;
+use crate::spectrum::Dispersion;
+use crate::texture::Texture;
+
+#[derive(Clone, Copy)]
 pub struct Material {
     pub albedo: Vector3<f32>,
     pub roughness: f32,
     pub metallic: f32,
 
+    // Dielectrics refract with this index of refraction, the albedo tints transmitted light
+    pub ior: f32,
+    // Replaces `ior` per wavelength in the spectral integrator
+    pub dispersion: Dispersion,
+    pub transmission: f32,
+
     pub emission_color: Vector3<f32>,
     pub emission_power: f32,
+
+    // Indices into `Scene::textures`, multiplying the constant values above. Roughness uses the first channel
+    pub albedo_texture: Option<usize>,
+    pub roughness_texture: Option<usize>,
+    pub emission_texture: Option<usize>,
+
+    // Index into `Scene::media` for the medium filling the inside, entered by rays transmitted through the front
+    pub medium: Option<usize>,
 }
 
 impl Material {
@@ -17,15 +43,52 @@ impl Material {
             metallic: 0.0,
             albedo,
             roughness: 0.0,
+            ior: 1.0,
+            transmission: 0.0,
+            albedo_texture: None,
+            roughness_texture: None,
+            emission_texture: None,
+            medium: None,
+            dispersion: Dispersion::None,
         }
     }
     pub const fn metal(albedo: Vector3<f32>, roughness: f32) -> Self {
         Self {
             emission_power: 0.0,
             emission_color: vec3(0.0, 0.0, 0.0),
-            metallic: 0.0,
+            metallic: 1.0,
             albedo,
             roughness,
+            ior: 1.0,
+            transmission: 0.0,
+            albedo_texture: None,
+            roughness_texture: None,
+            emission_texture: None,
+            medium: None,
+            dispersion: Dispersion::None,
+        }
+    }
+    pub const fn dielectric(albedo: Vector3<f32>, ior: f32) -> Self {
+        Self {
+            emission_power: 0.0,
+            emission_color: vec3(0.0, 0.0, 0.0),
+            metallic: 0.0,
+            albedo,
+            roughness: 0.0,
+            ior,
+            transmission: 1.0,
+            albedo_texture: None,
+            roughness_texture: None,
+            emission_texture: None,
+            medium: None,
+            dispersion: Dispersion::None,
+        }
+    }
+    // An invisible boundary, index matched so rays pass straight through into or out of the medium
+    pub const fn interface(medium: usize) -> Self {
+        Self {
+            medium: Some(medium),
+            ..Self::dielectric(vec3(1.0, 1.0, 1.0), 1.0)
         }
     }
     pub const fn emissive(self, emission_power: f32) -> Self {
@@ -35,20 +98,516 @@ impl Material {
             ..self
         }
     }
+    // `ior` still applies in RGB, where it should be the index at the middle of the spectrum
+    pub const fn with_dispersion(self, dispersion: Dispersion) -> Self {
+        Self {
+            dispersion,
+            ..self
+        }
+    }
+    pub const fn with_albedo_texture(self, texture: usize) -> Self {
+        Self {
+            albedo_texture: Some(texture),
+            ..self
+        }
+    }
+    pub const fn with_roughness_texture(self, texture: usize) -> Self {
+        Self {
+            roughness_texture: Some(texture),
+            ..self
+        }
+    }
+    pub fn is_interface(&self) -> bool {
+        self.transmission >= 1.0 && self.ior == 1.0 && self.emission_power == 0.0
+    }
     pub fn get_emission(&self) -> Vector3<f32> {
         self.emission_color * self.emission_power
     }
+
+    // The material at a surface point, with all textures applied
+    pub fn resolve(&self, textures: &[Texture], uv: Vector2<f32>, position: Vector3<f32>) -> Material {
+        let evaluate = |texture: Option<usize>| texture.map(|index| textures[index].evaluate(textures, uv, position));
+
+        let mut material = *self;
+        if let Some(albedo) = evaluate(self.albedo_texture) {
+            material.albedo = self.albedo.mul_element_wise(albedo);
+        }
+        if let Some(roughness) = evaluate(self.roughness_texture) {
+            material.roughness = (self.roughness * roughness.x).clamp(0.0, 1.0);
+        }
+        if let Some(emission) = evaluate(self.emission_texture) {
+            material.emission_color = self.emission_color.mul_element_wise(emission);
+        }
+
+        material
+    }
 }
 
 pub struct Sphere {
+    // Center at time zero, moving linearly by `motion` until time one
     pub position: Vector3<f32>,
+    pub motion: Vector3<f32>,
     pub radius: f32,
     pub material_index: usize,
 }
 
+impl Sphere {
+    pub fn center(&self, time: f32) -> Vector3<f32> {
+        self.position + self.motion * time
+    }
+
+    // Only intersection accounts for motion, surface queries and sampling need the sphere frozen at the ray's time
+    pub fn at_time(&self, time: f32) -> Sphere {
+        Sphere {
+            position: self.center(time),
+            motion: Vector3::zero(),
+            radius: self.radius,
+            material_index: self.material_index,
+        }
+    }
+}
+
+// Up to four spheres laid out one coordinate per vector, intersected with a ray all at once
+struct SpherePacket {
+    position: [F32x4; 3],
+    motion: [F32x4; 3],
+    radius_squared: F32x4,
+    count: usize,
+}
+
+impl SpherePacket {
+    fn new(spheres: &[&Sphere]) -> Self {
+        let lane = |value: &dyn Fn(&Sphere) -> f32| {
+            F32x4::new(std::array::from_fn(|i| spheres.get(i).map_or(0.0, |sphere| value(sphere))))
+        };
+
+        SpherePacket {
+            position: [0, 1, 2].map(|axis| lane(&|sphere| sphere.position[axis])),
+            motion: [0, 1, 2].map(|axis| lane(&|sphere| sphere.motion[axis])),
+            radius_squared: lane(&|sphere| sphere.radius * sphere.radius),
+            count: spheres.len(),
+        }
+    }
+
+    // Lane and distance of the closest hit, the same roots as the scalar test in `shape.rs`
+    fn intersect(&self, ray: &Ray, t_max: f32) -> Option<(usize, f32)> {
+        let time = F32x4::splat(ray.time);
+        let direction = [0, 1, 2].map(|axis| F32x4::splat(ray.direction[axis]));
+        let origin: [F32x4; 3] = std::array::from_fn(|axis| {
+            F32x4::splat(ray.origin[axis]) - (self.position[axis] + self.motion[axis] * time)
+        });
+        let dot = |a: &[F32x4; 3], b: &[F32x4; 3]| a[0] * b[0] + a[1] * b[1] + a[2] * b[2];
+
+        // With the halved linear term the quadratic's factors of two cancel out
+        let a = ray.direction.dot(ray.direction);
+        let half_b = dot(&origin, &direction);
+        let c = dot(&origin, &origin) - self.radius_squared;
+        let discriminant = half_b * half_b - F32x4::splat(a) * c;
+
+        let zero = F32x4::splat(0.0);
+        let t_max = F32x4::splat(t_max);
+        let root = discriminant.max(zero).sqrt();
+        let inverse_a = F32x4::splat(1.0 / a);
+        let closest_t = (zero - half_b - root) * inverse_a;
+        let farthest_t = (zero - half_b + root) * inverse_a;
+        let in_range = |t: F32x4| zero.lt(t).and(t.lt(t_max));
+
+        let t = closest_t.select(in_range(closest_t), farthest_t);
+        let hits = zero.le(discriminant).and(in_range(t)).bits() & ((1 << self.count) - 1);
+
+        let t = t.to_array();
+        (0..4)
+            .filter(|lane| hits & (1 << lane) != 0)
+            .map(|lane| (lane, t[lane]))
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+    }
+}
+
+// Where on a primitive a ray hit it, what shading needs besides the distance
+#[derive(Clone, Copy)]
+pub enum SurfaceHit {
+    // Spheres and other shapes, directly or instanced, see `ShapeHit`
+    Shape { part: u32 },
+    // Triangles of a mesh, directly or instanced, with the barycentric weights of the second and third vertex
+    Triangle { triangle: usize, b1: f32, b2: f32 },
+}
+
+fn shape_hit(hit: ShapeHit) -> (SurfaceHit, f32) {
+    (SurfaceHit::Shape { part: hit.part }, hit.distance)
+}
+
+fn triangle_hit(mesh: &Mesh, triangle: usize, ray: &Ray, t_max: f32) -> Option<(SurfaceHit, f32)> {
+    let hit = mesh.intersect(triangle, ray, t_max)?;
+    Some((SurfaceHit::Triangle { triangle, b1: hit.b1, b2: hit.b2 }, hit.distance))
+}
+
+// Closest hit along a ray
+#[derive(Clone, Copy)]
+pub struct Hit {
+    // Index into `Scene::primitives`
+    pub primitive: usize,
+    pub distance: f32,
+    pub surface: SurfaceHit,
+}
+
+// Any other shape, see `shape.rs`
+pub struct Object {
+    pub shape: Box<dyn Shape>,
+    pub material_index: usize,
+}
+
+// Object space geometry shared by any number of instances
+pub enum Prototype {
+    Mesh { mesh: Mesh, bvh: Bvh },
+    Shape { shape: Box<dyn Shape>, material_index: usize },
+}
+
+impl Prototype {
+    pub fn from_mesh(mesh: Mesh) -> Self {
+        let bounds: Vec<Aabb> = (0..mesh.triangles.len()).map(|i| mesh.triangle_bounds(i)).collect();
+        let bvh = Bvh::build(&bounds);
+        Prototype::Mesh { mesh, bvh }
+    }
+
+    // Nothing to hit, and no bounds to place in the BVH
+    pub fn is_empty(&self) -> bool {
+        match self {
+            Prototype::Mesh { mesh, .. } => mesh.triangles.is_empty(),
+            Prototype::Shape { .. } => false,
+        }
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        match self {
+            Prototype::Mesh { mesh, .. } => Some(mesh.positions.iter().fold(Aabb::EMPTY, |b, p| b.grow(*p))),
+            Prototype::Shape { shape, .. } => shape.bounds(),
+        }
+    }
+
+    // Expects an object space ray
+    pub fn intersect(&self, ray: &Ray, t_max: f32) -> Option<(SurfaceHit, f32)> {
+        match self {
+            Prototype::Mesh { mesh, bvh } => {
+                bvh.intersect(ray, t_max, |triangle, t_max| triangle_hit(mesh, triangle, ray, t_max))
+            }
+            Prototype::Shape { shape, .. } => shape.intersect(ray, t_max).map(shape_hit),
+        }
+    }
+}
+
+// A prototype placed in the world by an affine transform
+pub struct Instance {
+    pub prototype: usize,
+    transform: Matrix4<f32>,
+    inverse: Matrix4<f32>,
+    // Inverse transpose of the linear part, keeps normals perpendicular under non-uniform scale
+    normal_matrix: Matrix3<f32>,
+}
+
+impl Instance {
+    // None for a singular transform, which flattens the prototype and leaves rays nothing to map back
+    pub fn new(prototype: usize, transform: Matrix4<f32>) -> Option<Self> {
+        let inverse = transform.invert()?;
+        let normal_matrix = Matrix3::from_cols(inverse.x.truncate(), inverse.y.truncate(), inverse.z.truncate()).transpose();
+
+        Some(Self {
+            prototype,
+            transform,
+            inverse,
+            normal_matrix,
+        })
+    }
+
+    // The direction isn't normalized, so distances along the ray are the same in both spaces
+    pub fn to_object_space(&self, ray: &Ray) -> Ray {
+        Ray {
+            origin: (self.inverse * ray.origin.extend(1.0)).truncate(),
+            direction: (self.inverse * ray.direction.extend(0.0)).truncate(),
+            time: ray.time,
+        }
+    }
+
+    pub fn normal_to_world(&self, normal: Vector3<f32>) -> Vector3<f32> {
+        self.normal_matrix * normal
+    }
+}
+
+// Anything the scene can intersect, indexing into the scene's spheres, meshes, objects and instances
+#[derive(Clone, Copy)]
+pub enum Primitive {
+    Sphere(usize),
+    Triangle { mesh: usize, triangle: usize },
+    Object(usize),
+    Instance(usize),
+}
+
 #[derive(Default)]
 pub struct Scene {
     pub spheres: Vec<Sphere>,
+    pub meshes: Vec<Mesh>,
+    pub objects: Vec<Object>,
+    pub prototypes: Vec<Prototype>,
+    pub instances: Vec<Instance>,
     pub materials: Vec<Material>,
+    pub textures: Vec<Texture>,
+    // Constant sky color when no environment is set
     pub global_illumination: bool,
+    pub environment: Option<Environment>,
+    // Homogeneous media referenced by materials, and the one filling all space outside of them
+    pub media: Vec<Medium>,
+    pub atmosphere: Option<usize>,
+
+    // Bounded primitives come first and are the ones the BVH holds, unbounded ones follow
+    pub primitives: Vec<Primitive>,
+    pub bvh: Bvh,
+    wide_bvh: WideBvh,
+    // Per leaf of the wide BVH, present when the leaf holds nothing but spheres
+    sphere_packets: Vec<Option<SpherePacket>>,
+    unbounded_start: usize,
+    // Indices of emissive primitives with a finite area, sampled directly by the renderer, except for instances
+    pub lights: Vec<usize>,
+}
+
+impl Scene {
+    // Must be called whenever spheres, meshes, objects, instances or materials are added, removed or changed
+    pub fn rebuild(&mut self) {
+        let mut primitives: Vec<Primitive> = (0..self.spheres.len()).map(Primitive::Sphere).collect();
+        for (mesh_index, mesh) in self.meshes.iter().enumerate() {
+            primitives.extend((0..mesh.triangles.len()).map(|triangle| Primitive::Triangle {
+                mesh: mesh_index,
+                triangle,
+            }));
+        }
+        primitives.extend((0..self.objects.len()).map(Primitive::Object));
+        // Instances of empty prototypes would put the inverted bounds of `Aabb::EMPTY` into the BVH
+        primitives.extend(
+            (0..self.instances.len())
+                .filter(|&index| !self.prototypes[self.instances[index].prototype].is_empty())
+                .map(Primitive::Instance),
+        );
+
+        let (bounded, unbounded): (Vec<Primitive>, Vec<Primitive>) =
+            primitives.into_iter().partition(|p| self.primitive_bounds(*p).is_some());
+
+        let bounds: Vec<Aabb> = bounded.iter().filter_map(|p| self.primitive_bounds(*p)).collect();
+        self.bvh = Bvh::build(&bounds);
+
+        self.unbounded_start = bounded.len();
+        self.primitives = bounded;
+        self.primitives.extend(unbounded);
+
+        self.wide_bvh = WideBvh::collapse(&self.bvh, |i| matches!(self.primitives[i], Primitive::Sphere(_)));
+        self.sphere_packets = (0..self.wide_bvh.leaf_count())
+            .map(|leaf| {
+                let spheres: Option<Vec<&Sphere>> = self
+                    .wide_bvh
+                    .leaf_primitives(leaf)
+                    .iter()
+                    .map(|&i| match self.primitives[i] {
+                        Primitive::Sphere(index) => Some(&self.spheres[index]),
+                        _ => None,
+                    })
+                    .collect();
+                spheres.map(|spheres| SpherePacket::new(&spheres))
+            })
+            .collect();
+
+        // Emissive instances are only found by BSDF sampling, see `has_emissive_instances`
+        self.lights = (0..self.unbounded_start)
+            .filter(|&i| {
+                let material_index = match self.primitives[i] {
+                    Primitive::Sphere(index) => self.spheres[index].material_index,
+                    Primitive::Object(index) => self.objects[index].material_index,
+                    Primitive::Triangle { mesh, triangle } => self.meshes[mesh].triangles[triangle].material_index,
+                    Primitive::Instance(_) => return false,
+                };
+                self.materials[material_index].emission_power > 0.0
+            })
+            .collect();
+    }
+
+    // Instances aren't sampled as lights, their area changes with the transform, so emissive ones are noisy
+    pub fn has_emissive_instances(&self) -> bool {
+        let emits = |material_index: usize| self.materials[material_index].emission_power > 0.0;
+
+        self.instances.iter().any(|instance| match &self.prototypes[instance.prototype] {
+            Prototype::Mesh { mesh, .. } => mesh.triangles.iter().any(|triangle| emits(triangle.material_index)),
+            Prototype::Shape { material_index, .. } => emits(*material_index),
+        })
+    }
+
+    // Closest hit along the ray
+    pub fn intersect(&self, ray: &Ray, t_max: f32) -> Option<Hit> {
+        let closest = self.wide_bvh.intersect(ray, t_max, |leaf, t_max| {
+            let primitives = self.wide_bvh.leaf_primitives(leaf);
+            if let Some(packet) = &self.sphere_packets[leaf] {
+                return packet
+                    .intersect(ray, t_max)
+                    .map(|(lane, distance)| ((primitives[lane], SurfaceHit::Shape { part: 0 }), distance));
+            }
+
+            let mut closest = None;
+            for &index in primitives {
+                let t_max = closest.map_or(t_max, |(_, distance)| distance);
+                if let Some((surface, distance)) = self.hit_primitive(index, ray, t_max) {
+                    closest = Some(((index, surface), distance));
+                }
+            }
+            closest
+        });
+
+        self.intersect_unbounded(ray, t_max, closest)
+    }
+
+    // The same through the binary BVH one primitive at a time, which the benchmark compares against
+    pub fn intersect_scalar(&self, ray: &Ray, t_max: f32) -> Option<Hit> {
+        let closest = self.bvh.intersect(ray, t_max, |index, t_max| {
+            self.hit_primitive(index, ray, t_max)
+                .map(|(surface, distance)| ((index, surface), distance))
+        });
+
+        self.intersect_unbounded(ray, t_max, closest)
+    }
+
+    // Every bounded primitive in turn without any hierarchy, the starting point both BVHs are measured against
+    pub fn intersect_linear(&self, ray: &Ray, t_max: f32) -> Option<Hit> {
+        let mut closest = None;
+        for index in 0..self.unbounded_start {
+            let t_max = closest.map_or(t_max, |(_, distance)| distance);
+            if let Some((surface, distance)) = self.hit_primitive(index, ray, t_max) {
+                closest = Some(((index, surface), distance));
+            }
+        }
+
+        self.intersect_unbounded(ray, t_max, closest)
+    }
+
+    fn intersect_unbounded(&self, ray: &Ray, t_max: f32, mut closest: Option<((usize, SurfaceHit), f32)>) -> Option<Hit> {
+        for index in self.unbounded_start..self.primitives.len() {
+            let t_max = closest.map_or(t_max, |(_, distance)| distance);
+            if let Some((surface, distance)) = self.hit_primitive(index, ray, t_max) {
+                closest = Some(((index, surface), distance));
+            }
+        }
+
+        closest.map(|((primitive, surface), distance)| Hit { primitive, distance, surface })
+    }
+
+    fn hit_primitive(&self, index: usize, ray: &Ray, t_max: f32) -> Option<(SurfaceHit, f32)> {
+        match self.primitives[index] {
+            Primitive::Sphere(index) => self.spheres[index].intersect(ray, t_max).map(shape_hit),
+            Primitive::Triangle { mesh, triangle } => triangle_hit(&self.meshes[mesh], triangle, ray, t_max),
+            Primitive::Object(index) => self.objects[index]
+                .shape
+                .intersect(ray, t_max)
+                .map(shape_hit),
+            Primitive::Instance(index) => {
+                let instance = &self.instances[index];
+                self.prototypes[instance.prototype].intersect(&instance.to_object_space(ray), t_max)
+            }
+        }
+    }
+
+    fn primitive_bounds(&self, primitive: Primitive) -> Option<Aabb> {
+        match primitive {
+            Primitive::Sphere(index) => self.spheres[index].bounds(),
+            Primitive::Triangle { mesh, triangle } => Some(self.meshes[mesh].triangle_bounds(triangle)),
+            Primitive::Object(index) => self.objects[index].shape.bounds(),
+            Primitive::Instance(index) => {
+                let instance = &self.instances[index];
+                self.prototypes[instance.prototype]
+                    .bounds()
+                    .map(|bounds| bounds.transform(&instance.transform))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::{Rad, Transform};
+
+    use super::*;
+    use crate::mesh::Triangle;
+    use crate::renderer::Renderer;
+    use crate::utils::pcg_float;
+
+    fn octahedron() -> Mesh {
+        let positions = vec![
+            vec3(1.0, 0.0, 0.0),
+            vec3(-1.0, 0.0, 0.0),
+            vec3(0.0, 1.0, 0.0),
+            vec3(0.0, -1.0, 0.0),
+            vec3(0.0, 0.0, 1.0),
+            vec3(0.0, 0.0, -1.0),
+        ];
+        let faces = [[0, 2, 4], [2, 1, 4], [1, 3, 4], [3, 0, 4], [2, 0, 5], [1, 2, 5], [3, 1, 5], [0, 3, 5]];
+
+        Mesh {
+            positions,
+            triangles: faces
+                .into_iter()
+                .map(|positions| Triangle { positions, normals: None, uvs: None, material_index: 0 })
+                .collect(),
+            ..Mesh::default()
+        }
+    }
+
+    #[test]
+    fn skips_instances_of_empty_prototypes() {
+        let mut scene = Scene::default();
+        scene.materials.push(Material::lambertian(vec3(0.5, 0.5, 0.5)));
+        scene.spheres.push(Sphere { position: vec3(0.0, 0.0, -5.0), motion: Vector3::zero(), radius: 1.0, material_index: 0 });
+        scene.prototypes.push(Prototype::from_mesh(Mesh::default()));
+        scene.instances.push(Instance::new(0, Matrix4::from_scale(2.0)).unwrap());
+        scene.rebuild();
+
+        assert_eq!(scene.primitives.len(), 1);
+        let ray = Ray { origin: Vector3::zero(), direction: vec3(0.0, 0.0, -1.0), time: 0.0 };
+        let hit = scene.intersect(&ray, f32::MAX).unwrap();
+        assert!(hit.primitive == 0 && (hit.distance - 4.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn instances_match_pretransformed_meshes() {
+        let transform = Matrix4::from_translation(vec3(1.0, 2.0, -3.0))
+            * Matrix4::from_angle_y(Rad(0.7))
+            * Matrix4::from_angle_x(Rad(0.3))
+            * Matrix4::from_nonuniform_scale(2.0, 0.5, 1.5);
+
+        let mut instanced = Scene::default();
+        instanced.materials.push(Material::lambertian(vec3(0.5, 0.5, 0.5)));
+        instanced.prototypes.push(Prototype::from_mesh(octahedron()));
+        instanced.instances.push(Instance::new(0, transform).unwrap());
+        instanced.rebuild();
+
+        let mut placed = Scene::default();
+        placed.materials.push(Material::lambertian(vec3(0.5, 0.5, 0.5)));
+        let mut mesh = octahedron();
+        for position in &mut mesh.positions {
+            *position = transform.transform_vector(*position) + transform.w.truncate();
+        }
+        placed.meshes.push(mesh);
+        placed.rebuild();
+
+        let renderer = Renderer::default();
+        let mut state = 1;
+        let mut hits = 0;
+        for _ in 0..2000 {
+            let mut random = || vec3(pcg_float(&mut state), pcg_float(&mut state), pcg_float(&mut state)) - vec3(0.5, 0.5, 0.5);
+            let origin = vec3(1.0, 2.0, -3.0) + random().normalize() * 6.0;
+            let target = vec3(1.0, 2.0, -3.0) + random() * 3.0;
+            let ray = Ray { origin, direction: (target - origin).normalize(), time: 0.0 };
+
+            let (a, b) = (renderer.trace_ray(&ray, &instanced), renderer.trace_ray(&ray, &placed));
+            assert_eq!(a.is_some(), b.is_some());
+            if let (Some(a), Some(b)) = (a, b) {
+                assert!((a.hit_distance - b.hit_distance).abs() < 1e-4);
+                assert!((a.geometric_normal - b.geometric_normal).magnitude() < 1e-4);
+                assert!(a.front_face == b.front_face);
+                hits += 1;
+            }
+        }
+        assert!(hits > 500);
+    }
 }
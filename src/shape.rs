@@ -0,0 +1,690 @@
+use std::f32::consts::PI;
+
+use cgmath::{vec2, vec3, InnerSpace, Vector2, Vector3};
+
+use crate::aabb::Aabb;
+use crate::ray::Ray;
+use crate::scene::Sphere;
+use crate::utils::{orthonormal_basis, sample_concentric_disk, sample_uniform_sphere};
+
+pub struct ShapeHit {
+    pub distance: f32,
+    // Shape specific, e.g. which face of a box or which cap of a cylinder was hit
+    pub part: u32,
+}
+
+pub struct SurfaceInfo {
+    // Points outwards, regardless of the side the ray came from
+    pub normal: Vector3<f32>,
+    pub uv: Vector2<f32>,
+}
+
+pub struct SurfaceSample {
+    pub position: Vector3<f32>,
+    pub normal: Vector3<f32>,
+    // Same meaning as in `ShapeHit`
+    pub part: u32,
+}
+
+pub trait Shape: Send + Sync {
+    // Closest hit in (0, t_max)
+    fn intersect(&self, ray: &Ray, t_max: f32) -> Option<ShapeHit>;
+
+    fn surface(&self, position: Vector3<f32>, part: u32) -> SurfaceInfo;
+
+    // None for unbounded shapes, which are tested outside the BVH
+    fn bounds(&self) -> Option<Aabb>;
+
+    fn area(&self) -> f32;
+
+    // Uniformly distributed over the surface area, None for unbounded shapes
+    fn sample_surface(&self, u: Vector2<f32>) -> Option<SurfaceSample>;
+}
+
+impl Shape for Sphere {
+    fn intersect(&self, ray: &Ray, t_max: f32) -> Option<ShapeHit> {
+        let origin = ray.origin - self.center(ray.time);
+
+        let a = ray.direction.dot(ray.direction);
+        let b = 2.0 * origin.dot(ray.direction);
+        let c = origin.dot(origin) - self.radius * self.radius;
+
+        let discriminant = b * b - 4.0 * a * c;
+
+        if discriminant < 0.0 {
+            return None;
+        }
+
+        // Fall back to the far root for rays starting inside the sphere
+        let closest_t = (-b - discriminant.sqrt()) / (2.0 * a);
+        let farthest_t = (-b + discriminant.sqrt()) / (2.0 * a);
+
+        [closest_t, farthest_t]
+            .into_iter()
+            .find(|&t| t > 0.0 && t < t_max)
+            .map(|distance| ShapeHit { distance, part: 0 })
+    }
+
+    fn surface(&self, position: Vector3<f32>, _part: u32) -> SurfaceInfo {
+        let normal = (position - self.position).normalize();
+
+        // Longitude around the up axis and latitude from the south pole
+        let u = 0.5 + normal.z.atan2(normal.x) / (2.0 * PI);
+        let v = 0.5 + normal.y.clamp(-1.0, 1.0).asin() / PI;
+
+        SurfaceInfo { normal, uv: vec2(u, v) }
+    }
+
+    // Covers the whole motion
+    fn bounds(&self) -> Option<Aabb> {
+        let r = vec3(self.radius, self.radius, self.radius);
+        let end = self.center(1.0);
+        Some(Aabb::new(self.position - r, self.position + r).union(&Aabb::new(end - r, end + r)))
+    }
+
+    fn area(&self) -> f32 {
+        4.0 * PI * self.radius * self.radius
+    }
+
+    fn sample_surface(&self, u: Vector2<f32>) -> Option<SurfaceSample> {
+        let normal = sample_uniform_sphere(u);
+
+        Some(SurfaceSample {
+            position: self.position + normal * self.radius,
+            normal,
+            part: 0,
+        })
+    }
+}
+
+// Infinite plane through `point`, UVs repeat every world unit
+pub struct Plane {
+    pub point: Vector3<f32>,
+    pub normal: Vector3<f32>,
+}
+
+impl Shape for Plane {
+    fn intersect(&self, ray: &Ray, t_max: f32) -> Option<ShapeHit> {
+        let denominator = self.normal.dot(ray.direction);
+        if denominator.abs() < 1e-8 {
+            return None;
+        }
+
+        let distance = (self.point - ray.origin).dot(self.normal) / denominator;
+        (distance > 0.0 && distance < t_max).then_some(ShapeHit { distance, part: 0 })
+    }
+
+    fn surface(&self, position: Vector3<f32>, _part: u32) -> SurfaceInfo {
+        let (tangent, bitangent) = orthonormal_basis(self.normal);
+        let offset = position - self.point;
+
+        SurfaceInfo {
+            normal: self.normal,
+            uv: vec2(offset.dot(tangent), offset.dot(bitangent)),
+        }
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        None
+    }
+
+    fn area(&self) -> f32 {
+        f32::INFINITY
+    }
+
+    fn sample_surface(&self, _u: Vector2<f32>) -> Option<SurfaceSample> {
+        None
+    }
+}
+
+// Parallelogram spanned by two edges from a corner, UVs follow the edges
+pub struct Quad {
+    pub corner: Vector3<f32>,
+    pub edge_u: Vector3<f32>,
+    pub edge_v: Vector3<f32>,
+}
+
+impl Quad {
+    fn normal(&self) -> Vector3<f32> {
+        self.edge_u.cross(self.edge_v).normalize()
+    }
+
+    // Coordinates of a point in the plane of the quad along both edges
+    fn local(&self, position: Vector3<f32>) -> Vector2<f32> {
+        let n = self.edge_u.cross(self.edge_v);
+        let w = n / n.dot(n);
+        let offset = position - self.corner;
+
+        vec2(w.dot(offset.cross(self.edge_v)), w.dot(self.edge_u.cross(offset)))
+    }
+}
+
+impl Shape for Quad {
+    fn intersect(&self, ray: &Ray, t_max: f32) -> Option<ShapeHit> {
+        let normal = self.normal();
+        let denominator = normal.dot(ray.direction);
+        if denominator.abs() < 1e-8 {
+            return None;
+        }
+
+        let distance = (self.corner - ray.origin).dot(normal) / denominator;
+        if distance <= 0.0 || distance >= t_max {
+            return None;
+        }
+
+        let uv = self.local(ray.origin + ray.direction * distance);
+        if !(0.0..=1.0).contains(&uv.x) || !(0.0..=1.0).contains(&uv.y) {
+            return None;
+        }
+
+        Some(ShapeHit { distance, part: 0 })
+    }
+
+    fn surface(&self, position: Vector3<f32>, _part: u32) -> SurfaceInfo {
+        SurfaceInfo {
+            normal: self.normal(),
+            uv: self.local(position),
+        }
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        let c = self.corner;
+        Some(
+            Aabb::new(c, c + self.edge_u + self.edge_v)
+                .grow(c + self.edge_u)
+                .grow(c + self.edge_v),
+        )
+    }
+
+    fn area(&self) -> f32 {
+        self.edge_u.cross(self.edge_v).magnitude()
+    }
+
+    fn sample_surface(&self, u: Vector2<f32>) -> Option<SurfaceSample> {
+        Some(SurfaceSample {
+            position: self.corner + self.edge_u * u.x + self.edge_v * u.y,
+            normal: self.normal(),
+            part: 0,
+        })
+    }
+}
+
+// UVs are polar: distance from the center and angle
+pub struct Disc {
+    pub center: Vector3<f32>,
+    pub normal: Vector3<f32>,
+    pub radius: f32,
+}
+
+impl Shape for Disc {
+    fn intersect(&self, ray: &Ray, t_max: f32) -> Option<ShapeHit> {
+        let denominator = self.normal.dot(ray.direction);
+        if denominator.abs() < 1e-8 {
+            return None;
+        }
+
+        let distance = (self.center - ray.origin).dot(self.normal) / denominator;
+        if distance <= 0.0 || distance >= t_max {
+            return None;
+        }
+
+        let offset = ray.origin + ray.direction * distance - self.center;
+        (offset.magnitude2() <= self.radius * self.radius).then_some(ShapeHit { distance, part: 0 })
+    }
+
+    fn surface(&self, position: Vector3<f32>, _part: u32) -> SurfaceInfo {
+        let (tangent, bitangent) = orthonormal_basis(self.normal);
+        let offset = position - self.center;
+        let angle = offset.dot(bitangent).atan2(offset.dot(tangent));
+
+        SurfaceInfo {
+            normal: self.normal,
+            uv: vec2(offset.magnitude() / self.radius, 0.5 + angle / (2.0 * PI)),
+        }
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        // Extent of a disc along each axis is radius * sin of the angle between the axis and the normal
+        let n = self.normal;
+        let e = vec3(1.0 - n.x * n.x, 1.0 - n.y * n.y, 1.0 - n.z * n.z).map(|c| c.max(0.0).sqrt() * self.radius);
+        Some(Aabb::new(self.center - e, self.center + e))
+    }
+
+    fn area(&self) -> f32 {
+        PI * self.radius * self.radius
+    }
+
+    fn sample_surface(&self, u: Vector2<f32>) -> Option<SurfaceSample> {
+        let (tangent, bitangent) = orthonormal_basis(self.normal);
+        let d = sample_concentric_disk(u) * self.radius;
+
+        Some(SurfaceSample {
+            position: self.center + tangent * d.x + bitangent * d.y,
+            normal: self.normal,
+            part: 0,
+        })
+    }
+}
+
+const BOX_FACE_NORMALS: [Vector3<f32>; 6] = [
+    vec3(-1.0, 0.0, 0.0),
+    vec3(1.0, 0.0, 0.0),
+    vec3(0.0, -1.0, 0.0),
+    vec3(0.0, 1.0, 0.0),
+    vec3(0.0, 0.0, -1.0),
+    vec3(0.0, 0.0, 1.0),
+];
+
+// The hit part is the face index into BOX_FACE_NORMALS, UVs span each face
+pub struct AxisAlignedBox {
+    pub min: Vector3<f32>,
+    pub max: Vector3<f32>,
+}
+
+impl AxisAlignedBox {
+    fn face_area(&self, axis: usize) -> f32 {
+        let e = self.max - self.min;
+        e[(axis + 1) % 3] * e[(axis + 2) % 3]
+    }
+}
+
+impl Shape for AxisAlignedBox {
+    fn intersect(&self, ray: &Ray, t_max: f32) -> Option<ShapeHit> {
+        let mut t_near = f32::MIN;
+        let mut t_far = f32::MAX;
+        let mut near_face = 0;
+        let mut far_face = 0;
+
+        for axis in 0..3 {
+            let inverse = 1.0 / ray.direction[axis];
+            let mut t0 = (self.min[axis] - ray.origin[axis]) * inverse;
+            let mut t1 = (self.max[axis] - ray.origin[axis]) * inverse;
+            let (mut face0, mut face1) = (2 * axis, 2 * axis + 1);
+            if t0 > t1 {
+                std::mem::swap(&mut t0, &mut t1);
+                std::mem::swap(&mut face0, &mut face1);
+            }
+
+            if t0 > t_near {
+                t_near = t0;
+                near_face = face0;
+            }
+            if t1 < t_far {
+                t_far = t1;
+                far_face = face1;
+            }
+        }
+
+        if t_near > t_far {
+            return None;
+        }
+
+        // The far face is hit from inside
+        if t_near > 0.0 && t_near < t_max {
+            Some(ShapeHit { distance: t_near, part: near_face as u32 })
+        } else if t_far > 0.0 && t_far < t_max {
+            Some(ShapeHit { distance: t_far, part: far_face as u32 })
+        } else {
+            None
+        }
+    }
+
+    fn surface(&self, position: Vector3<f32>, part: u32) -> SurfaceInfo {
+        let axis = part as usize / 2;
+        let (a, b) = ((axis + 1) % 3, (axis + 2) % 3);
+        let extent = self.max - self.min;
+        let local = position - self.min;
+
+        SurfaceInfo {
+            normal: BOX_FACE_NORMALS[part as usize],
+            uv: vec2(local[a] / extent[a], local[b] / extent[b]),
+        }
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        Some(Aabb::new(self.min, self.max))
+    }
+
+    fn area(&self) -> f32 {
+        2.0 * (0..3).map(|axis| self.face_area(axis)).sum::<f32>()
+    }
+
+    fn sample_surface(&self, u: Vector2<f32>) -> Option<SurfaceSample> {
+        // Pick a face proportional to its area, then reuse the remainder of u.x on it
+        let mut target = u.x * self.area();
+        for (face, &normal) in BOX_FACE_NORMALS.iter().enumerate() {
+            let axis = face / 2;
+            let area = self.face_area(axis);
+            if target < area || face == 5 {
+                let s = (target / area).clamp(0.0, 1.0);
+                let (a, b) = ((axis + 1) % 3, (axis + 2) % 3);
+
+                let mut position = self.min;
+                position[axis] = if face % 2 == 0 { self.min[axis] } else { self.max[axis] };
+                position[a] += s * (self.max[a] - self.min[a]);
+                position[b] += u.y * (self.max[b] - self.min[b]);
+
+                return Some(SurfaceSample {
+                    position,
+                    normal,
+                    part: face as u32,
+                });
+            }
+            target -= area;
+        }
+
+        None
+    }
+}
+
+const CYLINDER_SIDE: u32 = 0;
+const CYLINDER_BOTTOM: u32 = 1;
+const CYLINDER_TOP: u32 = 2;
+
+// Capped cylinder standing on `base` along the unit `axis`.
+// UVs wrap around the side, the caps use the planar position scaled to [0, 1]
+pub struct Cylinder {
+    pub base: Vector3<f32>,
+    pub axis: Vector3<f32>,
+    pub radius: f32,
+    pub height: f32,
+}
+
+impl Cylinder {
+    fn side_area(&self) -> f32 {
+        2.0 * PI * self.radius * self.height
+    }
+
+    fn cap_area(&self) -> f32 {
+        PI * self.radius * self.radius
+    }
+}
+
+impl Shape for Cylinder {
+    fn intersect(&self, ray: &Ray, t_max: f32) -> Option<ShapeHit> {
+        let mut closest: Option<ShapeHit> = None;
+        let mut t_max = t_max;
+
+        // Side: solve for points at `radius` from the axis
+        let origin = ray.origin - self.base;
+        let d = ray.direction - self.axis * ray.direction.dot(self.axis);
+        let o = origin - self.axis * origin.dot(self.axis);
+
+        let a = d.dot(d);
+        let b = 2.0 * o.dot(d);
+        let c = o.dot(o) - self.radius * self.radius;
+        let discriminant = b * b - 4.0 * a * c;
+
+        if a > 1e-12 && discriminant >= 0.0 {
+            let root = discriminant.sqrt();
+            for t in [(-b - root) / (2.0 * a), (-b + root) / (2.0 * a)] {
+                let height = (origin + ray.direction * t).dot(self.axis);
+                if t > 0.0 && t < t_max && (0.0..=self.height).contains(&height) {
+                    closest = Some(ShapeHit { distance: t, part: CYLINDER_SIDE });
+                    t_max = t;
+                    break;
+                }
+            }
+        }
+
+        // Caps
+        let denominator = ray.direction.dot(self.axis);
+        if denominator.abs() > 1e-8 {
+            for (part, height) in [(CYLINDER_BOTTOM, 0.0), (CYLINDER_TOP, self.height)] {
+                let t = (height - origin.dot(self.axis)) / denominator;
+                if t <= 0.0 || t >= t_max {
+                    continue;
+                }
+
+                let p = origin + ray.direction * t;
+                let radial = p - self.axis * p.dot(self.axis);
+                if radial.magnitude2() <= self.radius * self.radius {
+                    closest = Some(ShapeHit { distance: t, part });
+                    t_max = t;
+                }
+            }
+        }
+
+        closest
+    }
+
+    fn surface(&self, position: Vector3<f32>, part: u32) -> SurfaceInfo {
+        let (tangent, bitangent) = orthonormal_basis(self.axis);
+        let local = position - self.base;
+        let height = local.dot(self.axis);
+        let radial = local - self.axis * height;
+
+        match part {
+            CYLINDER_SIDE => {
+                let angle = radial.dot(bitangent).atan2(radial.dot(tangent));
+                SurfaceInfo {
+                    normal: radial.normalize(),
+                    uv: vec2(0.5 + angle / (2.0 * PI), height / self.height),
+                }
+            }
+            _ => SurfaceInfo {
+                normal: if part == CYLINDER_TOP { self.axis } else { -self.axis },
+                uv: vec2(
+                    0.5 + 0.5 * radial.dot(tangent) / self.radius,
+                    0.5 + 0.5 * radial.dot(bitangent) / self.radius,
+                ),
+            },
+        }
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        let top = self.base + self.axis * self.height;
+        let a = self.axis;
+        let e = vec3(1.0 - a.x * a.x, 1.0 - a.y * a.y, 1.0 - a.z * a.z).map(|c| c.max(0.0).sqrt() * self.radius);
+
+        Some(Aabb::new(self.base - e, self.base + e).union(&Aabb::new(top - e, top + e)))
+    }
+
+    fn area(&self) -> f32 {
+        self.side_area() + 2.0 * self.cap_area()
+    }
+
+    fn sample_surface(&self, u: Vector2<f32>) -> Option<SurfaceSample> {
+        let (tangent, bitangent) = orthonormal_basis(self.axis);
+        let side_fraction = self.side_area() / self.area();
+
+        if u.x < side_fraction {
+            let s = u.x / side_fraction;
+            let phi = 2.0 * PI * u.y;
+            let normal = tangent * phi.cos() + bitangent * phi.sin();
+
+            return Some(SurfaceSample {
+                position: self.base + self.axis * (s * self.height) + normal * self.radius,
+                normal,
+                part: CYLINDER_SIDE,
+            });
+        }
+
+        // Remap the rest of u.x onto the two caps
+        let s = (u.x - side_fraction) / (1.0 - side_fraction);
+        let (top, s) = if s < 0.5 { (false, s * 2.0) } else { (true, s * 2.0 - 1.0) };
+        let d = sample_concentric_disk(vec2(s, u.y)) * self.radius;
+        let center = if top { self.base + self.axis * self.height } else { self.base };
+
+        Some(SurfaceSample {
+            position: center + tangent * d.x + bitangent * d.y,
+            normal: if top { self.axis } else { -self.axis },
+            part: if top { CYLINDER_TOP } else { CYLINDER_BOTTOM },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::pcg_float;
+
+    fn distance(shape: &dyn Shape, origin: Vector3<f32>, direction: Vector3<f32>) -> Option<f32> {
+        let ray = Ray { origin, direction: direction.normalize(), time: 0.0 };
+        shape.intersect(&ray, f32::MAX).map(|hit| hit.distance)
+    }
+
+    fn close(a: Vector2<f32>, b: Vector2<f32>) -> bool {
+        (a - b).magnitude() < 1e-5
+    }
+
+    fn assert_hits(shape: &dyn Shape, origin: Vector3<f32>, direction: Vector3<f32>, expected: f32) {
+        let hit = distance(shape, origin, direction);
+        assert!(hit.is_some_and(|t| (t - expected).abs() < 1e-4), "{hit:?} instead of {expected}");
+    }
+
+    // Every point on the surface lies in the bounds, with the normal the surface reports there
+    fn assert_samples_in_bounds(shape: &dyn Shape) {
+        let bounds = shape.bounds().unwrap();
+        let mut state = 1;
+        for _ in 0..1000 {
+            let sample = shape.sample_surface(vec2(pcg_float(&mut state), pcg_float(&mut state))).unwrap();
+            let (low, high) = (sample.position - bounds.min, bounds.max - sample.position);
+            assert!(low.x.min(low.y).min(low.z) > -1e-5 && high.x.min(high.y).min(high.z) > -1e-5);
+            let normal = shape.surface(sample.position, sample.part).normal;
+            assert!((normal - sample.normal).magnitude() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn plane() {
+        let plane = Plane { point: vec3(0.0, 1.0, 0.0), normal: vec3(0.0, 1.0, 0.0) };
+
+        assert_hits(&plane, vec3(0.0, 3.0, 0.0), vec3(0.0, -1.0, 0.0), 2.0);
+        assert_hits(&plane, vec3(0.0, 3.0, 0.0), vec3(1.0, -1.0, 0.0), 2.0 * 2.0f32.sqrt());
+        // From behind, and away from it
+        assert_hits(&plane, vec3(5.0, 0.0, 5.0), vec3(0.0, 1.0, 0.0), 1.0);
+        assert_eq!(distance(&plane, vec3(0.0, 3.0, 0.0), vec3(0.0, 1.0, 0.0)), None);
+        // Grazing
+        assert_eq!(distance(&plane, vec3(0.0, 1.0, 0.0), vec3(1.0, 0.0, 0.0)), None);
+        assert_eq!(distance(&plane, vec3(0.0, 1.5, 0.0), vec3(1.0, 0.0, 1.0)), None);
+        let ray = Ray { origin: vec3(0.0, 3.0, 0.0), direction: vec3(0.0, -1.0, 0.0), time: 0.0 };
+        assert!(plane.intersect(&ray, 1.5).is_none());
+
+        // UVs are world units along the plane
+        let (a, b) = (vec3(1.0, 1.0, 2.0), vec3(4.0, 1.0, -2.0));
+        let (uv_a, uv_b) = (plane.surface(a, 0).uv, plane.surface(b, 0).uv);
+        assert!(((uv_a - uv_b).magnitude() - 5.0).abs() < 1e-5);
+        assert!(plane.bounds().is_none() && plane.sample_surface(vec2(0.5, 0.5)).is_none());
+    }
+
+    #[test]
+    fn quad() {
+        let quad = Quad {
+            corner: vec3(0.0, 0.0, 0.0),
+            edge_u: vec3(2.0, 0.0, 0.0),
+            edge_v: vec3(0.0, 0.0, 1.0),
+        };
+
+        assert_hits(&quad, vec3(1.0, 1.0, 0.5), vec3(0.0, -1.0, 0.0), 1.0);
+        assert_hits(&quad, vec3(1.0, -2.0, 0.5), vec3(0.0, 1.0, 0.0), 2.0);
+        assert_hits(&quad, vec3(2.0, 1.0, 1.0), vec3(0.0, -1.0, 0.0), 1.0);
+        assert_eq!(distance(&quad, vec3(2.5, 1.0, 0.5), vec3(0.0, -1.0, 0.0)), None);
+        assert_eq!(distance(&quad, vec3(1.0, 1.0, -0.5), vec3(0.0, -1.0, 0.0)), None);
+        // Grazing, and starting on it
+        assert_eq!(distance(&quad, vec3(-1.0, 0.0, 0.5), vec3(1.0, 0.0, 0.0)), None);
+        assert_eq!(distance(&quad, vec3(1.0, 0.0, 0.5), vec3(0.0, 1.0, 0.0)), None);
+
+        let surface = quad.surface(vec3(1.5, 0.0, 0.25), 0);
+        assert!(close(surface.uv, vec2(0.75, 0.25)));
+        assert!((surface.normal - vec3(0.0, -1.0, 0.0)).magnitude() < 1e-6);
+        let bounds = quad.bounds().unwrap();
+        assert!(bounds.min == vec3(0.0, 0.0, 0.0) && bounds.max == vec3(2.0, 0.0, 1.0));
+        assert!((quad.area() - 2.0).abs() < 1e-6);
+        assert_samples_in_bounds(&quad);
+    }
+
+    #[test]
+    fn disc() {
+        let disc = Disc { center: vec3(0.0, 0.0, 0.0), normal: vec3(0.0, 0.0, 1.0), radius: 2.0 };
+
+        assert_hits(&disc, vec3(1.0, 1.0, 5.0), vec3(0.0, 0.0, -1.0), 5.0);
+        assert_hits(&disc, vec3(0.0, 0.0, -3.0), vec3(0.0, 1.0, 3.0), 10.0f32.sqrt());
+        assert_eq!(distance(&disc, vec3(1.5, 1.5, 5.0), vec3(0.0, 0.0, -1.0)), None);
+        assert_eq!(distance(&disc, vec3(1.0, 1.0, 5.0), vec3(0.0, 0.0, 1.0)), None);
+        // Grazing, and starting on it
+        assert_eq!(distance(&disc, vec3(-5.0, 0.0, 0.0), vec3(1.0, 0.0, 0.0)), None);
+        assert_eq!(distance(&disc, vec3(0.5, 0.0, 0.0), vec3(0.0, 0.0, 1.0)), None);
+
+        // Distance from the center, and the angle once around it
+        let uv = disc.surface(vec3(1.0, 1.0, 0.0), 0).uv;
+        assert!((uv.x - 2.0f32.sqrt() / 2.0).abs() < 1e-5 && (0.0..=1.0).contains(&uv.y));
+        let opposite = disc.surface(vec3(-1.0, -1.0, 0.0), 0).uv;
+        assert!(((uv.y - opposite.y).abs() - 0.5).abs() < 1e-5);
+
+        let bounds = disc.bounds().unwrap();
+        assert!(bounds.min == vec3(-2.0, -2.0, 0.0) && bounds.max == vec3(2.0, 2.0, 0.0));
+        assert_samples_in_bounds(&disc);
+
+        // Tilted, the extent along y and z shrinks to where the rim is highest
+        let tilted = Disc { normal: vec3(0.0, 1.0, 1.0).normalize(), ..disc };
+        let bounds = tilted.bounds().unwrap();
+        assert!((bounds.max - vec3(2.0, 2.0f32.sqrt(), 2.0f32.sqrt())).magnitude() < 1e-5);
+        assert_samples_in_bounds(&tilted);
+    }
+
+    #[test]
+    fn axis_aligned_box() {
+        let cube = AxisAlignedBox { min: vec3(-1.0, -1.0, -1.0), max: vec3(1.0, 1.0, 1.0) };
+        let hit = |origin, direction| {
+            let ray = Ray { origin, direction, time: 0.0 };
+            cube.intersect(&ray, f32::MAX).map(|hit| (hit.distance, hit.part))
+        };
+
+        assert_eq!(hit(vec3(-3.0, 0.0, 0.0), vec3(1.0, 0.0, 0.0)), Some((2.0, 0)));
+        assert_eq!(hit(vec3(0.5, 4.0, 0.5), vec3(0.0, -1.0, 0.0)), Some((3.0, 3)));
+        assert_hits(&cube, vec3(-3.0, -3.0, 0.0), vec3(1.0, 1.0, 0.0), 2.0 * 2.0f32.sqrt());
+        assert_eq!(hit(vec3(-3.0, 2.0, 0.0), vec3(1.0, 0.0, 0.0)), None);
+        assert_eq!(hit(vec3(-3.0, 0.0, 0.0), vec3(-1.0, 0.0, 0.0)), None);
+        // Inside, the far face
+        assert_eq!(hit(vec3(0.0, 0.0, 0.0), vec3(1.0, 0.0, 0.0)), Some((1.0, 1)));
+        assert_eq!(hit(vec3(0.0, 0.5, 0.0), vec3(0.0, 0.0, -1.0)), Some((1.0, 4)));
+        // Grazing just inside and just outside of a face
+        assert_eq!(hit(vec3(-3.0, 0.999, 0.0), vec3(1.0, 0.0, 0.0)), Some((2.0, 0)));
+        assert_eq!(hit(vec3(-3.0, 1.001, 0.0), vec3(1.0, 0.0, 0.0)), None);
+
+        let surface = cube.surface(vec3(-1.0, 0.5, -0.5), 0);
+        assert!(close(surface.uv, vec2(0.75, 0.25)));
+        assert!(surface.normal == vec3(-1.0, 0.0, 0.0));
+        let bounds = cube.bounds().unwrap();
+        assert!(bounds.min == cube.min && bounds.max == cube.max);
+        assert!((cube.area() - 24.0).abs() < 1e-6);
+        assert_samples_in_bounds(&cube);
+    }
+
+    #[test]
+    fn cylinder() {
+        let cylinder = Cylinder { base: vec3(0.0, 0.0, 0.0), axis: vec3(0.0, 1.0, 0.0), radius: 1.0, height: 2.0 };
+        let hit = |origin, direction| {
+            let ray = Ray { origin, direction, time: 0.0 };
+            cylinder.intersect(&ray, f32::MAX).map(|hit| (hit.distance, hit.part))
+        };
+
+        assert_eq!(hit(vec3(-3.0, 1.0, 0.0), vec3(1.0, 0.0, 0.0)), Some((2.0, CYLINDER_SIDE)));
+        assert_eq!(hit(vec3(0.5, 5.0, 0.0), vec3(0.0, -1.0, 0.0)), Some((3.0, CYLINDER_TOP)));
+        assert_eq!(hit(vec3(0.2, -1.0, 0.3), vec3(0.0, 1.0, 0.0)), Some((1.0, CYLINDER_BOTTOM)));
+        assert_eq!(hit(vec3(-3.0, 3.0, 0.0), vec3(1.0, 0.0, 0.0)), None);
+        assert_eq!(hit(vec3(-3.0, 1.0, 1.5), vec3(1.0, 0.0, 0.0)), None);
+        assert_eq!(hit(vec3(-3.0, 1.0, 0.0), vec3(-1.0, 0.0, 0.0)), None);
+        // Inside, through the side and through a cap
+        assert_eq!(hit(vec3(0.0, 1.0, 0.0), vec3(1.0, 0.0, 0.0)), Some((1.0, CYLINDER_SIDE)));
+        assert_eq!(hit(vec3(0.0, 1.0, 0.0), vec3(0.0, 1.0, 0.0)), Some((1.0, CYLINDER_TOP)));
+        // Grazing the side, touching it and passing just outside, and running along it on the rim of the caps
+        assert_hits(&cylinder, vec3(-3.0, 1.0, 1.0), vec3(1.0, 0.0, 0.0), 3.0);
+        assert_eq!(hit(vec3(-3.0, 1.0, 1.001), vec3(1.0, 0.0, 0.0)), None);
+        assert_eq!(hit(vec3(1.0, -1.0, 0.0), vec3(0.0, 1.0, 0.0)), Some((1.0, CYLINDER_BOTTOM)));
+
+        let side = cylinder.surface(vec3(1.0, 0.5, 0.0), CYLINDER_SIDE);
+        assert!(side.normal == vec3(1.0, 0.0, 0.0));
+        assert!((side.uv.y - 0.25).abs() < 1e-6 && (0.0..=1.0).contains(&side.uv.x));
+        let top = cylinder.surface(vec3(0.0, 2.0, 0.0), CYLINDER_TOP);
+        assert!(top.normal == vec3(0.0, 1.0, 0.0) && close(top.uv, vec2(0.5, 0.5)));
+        let rim = cylinder.surface(vec3(1.0, 0.0, 0.0), CYLINDER_BOTTOM).uv;
+        assert!(((rim - vec2(0.5, 0.5)).magnitude() - 0.5).abs() < 1e-5);
+
+        let bounds = cylinder.bounds().unwrap();
+        assert!(bounds.min == vec3(-1.0, 0.0, -1.0) && bounds.max == vec3(1.0, 2.0, 1.0));
+        assert!((cylinder.area() - 6.0 * PI).abs() < 1e-5);
+        assert_samples_in_bounds(&cylinder);
+    }
+}
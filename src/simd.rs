@@ -0,0 +1,183 @@
+use std::ops::{Add, Mul, Sub};
+
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::*;
+
+// Four floats processed by one instruction. SSE2 is part of every x86_64 target, which is what makes the intrinsics
+// below safe to call; other architectures fall back to plain arrays that the compiler is free to vectorize on its own
+#[cfg(target_arch = "x86_64")]
+#[derive(Clone, Copy)]
+pub struct F32x4(__m128);
+
+#[cfg(not(target_arch = "x86_64"))]
+#[derive(Clone, Copy)]
+pub struct F32x4([f32; 4]);
+
+// Per lane result of a comparison
+#[cfg(target_arch = "x86_64")]
+#[derive(Clone, Copy)]
+pub struct Mask4(__m128);
+
+#[cfg(not(target_arch = "x86_64"))]
+#[derive(Clone, Copy)]
+pub struct Mask4([bool; 4]);
+
+#[cfg(target_arch = "x86_64")]
+impl F32x4 {
+    pub fn splat(value: f32) -> Self {
+        unsafe { F32x4(_mm_set1_ps(value)) }
+    }
+
+    pub fn new(lanes: [f32; 4]) -> Self {
+        unsafe { F32x4(_mm_setr_ps(lanes[0], lanes[1], lanes[2], lanes[3])) }
+    }
+
+    pub fn to_array(self) -> [f32; 4] {
+        let mut lanes = [0.0; 4];
+        // `lanes` has room for the four floats and unaligned stores are allowed
+        unsafe { _mm_storeu_ps(lanes.as_mut_ptr(), self.0) };
+        lanes
+    }
+
+    // Like SSE, lanes where either side is NaN come from `other`, so a value that may be NaN goes first
+    pub fn min(self, other: Self) -> Self {
+        unsafe { F32x4(_mm_min_ps(self.0, other.0)) }
+    }
+
+    pub fn max(self, other: Self) -> Self {
+        unsafe { F32x4(_mm_max_ps(self.0, other.0)) }
+    }
+
+    pub fn sqrt(self) -> Self {
+        unsafe { F32x4(_mm_sqrt_ps(self.0)) }
+    }
+
+    pub fn lt(self, other: Self) -> Mask4 {
+        unsafe { Mask4(_mm_cmplt_ps(self.0, other.0)) }
+    }
+
+    pub fn le(self, other: Self) -> Mask4 {
+        unsafe { Mask4(_mm_cmple_ps(self.0, other.0)) }
+    }
+
+    // Lanes from `self` where the mask is set and from `other` elsewhere
+    pub fn select(self, mask: Mask4, other: Self) -> Self {
+        unsafe { F32x4(_mm_or_ps(_mm_and_ps(mask.0, self.0), _mm_andnot_ps(mask.0, other.0))) }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+impl Mask4 {
+    pub fn and(self, other: Self) -> Self {
+        unsafe { Mask4(_mm_and_ps(self.0, other.0)) }
+    }
+
+    // One bit per lane, lane zero lowest
+    pub fn bits(self) -> u32 {
+        unsafe { _mm_movemask_ps(self.0) as u32 }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+impl Add for F32x4 {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        unsafe { F32x4(_mm_add_ps(self.0, other.0)) }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+impl Sub for F32x4 {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        unsafe { F32x4(_mm_sub_ps(self.0, other.0)) }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+impl Mul for F32x4 {
+    type Output = Self;
+
+    fn mul(self, other: Self) -> Self {
+        unsafe { F32x4(_mm_mul_ps(self.0, other.0)) }
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+impl F32x4 {
+    pub fn splat(value: f32) -> Self {
+        F32x4([value; 4])
+    }
+
+    pub fn new(lanes: [f32; 4]) -> Self {
+        F32x4(lanes)
+    }
+
+    pub fn to_array(self) -> [f32; 4] {
+        self.0
+    }
+
+    pub fn min(self, other: Self) -> Self {
+        F32x4(std::array::from_fn(|i| if self.0[i] < other.0[i] { self.0[i] } else { other.0[i] }))
+    }
+
+    pub fn max(self, other: Self) -> Self {
+        F32x4(std::array::from_fn(|i| if self.0[i] > other.0[i] { self.0[i] } else { other.0[i] }))
+    }
+
+    pub fn sqrt(self) -> Self {
+        F32x4(self.0.map(f32::sqrt))
+    }
+
+    pub fn lt(self, other: Self) -> Mask4 {
+        Mask4(std::array::from_fn(|i| self.0[i] < other.0[i]))
+    }
+
+    pub fn le(self, other: Self) -> Mask4 {
+        Mask4(std::array::from_fn(|i| self.0[i] <= other.0[i]))
+    }
+
+    pub fn select(self, mask: Mask4, other: Self) -> Self {
+        F32x4(std::array::from_fn(|i| if mask.0[i] { self.0[i] } else { other.0[i] }))
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+impl Mask4 {
+    pub fn and(self, other: Self) -> Self {
+        Mask4(std::array::from_fn(|i| self.0[i] && other.0[i]))
+    }
+
+    pub fn bits(self) -> u32 {
+        (0..4).filter(|&i| self.0[i]).map(|i| 1 << i).sum()
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+impl Add for F32x4 {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        F32x4(std::array::from_fn(|i| self.0[i] + other.0[i]))
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+impl Sub for F32x4 {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        F32x4(std::array::from_fn(|i| self.0[i] - other.0[i]))
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+impl Mul for F32x4 {
+    type Output = Self;
+
+    fn mul(self, other: Self) -> Self {
+        F32x4(std::array::from_fn(|i| self.0[i] * other.0[i]))
+    }
+}
@@ -0,0 +1,270 @@
+use std::sync::OnceLock;
+
+use cgmath::{vec3, ElementWise, Vector3};
+
+use crate::medium::Medium;
+use crate::scene::Material;
+
+// Visible range the spectral integrator samples, in nanometers
+const WAVELENGTH_MIN: f32 = 380.0;
+const WAVELENGTH_MAX: f32 = 720.0;
+
+// What the three components of the colors carried along a path stand for
+#[derive(Clone, Copy)]
+pub enum Channels {
+    Rgb,
+    // Radiance at three wavelengths in nanometers, the hero first. After a dispersive event only the hero is left
+    Wavelengths { lambda: Vector3<f32>, hero_only: bool },
+}
+
+impl Channels {
+    // Hero wavelength sampling (Wilkie et al. 2014): one uniform wavelength, the others rotated from it by a third
+    // of the range so the three always cover the spectrum evenly
+    pub fn sample_wavelengths(u: f32) -> Self {
+        let range = WAVELENGTH_MAX - WAVELENGTH_MIN;
+        let offset = u * range;
+        let rotated = |k: f32| WAVELENGTH_MIN + (offset + k * range / 3.0) % range;
+
+        Channels::Wavelengths {
+            lambda: vec3(rotated(0.0), rotated(1.0), rotated(2.0)),
+            hero_only: false,
+        }
+    }
+
+    // An RGB input like an albedo or an emission as seen by these channels
+    pub fn color(self, rgb: Vector3<f32>) -> Vector3<f32> {
+        match self {
+            Channels::Rgb => rgb,
+            Channels::Wavelengths { lambda, .. } => lambda.map(|wavelength| rgb_to_spectrum(rgb, wavelength)),
+        }
+    }
+
+    pub fn medium(self, medium: &Medium) -> Medium {
+        Medium {
+            absorption: self.color(medium.absorption),
+            scattering: self.color(medium.scattering),
+            anisotropy: medium.anisotropy,
+        }
+    }
+
+    // A resolved material as seen by these channels, dispersive dielectrics take the index of the hero wavelength
+    pub fn material(self, material: &Material) -> Material {
+        let Channels::Wavelengths { lambda, .. } = self else {
+            return *material;
+        };
+
+        Material {
+            albedo: self.color(material.albedo),
+            emission_color: self.color(material.emission_color),
+            ior: material.dispersion.ior(lambda.x).unwrap_or(material.ior),
+            ..*material
+        }
+    }
+
+    // Once a path refracted at a wavelength dependent angle it only stays valid for the hero. Keeping the hero with
+    // three times the weight keeps the estimate unbiased, as it is still uniformly distributed over the spectrum
+    pub fn terminate_secondary(&mut self, contribution: &mut Vector3<f32>) {
+        if let Channels::Wavelengths { hero_only, .. } = self {
+            if !*hero_only {
+                *hero_only = true;
+                *contribution = vec3(contribution.x * 3.0, 0.0, 0.0);
+            }
+        }
+    }
+
+    // Radiance carried by these channels as linear sRGB for the film
+    pub fn to_rgb(self, radiance: Vector3<f32>) -> Vector3<f32> {
+        let Channels::Wavelengths { lambda, .. } = self else {
+            return radiance;
+        };
+
+        // Each wavelength was picked with density 1 / range and the three are averaged
+        let range = WAVELENGTH_MAX - WAVELENGTH_MIN;
+        let xyz = (0..3).fold(Vector3::new(0.0, 0.0, 0.0), |xyz, i| xyz + color_matching(lambda[i]) * radiance[i])
+            * (range / 3.0);
+
+        xyz_to_srgb(xyz).div_element_wise(white())
+    }
+}
+
+// How the index of refraction of a dielectric varies with the wavelength, which is in micrometers in both formulas
+#[derive(Clone, Copy, PartialEq)]
+pub enum Dispersion {
+    None,
+    // n = a + b / λ²
+    Cauchy { a: f32, b: f32 },
+    // n² = 1 + Σ bᵢ λ² / (λ² - cᵢ)
+    Sellmeier { b: [f32; 3], c: [f32; 3] },
+}
+
+impl Dispersion {
+    // Schott N-SF11, a dense flint glass with strong dispersion
+    pub const SF11: Self = Dispersion::Sellmeier {
+        b: [1.737_597, 0.313_747_35, 1.898_781],
+        c: [0.013_188_707, 0.062_306_814, 155.236_3],
+    };
+
+    pub fn ior(self, wavelength: f32) -> Option<f32> {
+        let l2 = (wavelength * 1e-3).powi(2);
+        match self {
+            Dispersion::None => None,
+            Dispersion::Cauchy { a, b } => Some(a + b / l2),
+            Dispersion::Sellmeier { b, c } => {
+                Some((1.0 + (0..3).map(|i| b[i] * l2 / (l2 - c[i])).sum::<f32>()).sqrt())
+            }
+        }
+    }
+}
+
+// CIE 1931 2° observer as the multi-lobe fit of Wyman et al. 2013
+fn color_matching(wavelength: f32) -> Vector3<f32> {
+    let g = |mu: f32, sigma_low: f32, sigma_high: f32| {
+        let t = (wavelength - mu) / if wavelength < mu { sigma_low } else { sigma_high };
+        (-0.5 * t * t).exp()
+    };
+
+    vec3(
+        1.056 * g(599.8, 37.9, 31.0) + 0.362 * g(442.0, 16.0, 26.7) - 0.065 * g(501.1, 20.4, 26.2),
+        0.821 * g(568.8, 46.9, 40.5) + 0.286 * g(530.9, 16.3, 31.1),
+        1.217 * g(437.0, 11.8, 36.0) + 0.681 * g(459.0, 26.0, 13.8),
+    )
+}
+
+fn xyz_to_srgb(xyz: Vector3<f32>) -> Vector3<f32> {
+    vec3(
+        3.240_454_2 * xyz.x - 1.537_138_5 * xyz.y - 0.498_531_4 * xyz.z,
+        -0.969_266 * xyz.x + 1.876_010_8 * xyz.y + 0.041_556 * xyz.z,
+        0.055_643_4 * xyz.x - 0.204_025_9 * xyz.y + 1.057_225_2 * xyz.z,
+    )
+}
+
+// What a constant spectrum of one comes out as. Dividing by it keeps greys grey and makes the spectral and RGB
+// integrators agree on white, instead of showing the equal energy white point tinted
+fn white() -> Vector3<f32> {
+    static WHITE: OnceLock<Vector3<f32>> = OnceLock::new();
+    *WHITE.get_or_init(|| {
+        let steps = (WAVELENGTH_MAX - WAVELENGTH_MIN) as usize;
+        let xyz = (0..steps).fold(Vector3::new(0.0, 0.0, 0.0), |xyz, i| {
+            xyz + color_matching(WAVELENGTH_MIN + i as f32 + 0.5)
+        });
+        xyz_to_srgb(xyz)
+    })
+}
+
+// Smits 1999: a spectrum built from white and the spectra of the primaries and their complements, each sampled in
+// ten bins over the visible range
+const SMITS_WHITE: [f32; 10] = [1.0, 1.0, 0.9999, 0.9993, 0.9992, 0.9998, 1.0, 1.0, 1.0, 1.0];
+const SMITS_CYAN: [f32; 10] = [0.971, 0.9426, 1.0007, 1.0007, 1.0007, 1.0007, 0.1564, 0.0, 0.0, 0.0];
+const SMITS_MAGENTA: [f32; 10] = [1.0, 1.0, 0.9685, 0.2229, 0.0, 0.0458, 0.8369, 1.0, 1.0, 0.9959];
+const SMITS_YELLOW: [f32; 10] = [0.0001, 0.0, 0.1088, 0.6651, 1.0, 1.0, 0.9996, 0.9586, 0.9685, 0.984];
+const SMITS_RED: [f32; 10] = [0.1012, 0.0515, 0.0, 0.0, 0.0, 0.0, 0.8325, 1.0149, 1.0149, 1.0149];
+const SMITS_GREEN: [f32; 10] = [0.0, 0.0, 0.0273, 0.7937, 1.0, 0.9418, 0.1719, 0.0, 0.0, 0.0025];
+const SMITS_BLUE: [f32; 10] = [1.0, 1.0, 0.8916, 0.3323, 0.0, 0.0, 0.0003, 0.0369, 0.0483, 0.0496];
+
+fn rgb_to_spectrum(rgb: Vector3<f32>, wavelength: f32) -> f32 {
+    // Linear between bin centers, so the spectra are smooth rather than steps
+    let x = ((wavelength - WAVELENGTH_MIN) / (WAVELENGTH_MAX - WAVELENGTH_MIN) * 10.0 - 0.5).clamp(0.0, 9.0);
+    let i = (x as usize).min(8);
+    let basis = |spectrum: &[f32; 10]| spectrum[i] + (spectrum[i + 1] - spectrum[i]) * (x - i as f32);
+
+    let (r, g, b) = (rgb.x, rgb.y, rgb.z);
+    if r <= g && r <= b {
+        r * basis(&SMITS_WHITE)
+            + if g <= b {
+                (g - r) * basis(&SMITS_CYAN) + (b - g) * basis(&SMITS_BLUE)
+            } else {
+                (b - r) * basis(&SMITS_CYAN) + (g - b) * basis(&SMITS_GREEN)
+            }
+    } else if g <= r && g <= b {
+        g * basis(&SMITS_WHITE)
+            + if r <= b {
+                (r - g) * basis(&SMITS_MAGENTA) + (b - r) * basis(&SMITS_BLUE)
+            } else {
+                (b - g) * basis(&SMITS_MAGENTA) + (r - b) * basis(&SMITS_RED)
+            }
+    } else {
+        b * basis(&SMITS_WHITE)
+            + if r <= g {
+                (r - b) * basis(&SMITS_YELLOW) + (g - r) * basis(&SMITS_GREEN)
+            } else {
+                (g - b) * basis(&SMITS_YELLOW) + (r - g) * basis(&SMITS_RED)
+            }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Mean of the film color over evenly spread hero wavelengths
+    fn average_rgb(rgb: Vector3<f32>, hero_only: bool) -> Vector3<f32> {
+        let n = 3400;
+        (0..n).fold(Vector3::new(0.0, 0.0, 0.0), |sum, i| {
+            let mut channels = Channels::sample_wavelengths((i as f32 + 0.5) / n as f32);
+            let mut radiance = channels.color(rgb);
+            if hero_only {
+                channels.terminate_secondary(&mut radiance);
+            }
+            sum + channels.to_rgb(radiance)
+        }) / n as f32
+    }
+
+    fn assert_close(a: Vector3<f32>, b: Vector3<f32>, tolerance: f32) {
+        for i in 0..3 {
+            assert!((a[i] - b[i]).abs() < tolerance, "{:?} != {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn constant_spectrum_comes_out_white() {
+        let n = 3400;
+        let white = (0..n).fold(Vector3::new(0.0, 0.0, 0.0), |sum, i| {
+            let channels = Channels::sample_wavelengths((i as f32 + 0.5) / n as f32);
+            sum + channels.to_rgb(vec3(1.0, 1.0, 1.0))
+        }) / n as f32;
+
+        assert_close(white, vec3(1.0, 1.0, 1.0), 1e-3);
+    }
+
+    #[test]
+    fn greys_stay_grey() {
+        for grey in [1.0, 0.5, 0.18] {
+            let rgb = vec3(grey, grey, grey);
+            assert_close(average_rgb(rgb, false), rgb, 1e-2);
+            assert_close(average_rgb(rgb, true), rgb, 1e-2);
+        }
+    }
+
+    #[test]
+    fn rgb_channels_pass_through() {
+        let rgb = vec3(0.2, 0.5, 0.9);
+        assert!(Channels::Rgb.color(rgb) == rgb);
+        assert!(Channels::Rgb.to_rgb(rgb) == rgb);
+    }
+
+    #[test]
+    fn wavelengths_cover_the_range_evenly() {
+        for u in [0.0, 0.3, 0.999] {
+            let Channels::Wavelengths { lambda, hero_only } = Channels::sample_wavelengths(u) else {
+                unreachable!();
+            };
+            assert!(!hero_only);
+            assert!((lambda.x - (WAVELENGTH_MIN + u * (WAVELENGTH_MAX - WAVELENGTH_MIN))).abs() < 1e-3);
+
+            let mut sorted: [f32; 3] = lambda.into();
+            sorted.sort_by(f32::total_cmp);
+            assert!(sorted.iter().all(|l| (WAVELENGTH_MIN..WAVELENGTH_MAX).contains(l)));
+            let third = (WAVELENGTH_MAX - WAVELENGTH_MIN) / 3.0;
+            assert!((sorted[1] - sorted[0] - third).abs() < 1e-3 && (sorted[2] - sorted[1] - third).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn dispersion_matches_catalog_indices() {
+        // N-SF11 at the helium d line, and a Cauchy glass with n = 1.5 + 0.01 / λ²
+        assert!((Dispersion::SF11.ior(587.56).unwrap() - 1.784_72).abs() < 1e-4);
+        assert!((Dispersion::Cauchy { a: 1.5, b: 0.01 }.ior(500.0).unwrap() - 1.54).abs() < 1e-5);
+        assert!(Dispersion::None.ior(500.0).is_none());
+        assert!(Dispersion::SF11.ior(400.0).unwrap() > Dispersion::SF11.ior(700.0).unwrap());
+    }
+}
@@ -259,7 +259,7 @@ const FONT_DATA: [u8; 1280] = [
 ];
 
 
-pub fn render_into_buffer(buffer: &mut Vec<u32>, c: u8, col_offset: usize, row_offset: usize) {
+pub fn render_into_buffer(buffer: &mut [u32], c: u8, col_offset: usize, row_offset: usize) {
     let black = 0xffffffff;
     let alpha = 0xff000000;
 
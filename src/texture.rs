@@ -0,0 +1,129 @@
+use std::io;
+use std::path::Path;
+
+use cgmath::{vec3, InnerSpace, Vector2, Vector3};
+
+use crate::image::read_image;
+use crate::utils::pcg_hash;
+
+// Evaluated at the UV and world position of a hit, other textures are referenced by index into the scene's textures
+pub enum Texture {
+    Constant(Vector3<f32>),
+    // Alternates between two textures, `scale` checks per UV unit
+    Checker { even: usize, odd: usize, scale: f32 },
+    // Fractal Brownian motion in world space, `scale` is the frequency of the first octave
+    Noise { color: Vector3<f32>, scale: f32, octaves: u32 },
+    // Stripes along the z axis, `scale` of them per 2π units, distorted by turbulence
+    Marble { color: Vector3<f32>, scale: f32 },
+    Image(ImageTexture),
+}
+
+// Linear pixels, top row first, sampled bilinearly and repeating outside [0, 1]
+pub struct ImageTexture {
+    width: usize,
+    height: usize,
+    pixels: Vec<Vector3<f32>>,
+}
+
+impl Texture {
+    // Color images are sRGB encoded, data such as roughness maps are loaded as is
+    pub fn load_image(path: &Path, srgb: bool) -> io::Result<Self> {
+        let (width, height, mut pixels) = read_image(path)?;
+        if srgb {
+            for pixel in &mut pixels {
+                *pixel = pixel.map(srgb_to_linear);
+            }
+        }
+
+        Ok(Texture::Image(ImageTexture { width, height, pixels }))
+    }
+
+    pub fn evaluate(&self, textures: &[Texture], uv: Vector2<f32>, position: Vector3<f32>) -> Vector3<f32> {
+        match self {
+            Texture::Constant(color) => *color,
+            Texture::Checker { even, odd, scale } => {
+                let parity = ((uv.x * scale).floor() + (uv.y * scale).floor()) as i64;
+                let index = if parity.rem_euclid(2) == 0 { *even } else { *odd };
+                textures[index].evaluate(textures, uv, position)
+            }
+            Texture::Noise { color, scale, octaves } => color * (0.5 + 0.5 * fbm(position * *scale, *octaves)).clamp(0.0, 1.0),
+            Texture::Marble { color, scale } => color * (0.5 * (1.0 + (scale * position.z + 10.0 * turbulence(position, 7)).sin())),
+            Texture::Image(image) => image.sample(uv),
+        }
+    }
+}
+
+impl ImageTexture {
+    fn sample(&self, uv: Vector2<f32>) -> Vector3<f32> {
+        // UV origin is the bottom left, rows are stored top down
+        let x = uv.x.rem_euclid(1.0) * self.width as f32 - 0.5;
+        let y = (1.0 - uv.y.rem_euclid(1.0)) * self.height as f32 - 0.5;
+
+        let (x0, y0) = (x.floor(), y.floor());
+        let (tx, ty) = (x - x0, y - y0);
+
+        let pixel = |px: f32, py: f32| {
+            let px = (px as i64).rem_euclid(self.width as i64) as usize;
+            let py = (py as i64).rem_euclid(self.height as i64) as usize;
+            self.pixels[px + py * self.width]
+        };
+
+        let top = pixel(x0, y0) * (1.0 - tx) + pixel(x0 + 1.0, y0) * tx;
+        let bottom = pixel(x0, y0 + 1.0) * (1.0 - tx) + pixel(x0 + 1.0, y0 + 1.0) * tx;
+        top * (1.0 - ty) + bottom * ty
+    }
+}
+
+fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+// Gradient noise (Perlin 2002) with lattice gradients picked by hashing, so no permutation table is needed
+fn perlin(p: Vector3<f32>) -> f32 {
+    const GRADIENTS: [Vector3<f32>; 12] = [
+        vec3(1.0, 1.0, 0.0),
+        vec3(-1.0, 1.0, 0.0),
+        vec3(1.0, -1.0, 0.0),
+        vec3(-1.0, -1.0, 0.0),
+        vec3(1.0, 0.0, 1.0),
+        vec3(-1.0, 0.0, 1.0),
+        vec3(1.0, 0.0, -1.0),
+        vec3(-1.0, 0.0, -1.0),
+        vec3(0.0, 1.0, 1.0),
+        vec3(0.0, -1.0, 1.0),
+        vec3(0.0, 1.0, -1.0),
+        vec3(0.0, -1.0, -1.0),
+    ];
+
+    let cell = p.map(f32::floor);
+    let f = p - cell;
+    let fade = f.map(|t| t * t * t * (t * (t * 6.0 - 15.0) + 10.0));
+
+    let mut result = 0.0;
+    for corner in 0..8 {
+        let offset = vec3((corner & 1) as f32, ((corner >> 1) & 1) as f32, ((corner >> 2) & 1) as f32);
+        let lattice = (cell + offset).map(|c| c as i32 as u32);
+        let hash = pcg_hash(lattice.x ^ pcg_hash(lattice.y ^ pcg_hash(lattice.z)));
+
+        let weight = offset.zip(fade, |o, t| if o > 0.0 { t } else { 1.0 - t });
+        result += weight.x * weight.y * weight.z * GRADIENTS[hash as usize % 12].dot(f - offset);
+    }
+
+    result
+}
+
+fn fbm(p: Vector3<f32>, octaves: u32) -> f32 {
+    (0..octaves)
+        .map(|i| perlin(p * 2.0f32.powi(i as i32)) * 0.5f32.powi(i as i32))
+        .sum()
+}
+
+fn turbulence(p: Vector3<f32>, octaves: u32) -> f32 {
+    (0..octaves)
+        .map(|i| perlin(p * 2.0f32.powi(i as i32)).abs() * 0.5f32.powi(i as i32))
+        .sum()
+}
@@ -0,0 +1,159 @@
+use std::str::FromStr;
+
+pub const TILE_SIZE: usize = 32;
+
+// A rectangle of pixels traced as one job, the end coordinates are exclusive
+#[derive(Clone, Copy)]
+pub struct Tile {
+    pub x0: usize,
+    pub y0: usize,
+    pub x1: usize,
+    pub y1: usize,
+}
+
+// The order tiles are handed out in within a frame, which is also the order they show up in
+#[derive(Clone, Copy, PartialEq)]
+pub enum TileOrder {
+    Scanline,
+    CenterOut,
+    Hilbert,
+}
+
+impl TileOrder {
+    pub const fn next(self) -> Self {
+        match self {
+            TileOrder::Scanline => TileOrder::CenterOut,
+            TileOrder::CenterOut => TileOrder::Hilbert,
+            TileOrder::Hilbert => TileOrder::Scanline,
+        }
+    }
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            TileOrder::Scanline => "scanline",
+            TileOrder::CenterOut => "center-out",
+            TileOrder::Hilbert => "hilbert",
+        }
+    }
+
+    // Covers the image with tiles, the ones along the right and top edges may be smaller
+    pub fn tiles(self, width: usize, height: usize) -> Vec<Tile> {
+        let columns = width.div_ceil(TILE_SIZE);
+        let rows = height.div_ceil(TILE_SIZE);
+
+        let tile = |column: usize, row: usize| Tile {
+            x0: column * TILE_SIZE,
+            y0: row * TILE_SIZE,
+            x1: ((column + 1) * TILE_SIZE).min(width),
+            y1: ((row + 1) * TILE_SIZE).min(height),
+        };
+
+        match self {
+            // The image is stored bottom row first, so start from the last row to go down the screen
+            TileOrder::Scanline => (0..rows)
+                .rev()
+                .flat_map(|row| (0..columns).map(move |column| (column, row)))
+                .map(|(column, row)| tile(column, row))
+                .collect(),
+            TileOrder::CenterOut => {
+                let mut tiles: Vec<Tile> = (0..rows)
+                    .flat_map(|row| (0..columns).map(move |column| (column, row)))
+                    .map(|(column, row)| tile(column, row))
+                    .collect();
+                let distance = |t: &Tile| {
+                    let dx = (t.x0 + t.x1) as f32 * 0.5 - width as f32 * 0.5;
+                    let dy = (t.y0 + t.y1) as f32 * 0.5 - height as f32 * 0.5;
+                    dx * dx + dy * dy
+                };
+                tiles.sort_by(|a, b| distance(a).total_cmp(&distance(b)));
+                tiles
+            }
+            // Walks a curve over the smallest power of two square holding the grid and skips what falls outside
+            TileOrder::Hilbert => {
+                let n = columns.max(rows).next_power_of_two();
+                (0..n * n)
+                    .map(|d| hilbert_point(n, d))
+                    .filter(|&(column, row)| column < columns && row < rows)
+                    .map(|(column, row)| tile(column, row))
+                    .collect()
+            }
+        }
+    }
+}
+
+impl FromStr for TileOrder {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [TileOrder::Scanline, TileOrder::CenterOut, TileOrder::Hilbert]
+            .into_iter()
+            .find(|order| order.name() == s)
+            .ok_or(())
+    }
+}
+
+// Position of the `d`-th cell along a Hilbert curve filling an `n` by `n` grid, `n` a power of two
+fn hilbert_point(n: usize, d: usize) -> (usize, usize) {
+    let (mut x, mut y) = (0, 0);
+    let mut t = d;
+    let mut s = 1;
+    while s < n {
+        let rx = 1 & (t / 2);
+        let ry = 1 & (t ^ rx);
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - x;
+                y = s - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        x += s * rx;
+        y += s * ry;
+        t /= 4;
+        s *= 2;
+    }
+
+    (x, y)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORDERS: [TileOrder; 3] = [TileOrder::Scanline, TileOrder::CenterOut, TileOrder::Hilbert];
+
+    #[test]
+    fn tiles_cover_every_pixel_exactly_once() {
+        for (width, height) in [(1, 1), (32, 32), (33, 17), (100, 64), (300, 40), (1280, 720)] {
+            for order in ORDERS {
+                let mut covered = vec![0; width * height];
+                for tile in order.tiles(width, height) {
+                    assert!(tile.x0 < tile.x1 && tile.y0 < tile.y1, "{} {}x{}", order.name(), width, height);
+                    for y in tile.y0..tile.y1 {
+                        for x in tile.x0..tile.x1 {
+                            covered[x + y * width] += 1;
+                        }
+                    }
+                }
+                assert!(covered.iter().all(|&count| count == 1), "{} {}x{}", order.name(), width, height);
+            }
+        }
+    }
+
+    #[test]
+    fn hilbert_curve_visits_each_cell_once_in_unit_steps() {
+        for n in [1, 2, 4, 8, 16] {
+            let points: Vec<(usize, usize)> = (0..n * n).map(|d| hilbert_point(n, d)).collect();
+
+            let mut visited = vec![false; n * n];
+            for &(x, y) in &points {
+                assert!(x < n && y < n && !visited[x + y * n]);
+                visited[x + y * n] = true;
+            }
+            for pair in points.windows(2) {
+                let ((x0, y0), (x1, y1)) = (pair[0], pair[1]);
+                assert_eq!(x0.abs_diff(x1) + y0.abs_diff(y1), 1);
+            }
+        }
+    }
+}
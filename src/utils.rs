@@ -1,4 +1,6 @@
-use cgmath::{InnerSpace, vec3, Vector3};
+use std::f32::consts::PI;
+
+use cgmath::{vec2, vec3, InnerSpace, Vector2, Vector3, Zero};
 use rand::random;
 
 pub fn pcg_hash(input: u32) -> u32 {
@@ -11,27 +13,187 @@ pub fn pcg_hash(input: u32) -> u32 {
 pub fn pcg_float(input: &mut u32) -> f32 {
     *input = pcg_hash(*input);
 
-    return (*input as f32) / (u32::MAX as f32);
+    (*input as f32) / (u32::MAX as f32)
 }
 
-pub fn pcg_vec3(input: &mut u32) -> Vector3<f32> {
-    vec3(pcg_float(input) * 2.0 - 1.0, pcg_float(input) * 2.0 - 1.0, pcg_float(input) * 2.0 - 1.0)
+// Multiple importance sampling weight for a sample drawn with `pdf` against one other strategy
+pub fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    let a = pdf * pdf;
+    let b = other_pdf * other_pdf;
+
+    if a + b > 0.0 { a / (a + b) } else { 0.0 }
+}
+
+// Rec. 709 / sRGB primaries
+pub fn luminance(color: Vector3<f32>) -> f32 {
+    color.dot(vec3(0.2126, 0.7152, 0.0722))
 }
 
 pub fn reflect(incident: Vector3<f32>, normal: Vector3<f32>) -> Vector3<f32> {
     incident - 2.0 * normal.dot(incident) * normal
 }
 
+// Builds a tangent frame around a unit vector (Duff et al. 2017)
+pub fn orthonormal_basis(n: Vector3<f32>) -> (Vector3<f32>, Vector3<f32>) {
+    let sign = 1.0f32.copysign(n.z);
+    let a = -1.0 / (sign + n.z);
+    let b = n.x * n.y * a;
+
+    (
+        vec3(1.0 + sign * n.x * n.x * a, sign * b, -sign * n.x),
+        vec3(b, sign + n.y * n.y * a, -n.y),
+    )
+}
+
+// Rotates a direction from a local frame with z up into the frame around `n`
+pub fn from_local(local: Vector3<f32>, n: Vector3<f32>) -> Vector3<f32> {
+    let (tangent, bitangent) = orthonormal_basis(n);
+    (tangent * local.x + bitangent * local.y + n * local.z).normalize()
+}
+
+// Warps from the unit square, directions are in a local frame with z up. Each comes with its density, in solid
+// angle for directions and in area for points
 
-pub fn random_vector3_in_range(min: f32, max: f32) -> Vector3<f32> {
-    vec3(random::<f32>() * (max - min) + min, random::<f32>() * (max - min) + min, random::<f32>() * (max - min) + min)
+pub fn sample_uniform_sphere(u: Vector2<f32>) -> Vector3<f32> {
+    let z = 1.0 - 2.0 * u.x;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * u.y;
+    vec3(r * phi.cos(), r * phi.sin(), z)
+}
+
+pub fn uniform_sphere_pdf() -> f32 {
+    1.0 / (4.0 * PI)
+}
+
+// Malley's method: project a uniform disk point up onto the hemisphere
+pub fn sample_cosine_hemisphere(u: Vector2<f32>) -> Vector3<f32> {
+    let d = sample_concentric_disk(u);
+    let z = (1.0 - d.magnitude2()).max(0.0).sqrt();
+    vec3(d.x, d.y, z)
+}
+
+pub fn cosine_hemisphere_pdf(cos_theta: f32) -> f32 {
+    cos_theta.max(0.0) / PI
+}
+
+// Uniform on the unit disk, with area density 1 / pi. The concentric mapping (Shirley and Chiu 1997) keeps the
+// strata of the input
+pub fn sample_concentric_disk(u: Vector2<f32>) -> Vector2<f32> {
+    let (a, b) = (2.0 * u.x - 1.0, 2.0 * u.y - 1.0);
+    if a == 0.0 && b == 0.0 {
+        return Vector2::zero();
+    }
+
+    let (r, phi) = if a.abs() > b.abs() {
+        (a, PI / 4.0 * (b / a))
+    } else {
+        (b, PI / 2.0 - PI / 4.0 * (a / b))
+    };
+    vec2(r * phi.cos(), r * phi.sin())
+}
+
+// Uniform over the directions within the cone around z whose half angle has cosine `cos_theta_max`
+pub fn sample_uniform_cone(u: Vector2<f32>, cos_theta_max: f32) -> Vector3<f32> {
+    let cos_theta = 1.0 + u.x * (cos_theta_max - 1.0);
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * u.y;
+    vec3(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+}
+
+pub fn uniform_cone_pdf(cos_theta_max: f32) -> f32 {
+    1.0 / (2.0 * PI * (1.0 - cos_theta_max).max(1e-7))
+}
+
+// Barycentric weights of a point uniform over a triangle, whose area density is one over the triangle's area.
+// Doesn't fold the square, so nearby inputs stay nearby (Heitz 2019)
+pub fn sample_uniform_triangle(u: Vector2<f32>) -> Vector3<f32> {
+    let (b0, b1) = if u.x < u.y {
+        let b0 = u.x / 2.0;
+        (b0, u.y - b0)
+    } else {
+        let b1 = u.y / 2.0;
+        (u.x - b1, b1)
+    };
+    vec3(b0, b1, 1.0 - b0 - b1)
 }
 
 pub fn random_vector3() -> Vector3<f32> {
     vec3(random(), random(), random())
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GRID: usize = 256;
+
+    // Midpoints of a regular grid over the unit square, enough for the fractions below to be within a percent
+    fn grid() -> impl Iterator<Item = Vector2<f32>> {
+        (0..GRID * GRID).map(|i| vec2((i % GRID) as f32 + 0.5, (i / GRID) as f32 + 0.5) / GRID as f32)
+    }
+
+    fn fraction<T>(warp: impl Fn(Vector2<f32>) -> T, inside: impl Fn(T) -> bool) -> f32 {
+        grid().filter(|&u| inside(warp(u))).count() as f32 / (GRID * GRID) as f32
+    }
+
+    // Probability of a direction with cos theta above `cos_cap`, integrating the pdf over rings of the cap
+    fn cap_probability(pdf: impl Fn(f32) -> f32, cos_cap: f32) -> f32 {
+        let steps = 1000;
+        let width = (1.0 - cos_cap) / steps as f32;
+        (0..steps).map(|i| pdf(cos_cap + (i as f32 + 0.5) * width) * 2.0 * PI * width).sum()
+    }
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!((actual - expected).abs() < 0.01, "{} != {}", actual, expected);
+    }
+
+    #[test]
+    fn uniform_sphere_matches_pdf() {
+        assert!(grid().all(|u| (sample_uniform_sphere(u).magnitude() - 1.0).abs() < 1e-4));
+        for cos_cap in [-1.0, -0.5, 0.0, 0.7] {
+            let expected = cap_probability(|_| uniform_sphere_pdf(), cos_cap);
+            assert_close(fraction(sample_uniform_sphere, |d| d.z > cos_cap), expected);
+        }
+    }
+
+    #[test]
+    fn cosine_hemisphere_matches_pdf() {
+        assert!(grid().all(|u| sample_cosine_hemisphere(u).z >= 0.0));
+        for cos_cap in [0.0, 0.3, 0.8] {
+            let expected = cap_probability(cosine_hemisphere_pdf, cos_cap);
+            assert_close(fraction(sample_cosine_hemisphere, |d| d.z > cos_cap), expected);
+        }
+    }
+
+    #[test]
+    fn uniform_cone_matches_pdf() {
+        let cos_theta_max = 0.9;
+        let sample = |u| sample_uniform_cone(u, cos_theta_max);
+        assert!(grid().all(|u| sample(u).z >= cos_theta_max - 1e-6));
+        for cos_cap in [0.9, 0.95, 0.99] {
+            let expected = cap_probability(|_| uniform_cone_pdf(cos_theta_max), cos_cap);
+            assert_close(fraction(sample, |d| d.z > cos_cap), expected);
+        }
+    }
+
+    #[test]
+    fn concentric_disk_is_uniform() {
+        assert!(grid().all(|u| sample_concentric_disk(u).magnitude() <= 1.0 + 1e-6));
+        // Area fractions of the disk with density 1 / pi
+        assert_close(fraction(sample_concentric_disk, |p| p.magnitude() < 0.5), 0.25);
+        assert_close(fraction(sample_concentric_disk, |p| p.x > 0.0 && p.y > 0.0), 0.25);
+        assert_close(fraction(sample_concentric_disk, |p| p.x > 0.5), 1.0 / 3.0 - 3f32.sqrt() / (4.0 * PI));
+    }
 
-pub fn random_in_unit_sphere() -> Vector3<f32> {
-    random_vector3_in_range(-1.0, 1.0).normalize()
+    #[test]
+    fn uniform_triangle_is_uniform() {
+        assert!(grid().all(|u| {
+            let b = sample_uniform_triangle(u);
+            b.x >= 0.0 && b.y >= 0.0 && b.z >= -1e-6 && (b.x + b.y + b.z - 1.0).abs() < 1e-6
+        }));
+        // The corner past half way to each vertex is a quarter of the area
+        for axis in 0..3 {
+            assert_close(fraction(sample_uniform_triangle, |b| b[axis] > 0.5), 0.25);
+        }
+    }
 }